@@ -98,7 +98,8 @@ impl TileServerConfig {
         match self.img_type.as_str() {
             "jpg" => image::ImageFormat::Jpeg,
             "png" => image::ImageFormat::Png,
-            _ => panic!("unknwon img frmat"),
+            "webp" => image::ImageFormat::WebP,
+            _ => panic!("unknwon img frmat: {}", self.img_type),
         }
     }
 }
@@ -180,16 +181,24 @@ pub fn download_server_configs(
     let img_map: HashMap<_, _> = srv_map
         .par_iter()
         .map(|(k, v)| {
+            // servers rendered from DEM tiles have no tiles at z=1
             let img = reqwest::blocking::get(v.get_tile_url(TileCoord {
                 x: 1,
                 y: 0,
                 z: 1,
             }))
-            .expect("cannot download demo img")
-            .bytes()
-            .expect("why no bytes");
-            info!("downloaded demoimg {} size {}", k, img.len());
-            let img = earth_fetch::parse_bytes_to_image(img, v.img_type());
+            .and_then(|resp| resp.error_for_status())
+            .and_then(|resp| resp.bytes());
+            let img = match img {
+                Ok(img) => {
+                    info!("downloaded demoimg {} size {}", k, img.len());
+                    earth_fetch::parse_bytes_to_image(img, v.img_type())
+                }
+                Err(err) => {
+                    warn!("no demoimg for {}: {}", k, err);
+                    Image::default()
+                }
+            };
             (k.clone(), img)
         })
         .collect();
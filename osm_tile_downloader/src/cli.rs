@@ -0,0 +1,148 @@
+use anyhow::Context;
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::export_job::{ExportRegion, TileExportRequest, TileExportSummary};
+use crate::geo_trig::{parse_polygon, GeoBBOX};
+use crate::mbtiles_export;
+use crate::pmtiles_export;
+use crate::tile_store;
+
+const USAGE: &str = "usage:
+    osm_tile_downloader
+        run the http server
+    osm_tile_downloader export-mbtiles --servers a,b --zoom 0-12
+            (--bbox x_min,y_min,x_max,y_max | --polygon lon,lat;lon,lat;...)
+        write cached tiles to one .mbtiles file per server
+    osm_tile_downloader export-pmtiles (same flags as export-mbtiles)
+        write cached tiles to one .pmtiles archive per server
+    osm_tile_downloader migrate-content --servers a,b [--from filesystem]
+        move cached tiles into the content-addressed store; run with the
+        server stopped, then set tile_store = \"content\" for them
+    osm_tile_downloader dedup-report
+        space saved by the content-addressed store, per server
+    osm_tile_downloader gc-content
+        delete content-addressed blobs no tile or tile history points to";
+
+/// `--key value` pairs after the command name.
+fn parse_flags(args: &[String]) -> Result<HashMap<String, String>> {
+    let mut flags = HashMap::new();
+    for pair in args.chunks(2) {
+        match pair {
+            [key, value] if key.starts_with("--") => {
+                flags.insert(key[2..].to_owned(), value.clone());
+            }
+            _ => anyhow::bail!("bad arguments {:?}\n{}", pair, USAGE),
+        }
+    }
+    Ok(flags)
+}
+
+fn parse_export_request(
+    flags: &HashMap<String, String>,
+) -> Result<TileExportRequest> {
+    let flag = |name: &str| {
+        flags
+            .get(name)
+            .with_context(|| format!("missing --{}\n{}", name, USAGE))
+    };
+    let (min_z, max_z) = flag("zoom")?
+        .split_once('-')
+        .context("--zoom should look like 0-12")?;
+    let request = TileExportRequest {
+        servers: flag("servers")?.split(',').map(str::to_owned).collect(),
+        region: ExportRegion {
+            bbox: flags
+                .get("bbox")
+                .map(|b| b.parse::<GeoBBOX>())
+                .transpose()?,
+            polygon: flags
+                .get("polygon")
+                .map(|p| parse_polygon(p))
+                .transpose()?,
+        },
+        min_z: min_z.parse()?,
+        max_z: max_z.parse()?,
+    };
+    request.check()?;
+    Ok(request)
+}
+
+fn print_results(results: &[TileExportSummary]) {
+    for result in results {
+        println!(
+            "{}\t{}\t{}",
+            result.server_name,
+            result.tile_count,
+            result.path.display()
+        );
+    }
+}
+
+/// Run the command given on the command line. Returns false when there
+/// is none and the server should start instead.
+pub async fn run_cli(args: &[String]) -> Result<bool> {
+    let (command, rest) = match args.split_first() {
+        Some(split) => split,
+        None => return Ok(false),
+    };
+    match command.as_str() {
+        "export-mbtiles" => {
+            let request = parse_export_request(&parse_flags(rest)?)?;
+            print_results(&mbtiles_export::export_mbtiles(request).await?);
+        }
+        "export-pmtiles" => {
+            let request = parse_export_request(&parse_flags(rest)?)?;
+            print_results(&pmtiles_export::export_pmtiles(request).await?);
+        }
+        "migrate-content" => {
+            let flags = parse_flags(rest)?;
+            let servers = flags
+                .get("servers")
+                .with_context(|| format!("missing --servers\n{}", USAGE))?
+                .clone();
+            let from = flags
+                .get("from")
+                .cloned()
+                .unwrap_or("filesystem".to_owned());
+            for server_name in servers.split(',') {
+                let server_name = server_name.to_owned();
+                let from = from.clone();
+                let summary = tokio::task::spawn_blocking(move || {
+                    tile_store::migrate_to_content_store(&server_name, &from)
+                })
+                .await??;
+                println!("{}\t{}", summary.server_name, summary.tile_count);
+            }
+        }
+        "dedup-report" => {
+            let reports =
+                tokio::task::spawn_blocking(tile_store::get_dedup_report)
+                    .await??;
+            for report in reports {
+                println!(
+                    "{}\t{}\t{} tiles\t{} unique\t{} MB saved",
+                    report.server_name,
+                    report.tile_store,
+                    report.tile_count,
+                    report.unique_count,
+                    report.saved_bytes / 1024 / 1024
+                );
+            }
+        }
+        "gc-content" => {
+            let summary =
+                tokio::task::spawn_blocking(tile_store::gc_content_blobs)
+                    .await??;
+            println!(
+                "{} blobs\t{} removed\t{} MB freed",
+                summary.blob_count,
+                summary.removed_count,
+                summary.removed_bytes / 1024 / 1024
+            );
+        }
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => anyhow::bail!("unknown command {:?}\n{}", command, USAGE),
+    }
+    Ok(true)
+}
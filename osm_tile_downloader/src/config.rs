@@ -17,7 +17,7 @@ lazy_static::lazy_static! {
         typed_sled::Tree::<String, TileServerConfig>
         = typed_sled::Tree::<String, TileServerConfig>::open(
             &SLED_DB,
-            "tile_server_configs_v3");
+            "tile_server_configs_v8");
 
     pub static ref DB_SOCKS_SCRAPER_CONFIGS:
         typed_sled::Tree::<String, Socks5ProxyScraperConfig>
@@ -41,7 +41,18 @@ pub struct LinksConfig {
     pub tile_servers: Vec<TileServerConfig>,
     pub socks5_scrape_servers: Vec<Socks5ProxyScraperConfig>,
     pub geo_search_url: String,
+    /// Reverse geocoding URL with `{lat}` and `{lon}` placeholders; reverse
+    /// geocoding is off when unset.
+    pub reverse_geo_url: Option<String>,
+    /// Decimals the coordinates are rounded to before reverse geocoding,
+    /// default 4 (about 11 m), so nearby lookups share a cache entry.
+    pub reverse_geo_precision: Option<u32>,
     pub topography_servers: Vec<TopographyServerConfig>,
+    /// Topography server for elevation requests that name none; default
+    /// the first one listed.
+    pub default_topography_server: Option<String>,
+    /// Served under /pmtiles/, and where PMTiles exports are written.
+    pub pmtiles_location: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
@@ -51,6 +62,8 @@ pub struct TopographyServerConfig {
     pub url: String,
     pub download_zoomlevel: u32,
     pub scale_zoomlevel: u32,
+    /// One of `download_dem::DEM_FORMATS`, default "geotiff".
+    pub format: Option<String>,
 }
 
 
@@ -66,6 +79,25 @@ pub struct TileServerConfig {
     pub map_type: String,
     pub servers: Option<Vec<String>>,
     pub planet: String,
+    pub overzoom_filter: Option<String>,
+    pub attribution: Option<String>,
+    /// One of `tile_store::TILE_STORE_KINDS`, default "filesystem".
+    pub tile_store: Option<String>,
+    /// Seconds a cached tile is fresh; older ones are served while being
+    /// revalidated. Never refreshed when unset.
+    pub max_age: Option<u64>,
+    /// Topography server whose DEM tiles are rendered locally into this
+    /// server's tiles, instead of fetching `url`.
+    pub dem_server: Option<String>,
+    /// One of `dem_render::DEM_RENDER_KINDS`, default "hillshade".
+    pub dem_render: Option<String>,
+    /// Hillshade sun position: compass degrees, default 315, and degrees
+    /// above the horizon, default 45.
+    pub sun_azimuth: Option<f64>,
+    pub sun_altitude: Option<f64>,
+    /// Store uniform grey, white or black tiles as placeholders, default
+    /// false. Only for providers that never serve such tiles as imagery.
+    pub uniform_placeholders: Option<bool>,
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
@@ -110,13 +142,94 @@ pub fn load_config() -> anyhow::Result<LinksConfig> {
             .map(|x| x.name.clone())
     ));
 
+    assert!(
+        config.reverse_geo_precision.unwrap_or(0) <= 7,
+        "reverse_geo_precision above 7 decimals defeats the cache"
+    );
+    if let Some(name) = &config.default_topography_server {
+        assert!(
+            config.topography_servers.iter().any(|t| &t.name == name),
+            "default_topography_server not in topography_servers: {}",
+            name
+        );
+    }
+
     // CHECK TILE SERVER CONFIGS
     for tile_server in config.tile_servers.iter() {
+        assert!(
+            !is_pyramid_tile_server(&tile_server.name),
+            "tile server name cannot end with {}: {}",
+            PYRAMID_SUFFIX,
+            tile_server.name
+        );
         assert!(
             tile_server.servers.is_none()
                 || (tile_server.servers.is_some()
                     && !tile_server.servers.as_ref().unwrap().is_empty())
         );
+        assert!(
+            crate::download_tile::TILE_IMG_TYPES
+                .contains(&tile_server.img_type.as_str()),
+            "bad img_type for {}: {}",
+            tile_server.name,
+            tile_server.img_type
+        );
+        if let Some(filter) = &tile_server.overzoom_filter {
+            assert!(
+                crate::download_tile::parse_overzoom_filter(filter).is_ok(),
+                "bad overzoom_filter for {}: {}",
+                tile_server.name,
+                filter
+            );
+        }
+        if let Some(dem_server) = &tile_server.dem_server {
+            assert!(
+                config.topography_servers.iter().any(|t| &t.name == dem_server),
+                "dem_server for {} not in topography_servers: {}",
+                tile_server.name,
+                dem_server
+            );
+            assert!(
+                crate::dem_render::DEM_RENDER_KINDS
+                    .contains(&crate::dem_render::get_dem_render(tile_server)),
+                "bad dem_render for {}: {:?}",
+                tile_server.name,
+                tile_server.dem_render
+            );
+            assert!(
+                tile_server.img_type == "png",
+                "dem tiles are png: {}",
+                tile_server.name
+            );
+        }
+        if let Some(tile_store) = &tile_server.tile_store {
+            assert!(
+                crate::tile_store::TILE_STORE_KINDS
+                    .contains(&tile_store.as_str()),
+                "bad tile_store for {}: {}",
+                tile_server.name,
+                tile_store
+            );
+        }
+    }
+
+    // CHECK TOPOGRAPHY SERVER CONFIGS
+    assert!(has_unique_elements(
+        config.topography_servers.iter().map(|x| x.name.clone())
+    ));
+    for topo_server in config.topography_servers.iter() {
+        assert!(
+            topo_server.scale_zoomlevel >= topo_server.download_zoomlevel,
+            "scale_zoomlevel below download_zoomlevel for {}",
+            topo_server.name
+        );
+        assert!(
+            crate::download_dem::DEM_FORMATS
+                .contains(&crate::download_dem::get_dem_format(topo_server)),
+            "bad format for {}: {:?}",
+            topo_server.name,
+            topo_server.format
+        );
     }
 
     Ok(config)
@@ -169,6 +282,9 @@ pub fn get_all_tile_servers() -> anyhow::Result<Vec<TileServerConfig>> {
 }
 
 pub fn get_tile_server(server_name: &str) -> anyhow::Result<TileServerConfig> {
+    if let Some(source_name) = server_name.strip_suffix(PYRAMID_SUFFIX) {
+        return pyramid_tile_server(&get_tile_server(source_name)?);
+    }
     let server_config = DB_TILE_SERVER_CONFIGS
         .get(&server_name.to_owned())
         .context("db get error")?
@@ -178,6 +294,43 @@ pub fn get_tile_server(server_name: &str) -> anyhow::Result<TileServerConfig> {
     Ok(server_config)
 }
 
+pub fn get_topography_server(
+    server_name: &str,
+) -> anyhow::Result<TopographyServerConfig> {
+    LINKS_CONFIG
+        .topography_servers
+        .iter()
+        .find(|x| x.name == server_name)
+        .cloned()
+        .with_context(|| {
+            format!("topography server not found: '{}'", &server_name)
+        })
+}
+
+pub const PYRAMID_SUFFIX: &str = "__pyramid";
+
+/// Derived server holding tiles built by downsampling cached children.
+/// Only levels below the source max_level can be built.
+pub fn pyramid_tile_server(
+    source: &TileServerConfig,
+) -> anyhow::Result<TileServerConfig> {
+    if source.max_level == 0 {
+        anyhow::bail!("cannot build pyramid for max_level=0: {}", source.name);
+    }
+    Ok(TileServerConfig {
+        name: format!("{}{}", source.name, PYRAMID_SUFFIX),
+        comment: format!("pyramid downsampled from {}", source.name),
+        url: "".to_owned(),
+        max_level: source.max_level - 1,
+        servers: None,
+        ..source.clone()
+    })
+}
+
+pub fn is_pyramid_tile_server(server_name: &str) -> bool {
+    server_name.ends_with(PYRAMID_SUFFIX)
+}
+
 pub fn get_all_socks5_scrapers() -> anyhow::Result<Vec<Socks5ProxyScraperConfig>>
 {
     let mut servers = Vec::<Socks5ProxyScraperConfig>::new();
@@ -188,6 +341,13 @@ pub fn get_all_socks5_scrapers() -> anyhow::Result<Vec<Socks5ProxyScraperConfig>
     Ok(servers)
 }
 
+pub fn pmtiles_dir() -> PathBuf {
+    LINKS_CONFIG
+        .pmtiles_location
+        .clone()
+        .unwrap_or(LINKS_CONFIG.tile_location.join("pmtiles"))
+}
+
 pub fn tmpdir() -> PathBuf {
     LINKS_CONFIG.tile_location.join("tmp")
 }
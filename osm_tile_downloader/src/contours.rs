@@ -0,0 +1,345 @@
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+
+use crate::dem_render::DEM_RENDER_MAX_ZOOM_OUT;
+use crate::elevation;
+use crate::geo_trig::tile_float_to_geo_point;
+
+/// Grid cells per tile side for GeoJSON contours.
+pub const CONTOUR_GRID_SIZE: u32 = 256;
+pub const CONTOUR_MAX_LEVELS: usize = 500;
+/// Every n-th contour is a major (index) contour.
+pub const CONTOUR_MAJOR_EVERY: i64 = 5;
+
+/// One contour line in grid coordinates, (0, 0) being the tile's
+/// north-west corner and (size, size) its south-east one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContourLine {
+    pub elevation: f64,
+    pub major: bool,
+    pub points: Vec<(f64, f64)>,
+}
+
+/// Cell edge a contour crosses: horizontal edges start at their left
+/// corner, vertical ones at their top corner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum CellEdge {
+    H(usize, usize),
+    V(usize, usize),
+}
+
+/// Elevations at the pixel corners of a web tile, `(size + 1)^2` samples
+/// row major; NaN without data. Corners on the tile border are shared with
+/// the neighbour tiles, so contours join up across tiles.
+pub async fn get_tile_corner_elevations(
+    server_name: Option<&str>,
+    z: u8,
+    x: u64,
+    y: u64,
+    size: u32,
+) -> Result<Vec<f64>> {
+    let topo_config = elevation::get_elevation_server(server_name)?;
+    let min_z = topo_config
+        .download_zoomlevel
+        .saturating_sub(DEM_RENDER_MAX_ZOOM_OUT);
+    if (z as u32) < min_z {
+        anyhow::bail!(
+            "got z = {} when min for {} contours is {}",
+            z,
+            topo_config.name,
+            min_z
+        );
+    }
+    let mut points = Vec::with_capacity(((size + 1) * (size + 1)) as usize);
+    for j in 0..=size {
+        for i in 0..=size {
+            points.push(tile_float_to_geo_point(
+                z,
+                x as f64 + i as f64 / size as f64,
+                y as f64 + j as f64 / size as f64,
+            ));
+        }
+    }
+    let elevations =
+        elevation::get_elevations(Some(&topo_config.name), &points).await?;
+    Ok(elevations
+        .into_iter()
+        .map(|e| e.map_or(f64::NAN, |e| e as f64))
+        .collect())
+}
+
+/// Contour lines every `interval` meters over a `(size + 1)^2` grid, by
+/// marching squares. Saddles are resolved by the cell center average;
+/// cells with a nodata corner are skipped. Closed contours end on their
+/// first point, since the walk comes back to the start edge.
+pub fn marching_squares(
+    grid: &[f64],
+    size: usize,
+    interval: f64,
+) -> Result<Vec<ContourLine>> {
+    if interval.is_nan() || interval < 0.1 {
+        anyhow::bail!(
+            "contour interval must be at least 0.1, got {}",
+            interval
+        );
+    }
+    let stride = size + 1;
+    let (min_e, max_e) = grid
+        .iter()
+        .filter(|e| !e.is_nan())
+        .fold((f64::MAX, f64::MIN), |(lo, hi), e| (lo.min(*e), hi.max(*e)));
+    if min_e > max_e {
+        return Ok(vec![]);
+    }
+    let first = (min_e / interval).ceil() as i64;
+    let last = (max_e / interval).floor() as i64;
+    if last - first >= CONTOUR_MAX_LEVELS as i64 {
+        anyhow::bail!(
+            "more than {} contour levels, use a larger interval",
+            CONTOUR_MAX_LEVELS
+        );
+    }
+
+    let mut lines = vec![];
+    for level_index in first..=last {
+        let level = level_index as f64 * interval;
+        let mut positions: HashMap<CellEdge, (f64, f64)> = HashMap::new();
+        let mut segments: Vec<[CellEdge; 2]> = vec![];
+        for j in 0..size {
+            for i in 0..size {
+                let tl = grid[j * stride + i];
+                let tr = grid[j * stride + i + 1];
+                let br = grid[(j + 1) * stride + i + 1];
+                let bl = grid[(j + 1) * stride + i];
+                if [tl, tr, br, bl].iter().any(|e| e.is_nan()) {
+                    continue;
+                }
+                let case = (tl >= level) as u8 * 8
+                    + (tr >= level) as u8 * 4
+                    + (br >= level) as u8 * 2
+                    + (bl >= level) as u8;
+                if case == 0 || case == 15 {
+                    continue;
+                }
+                let (top, right) = (CellEdge::H(i, j), CellEdge::V(i + 1, j));
+                let (bottom, left) = (CellEdge::H(i, j + 1), CellEdge::V(i, j));
+                let center_inside = (tl + tr + br + bl) / 4.0 >= level;
+                let cell_segments = match case {
+                    1 | 14 => vec![[left, bottom]],
+                    2 | 13 => vec![[bottom, right]],
+                    3 | 12 => vec![[left, right]],
+                    4 | 11 => vec![[top, right]],
+                    6 | 9 => vec![[top, bottom]],
+                    7 | 8 => vec![[left, top]],
+                    // cut off the two corners on the other side of the
+                    // center: tl and br
+                    5 if center_inside => {
+                        vec![[left, top], [bottom, right]]
+                    }
+                    10 if !center_inside => {
+                        vec![[left, top], [bottom, right]]
+                    }
+                    _ => vec![[top, right], [left, bottom]],
+                };
+                let crossing = |a: f64, b: f64| (level - a) / (b - a);
+                for edge in cell_segments.iter().flatten() {
+                    let position = match *edge {
+                        CellEdge::H(ei, ej) => {
+                            let (a, b) =
+                                if ej == j { (tl, tr) } else { (bl, br) };
+                            (ei as f64 + crossing(a, b), ej as f64)
+                        }
+                        CellEdge::V(ei, ej) => {
+                            let (a, b) =
+                                if ei == i { (tl, bl) } else { (tr, br) };
+                            (ei as f64, ej as f64 + crossing(a, b))
+                        }
+                    };
+                    positions.insert(*edge, position);
+                }
+                segments.extend(cell_segments);
+            }
+        }
+
+        // join segments sharing an edge into polylines
+        let mut by_edge: HashMap<CellEdge, Vec<usize>> = HashMap::new();
+        for (k, segment) in segments.iter().enumerate() {
+            for edge in segment {
+                by_edge.entry(*edge).or_default().push(k);
+            }
+        }
+        let mut used = vec![false; segments.len()];
+        for start in 0..segments.len() {
+            if used[start] {
+                continue;
+            }
+            used[start] = true;
+            let mut chain = VecDeque::from(segments[start]);
+            for forward in [true, false] {
+                loop {
+                    let end =
+                        if forward { chain.back() } else { chain.front() };
+                    let end = *end.expect("chain is never empty");
+                    let next = by_edge[&end].iter().find(|k| !used[**k]);
+                    let Some(&k) = next else { break };
+                    used[k] = true;
+                    let [a, b] = segments[k];
+                    let other = if a == end { b } else { a };
+                    if forward {
+                        chain.push_back(other);
+                    } else {
+                        chain.push_front(other);
+                    }
+                }
+            }
+            lines.push(ContourLine {
+                elevation: level,
+                major: level_index % CONTOUR_MAJOR_EVERY == 0,
+                points: chain.iter().map(|e| positions[e]).collect(),
+            });
+        }
+    }
+    Ok(lines)
+}
+
+/// Elevation tagged LineStrings for one web tile.
+pub async fn get_contours_geojson(
+    server_name: Option<&str>,
+    z: u8,
+    x: u64,
+    y: u64,
+    interval: f64,
+) -> Result<serde_json::Value> {
+    let size = CONTOUR_GRID_SIZE;
+    let grid = get_tile_corner_elevations(server_name, z, x, y, size).await?;
+    let lines = tokio::task::spawn_blocking(move || {
+        marching_squares(&grid, size as usize, interval)
+    })
+    .await??;
+    let features: Vec<serde_json::Value> = lines
+        .iter()
+        .map(|line| {
+            let coordinates: Vec<[f64; 2]> = line
+                .points
+                .iter()
+                .map(|(gx, gy)| {
+                    let p = tile_float_to_geo_point(
+                        z,
+                        x as f64 + gx / size as f64,
+                        y as f64 + gy / size as f64,
+                    );
+                    [p.x_lon, p.y_lat]
+                })
+                .collect();
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": coordinates,
+                },
+                "properties": {
+                    "elevation": line.elevation,
+                    "major": line.major,
+                },
+            })
+        })
+        .collect();
+    Ok(serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features,
+    }))
+}
+
+/// Draw contour lines in grid coordinates onto an image of `size` pixels
+/// per side; major contours twice as thick.
+pub fn draw_contour_lines(
+    img: &mut image::RgbImage,
+    lines: &[ContourLine],
+    size: u32,
+) {
+    let color = image::Rgb([150, 90, 40]);
+    let (w, h) = (img.width() as f64, img.height() as f64);
+    let (sx, sy) = (w / size as f64, h / size as f64);
+    for line in lines {
+        let width = if line.major { 1.0 } else { 0.5 };
+        for pair in line.points.windows(2) {
+            let (x0, y0) = (pair[0].0 * sx, pair[0].1 * sy);
+            let (x1, y1) = (pair[1].0 * sx, pair[1].1 * sy);
+            let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0);
+            for step in 0..=steps as u32 {
+                let t = step as f64 / steps;
+                let (px, py) = (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t);
+                let (ix0, ix1) = ((px - width).round(), (px + width).round());
+                let (iy0, iy1) = ((py - width).round(), (py + width).round());
+                for iy in iy0 as i64..iy1 as i64 {
+                    for ix in ix0 as i64..ix1 as i64 {
+                        if ix >= 0
+                            && iy >= 0
+                            && (ix as f64) < w
+                            && (iy as f64) < h
+                        {
+                            img.put_pixel(ix as u32, iy as u32, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines of one level, each with its points sorted, for comparing
+    /// without caring about the walking direction.
+    fn level_lines(lines: &[ContourLine], level: f64) -> Vec<Vec<(f64, f64)>> {
+        let mut found: Vec<Vec<(f64, f64)>> = lines
+            .iter()
+            .filter(|l| l.elevation == level)
+            .map(|l| {
+                let mut points = l.points.clone();
+                points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                points
+            })
+            .collect();
+        found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        found
+    }
+
+    #[test]
+    fn test_saddle() {
+        // tl, tr / bl, br: high corners on the tr-bl diagonal (case 5)
+        let grid = [0.0, 10.0, 10.0, 0.0];
+        let lines = marching_squares(&grid, 1, 4.0).unwrap();
+        // center average 5 is above 4: the low corners are cut off
+        assert_eq!(
+            level_lines(&lines, 4.0),
+            vec![vec![(0.0, 0.4), (0.4, 0.0)], vec![(0.6, 1.0), (1.0, 0.6)]]
+        );
+        // and below 8: the high corners are cut off
+        assert_eq!(
+            level_lines(&lines, 8.0),
+            vec![vec![(0.0, 0.8), (0.2, 1.0)], vec![(0.8, 0.0), (1.0, 0.2)]]
+        );
+
+        // high corners on the tl-br diagonal (case 10)
+        let grid = [10.0, 0.0, 0.0, 10.0];
+        let lines = marching_squares(&grid, 1, 4.0).unwrap();
+        assert_eq!(
+            level_lines(&lines, 4.0),
+            vec![vec![(0.0, 0.6), (0.4, 1.0)], vec![(0.6, 0.0), (1.0, 0.4)]]
+        );
+    }
+
+    #[test]
+    fn test_closed_contour() {
+        let grid = [0.0, 0.0, 0.0, 0.0, 10.0, 0.0, 0.0, 0.0, 0.0];
+        let lines = marching_squares(&grid, 2, 5.0).unwrap();
+        let ring = level_lines(&lines, 5.0);
+        assert_eq!(ring.len(), 1);
+        let line = &lines.iter().find(|l| l.elevation == 5.0).unwrap();
+        assert_eq!(line.points.len(), 5);
+        assert_eq!(line.points.first(), line.points.last());
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::config;
+use crate::download_tile::{TileFetchId, TileParseResult};
+use crate::geo_trig::{geo_bbox, tile_range, GeoBBOX};
+use crate::proxy_manager;
+use crate::tile_store;
+
+/// Coverage PNGs have at most this many cells per side; at higher zooms
+/// one cell covers a square of tiles.
+pub const COVERAGE_MAX_CELLS: u64 = 1024;
+/// Larger GeoJSON answers should use a smaller bbox or the PNG.
+pub const COVERAGE_MAX_FEATURES: usize = 50_000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TileCoverage {
+    Cached,
+    /// Cached, but a placeholder or blank tile.
+    NoData,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CoverageSummary {
+    pub server_name: String,
+    pub z: u8,
+    /// Inclusive tile range the coverage covers.
+    pub range: ((u64, u64), (u64, u64)),
+    pub tiles: HashMap<TileCoverage, u64>,
+    pub missing: u64,
+}
+
+/// State of every known tile of one zoom level in the range, from the
+/// tile store and the download results. Tiles not listed are missing;
+/// stored tiles whose refresh failed count as cached. Blocking.
+pub fn get_coverage(
+    server_name: &str,
+    z: u8,
+    range: ((u64, u64), (u64, u64)),
+) -> Result<Vec<(u64, u64, TileCoverage)>> {
+    let server_config = config::get_tile_server(server_name)?;
+    let store = tile_store::get_tile_store(server_name)?;
+    let ((x0, y0), (x1, y1)) = range;
+    let mut coverage = vec![];
+    let mut stored = HashSet::new();
+    for (x, y) in store.list(server_name, z, range)? {
+        let tile = TileFetchId {
+            x,
+            y,
+            z,
+            server_name: server_name.to_owned(),
+            extension: server_config.img_type.clone(),
+        };
+        let state = match proxy_manager::get_download_result(&tile)? {
+            Some(TileParseResult::NoData(_)) => TileCoverage::NoData,
+            _ => TileCoverage::Cached,
+        };
+        coverage.push((x, y, state));
+        stored.insert((x, y));
+    }
+    for (tile, _error) in proxy_manager::get_failed_downloads::<TileFetchId>()?
+    {
+        if tile.server_name == server_name
+            && tile.z == z
+            && tile.x >= x0
+            && tile.x <= x1
+            && tile.y >= y0
+            && tile.y <= y1
+            && !stored.contains(&(tile.x, tile.y))
+        {
+            coverage.push((tile.x, tile.y, TileCoverage::Failed));
+        }
+    }
+    Ok(coverage)
+}
+
+/// Tile range of the bbox, or the whole zoom level.
+pub fn get_coverage_range(
+    bbox: Option<&GeoBBOX>,
+    z: u8,
+) -> ((u64, u64), (u64, u64)) {
+    match bbox {
+        Some(bbox) => tile_range(bbox, z),
+        None => {
+            let max_extent = (1u64 << z) - 1;
+            ((0, 0), (max_extent, max_extent))
+        }
+    }
+}
+
+pub fn summarize_coverage(
+    server_name: &str,
+    z: u8,
+    range: ((u64, u64), (u64, u64)),
+    coverage: &[(u64, u64, TileCoverage)],
+) -> CoverageSummary {
+    let ((x0, y0), (x1, y1)) = range;
+    let mut tiles = HashMap::new();
+    for (_, _, state) in coverage {
+        *tiles.entry(*state).or_insert(0) += 1;
+    }
+    let total = (x1 - x0 + 1) * (y1 - y0 + 1);
+    CoverageSummary {
+        server_name: server_name.to_owned(),
+        z,
+        range,
+        tiles,
+        missing: total.saturating_sub(coverage.len() as u64),
+    }
+}
+
+/// Tiles per cell side for a range, so the PNG stays under
+/// [`COVERAGE_MAX_CELLS`].
+pub fn get_coverage_cell_size(range: ((u64, u64), (u64, u64))) -> u64 {
+    let ((x0, y0), (x1, y1)) = range;
+    let side = (x1 - x0 + 1).max(y1 - y0 + 1);
+    side.div_ceil(COVERAGE_MAX_CELLS).next_power_of_two()
+}
+
+/// Heat-map with one pixel per cell: green for cached, red for failed and
+/// grey for placeholder tiles, mixed by share; more opaque the more of the
+/// cell is known. Missing cells are transparent.
+pub fn render_coverage_png(
+    range: ((u64, u64), (u64, u64)),
+    coverage: &[(u64, u64, TileCoverage)],
+) -> Result<Vec<u8>> {
+    let ((x0, y0), (x1, y1)) = range;
+    let cell = get_coverage_cell_size(range);
+    let width = ((x1 - x0) / cell + 1) as u32;
+    let height = ((y1 - y0) / cell + 1) as u32;
+    let mut counts = vec![[0u64; 3]; (width * height) as usize];
+    for (x, y, state) in coverage {
+        let i = ((y - y0) / cell) as usize * width as usize
+            + ((x - x0) / cell) as usize;
+        counts[i][*state as usize] += 1;
+    }
+    let mut img = image::RgbaImage::new(width, height);
+    for (i, [cached, no_data, failed]) in counts.into_iter().enumerate() {
+        let known = cached + no_data + failed;
+        if known == 0 {
+            continue;
+        }
+        let share = |n: u64| (n * 255 / known) as u8;
+        let grey = share(no_data) / 2;
+        let alpha = 96 + (159 * known / (cell * cell)).min(159) as u8;
+        img.put_pixel(
+            i as u32 % width,
+            i as u32 / width,
+            image::Rgba([
+                share(failed).saturating_add(grey),
+                share(cached).saturating_add(grey),
+                grey,
+                alpha,
+            ]),
+        );
+    }
+    crate::download_tile::encode_tile_image(
+        image::DynamicImage::ImageRgba8(img),
+        "png",
+    )
+}
+
+/// One polygon feature per known tile, with `status` and z/x/y.
+pub fn coverage_geojson(
+    z: u8,
+    coverage: &[(u64, u64, TileCoverage)],
+) -> Result<serde_json::Value> {
+    if coverage.len() > COVERAGE_MAX_FEATURES {
+        anyhow::bail!(
+            "{} tiles is too many for geojson, use a smaller bbox or png",
+            coverage.len()
+        );
+    }
+    let features: Vec<serde_json::Value> = coverage
+        .iter()
+        .map(|(x, y, state)| {
+            let b = geo_bbox(*x, *y, z);
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[
+                        [b.x_min, b.y_min],
+                        [b.x_max, b.y_min],
+                        [b.x_max, b.y_max],
+                        [b.x_min, b.y_max],
+                        [b.x_min, b.y_min],
+                    ]],
+                },
+                "properties": {"status": state, "z": z, "x": x, "y": y},
+            })
+        })
+        .collect();
+    Ok(serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features,
+    }))
+}
@@ -0,0 +1,179 @@
+use anyhow::Context;
+use anyhow::Result;
+use std::collections::HashSet;
+use tokio::task::spawn_blocking;
+
+use crate::config;
+use crate::config::TileServerConfig;
+use crate::download_tile;
+use crate::download_tile::TileFetchId;
+use crate::elevation;
+use crate::geo_trig::tile_float_to_geo_point;
+
+/// Values of `TileServerConfig.dem_render`; the default is "hillshade".
+pub const DEM_RENDER_KINDS: &[&str] = &["hillshade", "slope", "aspect"];
+pub const DEFAULT_SUN_AZIMUTH: f64 = 315.0;
+pub const DEFAULT_SUN_ALTITUDE: f64 = 45.0;
+/// Rendered tiles go at most this many levels below the DEM download
+/// zoom, so one tile needs few DEM tiles.
+pub const DEM_RENDER_MAX_ZOOM_OUT: u32 = 2;
+/// DEM tiles under a tile at the max zoom out, plus the border pixels.
+const DEM_RENDER_MAX_DEM_TILES: usize =
+    ((1 << DEM_RENDER_MAX_ZOOM_OUT) + 2) * ((1 << DEM_RENDER_MAX_ZOOM_OUT) + 2);
+/// Slope rendered black, in degrees.
+const SLOPE_MAX_DEGREES: f64 = 60.0;
+/// Slope where aspect colors are fully saturated, in degrees.
+const ASPECT_FULL_SLOPE_DEGREES: f64 = 30.0;
+const WEB_MERCATOR_CIRCUMFERENCE: f64 = 40_075_016.686;
+
+pub fn get_dem_render(server_config: &TileServerConfig) -> &str {
+    server_config.dem_render.as_deref().unwrap_or("hillshade")
+}
+
+/// Lowest zoom a DEM derived server can render.
+pub fn get_dem_render_min_zoom(server_config: &TileServerConfig) -> Result<u8> {
+    let dem_server = server_config
+        .dem_server
+        .as_ref()
+        .context("not a dem tile server")?;
+    let topo_config = config::get_topography_server(dem_server)?;
+    Ok(topo_config
+        .download_zoomlevel
+        .saturating_sub(DEM_RENDER_MAX_ZOOM_OUT) as u8)
+}
+
+/// Slope and compass aspect (direction the slope faces), in radians, from
+/// a 3x3 window with Horn's method. `spacing` is the pixel size in meters.
+fn slope_aspect(window: &[f64; 9], spacing: f64) -> (f64, f64) {
+    let [a, b, c, d, _, f, g, h, i] = *window;
+    let dz_east = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / (8.0 * spacing);
+    let dz_south = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / (8.0 * spacing);
+    let slope = dz_east.hypot(dz_south).atan();
+    let aspect = (-dz_east).atan2(dz_south);
+    (slope, aspect.rem_euclid(std::f64::consts::TAU))
+}
+
+/// RGB of a hue in degrees, at full value.
+fn hue_to_rgb(hue: f64, saturation: f64) -> [u8; 3] {
+    let h = hue.rem_euclid(360.0) / 60.0;
+    let x = 1.0 - (h % 2.0 - 1.0).abs();
+    let (r, g, b) = match h as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    let mix = |v: f64| ((1.0 - saturation + saturation * v) * 255.0) as u8;
+    [mix(r), mix(g), mix(b)]
+}
+
+/// Hillshade, slope or aspect tile computed from the DEM tiles of the
+/// server's `dem_server`. Pixels without elevation data are transparent.
+pub async fn render_dem_tile(tile: &TileFetchId) -> Result<Vec<u8>> {
+    let server_config = config::get_tile_server(&tile.server_name)?;
+    let topo_config = config::get_topography_server(
+        server_config
+            .dem_server
+            .as_ref()
+            .context("not a dem tile server")?,
+    )?;
+    let (width, height) = (server_config.width, server_config.height);
+
+    // pixel centers, with a one pixel border for the 3x3 windows
+    let mut points = Vec::with_capacity(((width + 2) * (height + 2)) as usize);
+    for j in 0..height + 2 {
+        let ty = tile.y as f64 + (j as f64 - 0.5) / height as f64;
+        for i in 0..width + 2 {
+            let tx = tile.x as f64 + (i as f64 - 0.5) / width as f64;
+            let mut point = tile_float_to_geo_point(tile.z, tx, ty);
+            point.x_lon = point.x_lon.clamp(-180.0, 180.0);
+            points.push(point);
+        }
+    }
+    let dem_tiles: HashSet<(u64, u64)> = points
+        .iter()
+        .map(|p| elevation::get_dem_tile_index(&topo_config, p))
+        .collect();
+    if dem_tiles.len() > DEM_RENDER_MAX_DEM_TILES {
+        anyhow::bail!(
+            "{} dem tiles for {:?}, zoom in further",
+            dem_tiles.len(),
+            tile
+        );
+    }
+    let grids =
+        elevation::fetch_dem_grids_for_points(&topo_config, &points).await?;
+    if grids.is_empty() {
+        anyhow::bail!("no dem tiles for {:?}", tile);
+    }
+
+    let render = get_dem_render(&server_config).to_owned();
+    let sun_azimuth = server_config
+        .sun_azimuth
+        .unwrap_or(DEFAULT_SUN_AZIMUTH)
+        .to_radians();
+    let sun_zenith = (90.0_f64
+        - server_config.sun_altitude.unwrap_or(DEFAULT_SUN_ALTITUDE))
+    .to_radians();
+    let img_type = server_config.img_type.clone();
+    let tile_z = tile.z;
+    spawn_blocking(move || {
+        let stride = (width + 2) as usize;
+        let elevations: Vec<f64> = points
+            .iter()
+            .map(|p| {
+                elevation::sample_dem(&topo_config, &grids, p)
+                    .map_or(f64::NAN, |e| e as f64)
+            })
+            .collect();
+        let mut img = image::RgbaImage::new(width, height);
+        for j in 0..height as usize {
+            let lat = points[(j + 1) * stride].y_lat;
+            let spacing = WEB_MERCATOR_CIRCUMFERENCE * lat.to_radians().cos()
+                / (width as f64 * 2f64.powi(tile_z as i32));
+            for i in 0..width as usize {
+                let center = elevations[(j + 1) * stride + i + 1];
+                if center.is_nan() {
+                    continue;
+                }
+                let mut window = [center; 9];
+                for (k, value) in window.iter_mut().enumerate() {
+                    let e = elevations[(j + k / 3) * stride + i + k % 3];
+                    if !e.is_nan() {
+                        *value = e;
+                    }
+                }
+                let (slope, aspect) = slope_aspect(&window, spacing);
+                let [r, g, b] = match render.as_str() {
+                    "slope" => {
+                        let steep =
+                            (slope.to_degrees() / SLOPE_MAX_DEGREES).min(1.0);
+                        let v = ((1.0 - steep) * 255.0) as u8;
+                        [v, v, v]
+                    }
+                    "aspect" => hue_to_rgb(
+                        aspect.to_degrees(),
+                        (slope.to_degrees() / ASPECT_FULL_SLOPE_DEGREES)
+                            .min(1.0),
+                    ),
+                    _ => {
+                        let shade = sun_zenith.cos() * slope.cos()
+                            + sun_zenith.sin()
+                                * slope.sin()
+                                * (sun_azimuth - aspect).cos();
+                        let v = (shade.clamp(0.0, 1.0) * 255.0) as u8;
+                        [v, v, v]
+                    }
+                };
+                img.put_pixel(i as u32, j as u32, image::Rgba([r, g, b, 255]));
+            }
+        }
+        download_tile::encode_tile_image(
+            image::DynamicImage::ImageRgba8(img),
+            &img_type,
+        )
+    })
+    .await?
+}
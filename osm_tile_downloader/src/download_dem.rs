@@ -0,0 +1,474 @@
+use anyhow::Context;
+use anyhow::Result;
+use image::io::Reader as ImageReader;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::config::{TopographyServerConfig, LINKS_CONFIG};
+use crate::geo_trig::geo_bbox;
+use crate::proxy_manager::{download2, DownloadId};
+
+/// Formats of `TopographyServerConfig.format`; the default is "geotiff".
+pub const DEM_FORMATS: &[&str] = &["geotiff", "terrarium", "terrain_rgb"];
+
+/// Deepest ocean trench and highest peak, with some slack for datum and
+/// encoding differences. Decoded samples outside are rejected.
+pub const DEM_MIN_ELEVATION: f32 = -11_500.0;
+pub const DEM_MAX_ELEVATION: f32 = 9_000.0;
+/// GeoTIFF samples at or below this are nodata (SRTM uses -32768).
+const DEM_NODATA_BELOW: f32 = -30_000.0;
+
+/// Written for nodata samples of resampled GeoTIFF tiles.
+const DEM_NODATA_VALUE: f32 = -32_768.0;
+
+/// One DEM tile of a topography server, always at its `download_zoomlevel`.
+/// XYZ formats are fetched by `{z}/{x}/{y}`, GeoTIFF ones by the tile's
+/// `{south}`/`{north}`/`{west}`/`{east}` bounds.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct DemTileId {
+    pub server_name: String,
+    pub x: u64,
+    pub y: u64,
+    pub z: u8,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct DemTileSummary {
+    pub width: u32,
+    pub height: u32,
+    pub min_elevation: f32,
+    pub max_elevation: f32,
+    pub nodata_count: u64,
+}
+
+/// Decoded elevations in meters, row major from the north-west corner.
+/// Nodata samples are NaN.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DemGrid {
+    pub width: u32,
+    pub height: u32,
+    pub values: Vec<f32>,
+}
+
+pub fn get_dem_format(server_config: &TopographyServerConfig) -> &str {
+    server_config.format.as_deref().unwrap_or("geotiff")
+}
+
+fn get_dem_extension(format: &str) -> &'static str {
+    match format {
+        "geotiff" => "tif",
+        _ => "png",
+    }
+}
+
+/// Downloaded and resampled tiles share one tree, by zoom level.
+fn get_dem_tile_path(
+    server_config: &TopographyServerConfig,
+    x: u64,
+    y: u64,
+    z: u8,
+) -> PathBuf {
+    let extension = get_dem_extension(get_dem_format(server_config));
+    LINKS_CONFIG
+        .tile_location
+        .join("elevation")
+        .join(&server_config.name)
+        .join(z.to_string())
+        .join(x.to_string())
+        .join(format!("{}.{}", y, extension))
+}
+
+impl DemTileId {
+    pub fn get_server_config(&self) -> Result<TopographyServerConfig> {
+        config::get_topography_server(&self.server_name)
+    }
+}
+
+impl DownloadId for DemTileId {
+    type TParseResult = DemTileSummary;
+    fn get_version() -> usize {
+        0
+    }
+    fn is_valid_request(&self) -> Result<()> {
+        let server_config = self.get_server_config()?;
+        if self.z as u32 != server_config.download_zoomlevel {
+            anyhow::bail!(
+                "BAD ZOOM LEVEL {} for {}, expected {}",
+                self.z,
+                self.server_name,
+                server_config.download_zoomlevel
+            );
+        }
+        let max_extent = 2u64.pow(self.z.into()) - 1;
+        if !(self.x <= max_extent && self.y <= max_extent) {
+            anyhow::bail!(
+                "x={}, y={} not inside extent={} for z={}",
+                self.x,
+                self.y,
+                max_extent,
+                self.z
+            );
+        }
+        Ok(())
+    }
+    fn get_final_path(&self) -> Result<PathBuf> {
+        let server_config = self.get_server_config()?;
+        Ok(get_dem_tile_path(&server_config, self.x, self.y, self.z))
+    }
+    fn get_random_url(&self) -> Result<String> {
+        let server_config = self.get_server_config()?;
+        let bbox = geo_bbox(self.x, self.y, self.z);
+        let mut map: HashMap<String, String> = HashMap::with_capacity(10);
+        map.insert("x".to_owned(), self.x.to_string());
+        map.insert("y".to_owned(), self.y.to_string());
+        map.insert("z".to_owned(), self.z.to_string());
+        map.insert("south".to_owned(), bbox.y_min.to_string());
+        map.insert("north".to_owned(), bbox.y_max.to_string());
+        map.insert("west".to_owned(), bbox.x_min.to_string());
+        map.insert("east".to_owned(), bbox.x_max.to_string());
+        strfmt::strfmt(&server_config.url, &map).context("failed strfmt on URL")
+    }
+    fn parse_respose(&self, tmp_file: &Path) -> Result<Self::TParseResult> {
+        let server_config = self.get_server_config()?;
+        let grid = decode_dem(
+            &std::fs::read(tmp_file)?,
+            get_dem_format(&server_config),
+        )?;
+        let mut summary = DemTileSummary {
+            width: grid.width,
+            height: grid.height,
+            min_elevation: f32::MAX,
+            max_elevation: f32::MIN,
+            nodata_count: 0,
+        };
+        for value in grid.values.iter() {
+            if value.is_nan() {
+                summary.nodata_count += 1;
+                continue;
+            }
+            if !(DEM_MIN_ELEVATION..=DEM_MAX_ELEVATION).contains(value) {
+                anyhow::bail!(
+                    "elevation {} out of range {}..{}, wrong format?",
+                    value,
+                    DEM_MIN_ELEVATION,
+                    DEM_MAX_ELEVATION
+                );
+            }
+            summary.min_elevation = summary.min_elevation.min(*value);
+            summary.max_elevation = summary.max_elevation.max(*value);
+        }
+        if summary.nodata_count == grid.values.len() as u64 {
+            anyhow::bail!("dem tile has no data");
+        }
+        Ok(summary)
+    }
+}
+
+/// Decode Terrarium or Mapbox terrain-RGB PNGs, or a single band GeoTIFF.
+pub fn decode_dem(bytes: &[u8], format: &str) -> Result<DemGrid> {
+    if format == "geotiff" {
+        return decode_geotiff_dem(bytes);
+    }
+    let img = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?
+        .to_rgb8();
+    let decode_pixel: fn(&image::Rgb<u8>) -> f32 = match format {
+        "terrarium" => |p| {
+            p[0] as f32 * 256.0 + p[1] as f32 + p[2] as f32 / 256.0 - 32768.0
+        },
+        "terrain_rgb" => |p| {
+            let v = (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32;
+            -10_000.0 + v as f32 * 0.1
+        },
+        _ => anyhow::bail!("unknown dem format {:?}", format),
+    };
+    Ok(DemGrid {
+        width: img.width(),
+        height: img.height(),
+        values: img.pixels().map(decode_pixel).collect(),
+    })
+}
+
+fn decode_geotiff_dem(bytes: &[u8]) -> Result<DemGrid> {
+    use tiff::decoder::{Decoder, DecodingResult};
+    use tiff::tags::Tag;
+
+    let mut decoder = Decoder::new(Cursor::new(bytes))
+        .context("not a tiff, maybe an error page?")?;
+    let (width, height) = decoder.dimensions()?;
+    // GDAL writes the nodata value as text, e.g. "-9999"
+    let nodata: Option<f32> = match decoder.find_tag(Tag::GdalNodata)? {
+        Some(value) => {
+            value.into_string()?.trim_matches(['\0', ' ']).parse().ok()
+        }
+        None => None,
+    };
+    let samples = (width * height) as usize;
+    let values: Vec<f32> = match decoder.read_image()? {
+        DecodingResult::I16(v) => v.into_iter().map(f32::from).collect(),
+        DecodingResult::U16(v) => v.into_iter().map(f32::from).collect(),
+        DecodingResult::I32(v) => v.into_iter().map(|e| e as f32).collect(),
+        DecodingResult::F32(v) => v,
+        DecodingResult::F64(v) => v.into_iter().map(|e| e as f32).collect(),
+        _ => anyhow::bail!("unsupported geotiff sample format"),
+    };
+    if values.len() != samples {
+        anyhow::bail!(
+            "geotiff has {} bands, expected 1",
+            values.len() / samples
+        );
+    }
+    Ok(DemGrid {
+        width,
+        height,
+        values: values
+            .into_iter()
+            .map(|v| {
+                if v <= DEM_NODATA_BELOW || Some(v) == nodata {
+                    f32::NAN
+                } else {
+                    v
+                }
+            })
+            .collect(),
+    })
+}
+
+/// Encode a grid the way [`decode_dem`] reads it back. Nodata becomes
+/// the lowest value of the encoding.
+pub fn encode_dem(grid: &DemGrid, format: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    if format == "geotiff" {
+        use tiff::encoder::{colortype, TiffEncoder};
+        let values: Vec<f32> = grid
+            .values
+            .iter()
+            .map(|v| if v.is_nan() { DEM_NODATA_VALUE } else { *v })
+            .collect();
+        TiffEncoder::new(Cursor::new(&mut bytes))?
+            .write_image::<colortype::Gray32Float>(
+                grid.width,
+                grid.height,
+                &values,
+            )?;
+        return Ok(bytes);
+    }
+    let encode_pixel: fn(f32) -> [u8; 3] = match format {
+        "terrarium" => |e| {
+            let v = (e as f64 + 32768.0).max(0.0);
+            let whole = v.floor() as u32;
+            let frac = ((v - v.floor()) * 256.0) as u8;
+            [(whole >> 8).min(255) as u8, whole as u8, frac]
+        },
+        "terrain_rgb" => |e| {
+            let v = ((e as f64 + 10_000.0) * 10.0).round().max(0.0) as u32;
+            [(v >> 16) as u8, (v >> 8) as u8, v as u8]
+        },
+        _ => anyhow::bail!("unknown dem format {:?}", format),
+    };
+    let img = image::RgbImage::from_fn(grid.width, grid.height, |x, y| {
+        let value = grid.values[(y * grid.width + x) as usize];
+        image::Rgb(encode_pixel(if value.is_nan() { -1e9 } else { value }))
+    });
+    img.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)?;
+    Ok(bytes)
+}
+
+/// Fractional pixel in the ancestor tile `parent` under the center of
+/// pixel (i, j) of a `size` pixel child tile. XYZ tiles are in web
+/// mercator, GeoTIFF tiles are plain lat/lon over the tile bounds.
+fn scaled_source_pixel(
+    format: &str,
+    grid: &DemGrid,
+    parent: (u64, u64, u8),
+    child: (u64, u64, u8),
+    (i, j): (u32, u32),
+) -> (f64, f64) {
+    let (u, v) = (
+        (i as f64 + 0.5) / grid.width as f64,
+        (j as f64 + 0.5) / grid.height as f64,
+    );
+    if format == "geotiff" {
+        let c = geo_bbox(child.0, child.1, child.2);
+        let p = geo_bbox(parent.0, parent.1, parent.2);
+        let lon = c.x_min + u * (c.x_max - c.x_min);
+        let lat = c.y_max - v * (c.y_max - c.y_min);
+        return (
+            (lon - p.x_min) / (p.x_max - p.x_min) * grid.width as f64,
+            (p.y_max - lat) / (p.y_max - p.y_min) * grid.height as f64,
+        );
+    }
+    let scale = (1u64 << (child.2 - parent.2)) as f64;
+    (
+        ((child.0 as f64 + u) / scale - parent.0 as f64) * grid.width as f64,
+        ((child.1 as f64 + v) / scale - parent.1 as f64) * grid.height as f64,
+    )
+}
+
+/// Bilinear resample of the part of `grid` (tile `parent`) under tile
+/// `child`, at the same pixel size. Blocking.
+fn resample_dem(
+    format: &str,
+    grid: &DemGrid,
+    parent: (u64, u64, u8),
+    child: (u64, u64, u8),
+) -> DemGrid {
+    let mut values = Vec::with_capacity((grid.width * grid.height) as usize);
+    for j in 0..grid.height {
+        for i in 0..grid.width {
+            let (px, py) =
+                scaled_source_pixel(format, grid, parent, child, (i, j));
+            values.push(grid.sample(px, py).unwrap_or(f32::NAN));
+        }
+    }
+    DemGrid {
+        width: grid.width,
+        height: grid.height,
+        values,
+    }
+}
+
+/// Download (or read) one DEM tile and return its stored path. Tiles
+/// above `download_zoomlevel`, up to `scale_zoomlevel`, are resampled
+/// from their ancestor at `download_zoomlevel` and stored alongside.
+pub async fn get_dem_tile(
+    server_name: &str,
+    x: u64,
+    y: u64,
+    z: u8,
+) -> Result<PathBuf> {
+    let server_config = config::get_topography_server(server_name)?;
+    let download_z = server_config.download_zoomlevel as u8;
+    if z <= download_z {
+        let download_id = DemTileId {
+            server_name: server_name.to_owned(),
+            x,
+            y,
+            z,
+        };
+        download2(&download_id).await?;
+        return download_id.get_final_path();
+    }
+    if z as u32 > server_config.scale_zoomlevel {
+        anyhow::bail!(
+            "got z = {} when max for {} is scale_zoomlevel {}",
+            z,
+            server_name,
+            server_config.scale_zoomlevel
+        );
+    }
+    let max_extent = 2u64.pow(z.into()) - 1;
+    if !(x <= max_extent && y <= max_extent) {
+        anyhow::bail!(
+            "x={}, y={} not inside extent={} for z={}",
+            x,
+            y,
+            max_extent,
+            z
+        );
+    }
+    let path = get_dem_tile_path(&server_config, x, y, z);
+    if tokio::fs::metadata(&path).await.is_ok() {
+        return Ok(path);
+    }
+    let shift = z - download_z;
+    let parent = (x >> shift, y >> shift, download_z);
+    let parent_id = DemTileId {
+        server_name: server_name.to_owned(),
+        x: parent.0,
+        y: parent.1,
+        z: parent.2,
+    };
+    download2(&parent_id).await?;
+    let parent_path = parent_id.get_final_path()?;
+    let format = get_dem_format(&server_config).to_owned();
+    let bytes = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let grid = decode_dem(&std::fs::read(parent_path)?, &format)?;
+        let scaled = resample_dem(&format, &grid, parent, (x, y, z));
+        encode_dem(&scaled, &format)
+    })
+    .await??;
+    let temp_path = config::tmpdir().join(format!(
+        "{}.dem",
+        rand::Rng::gen::<u128>(&mut rand::thread_rng())
+    ));
+    tokio::fs::write(&temp_path, &bytes).await?;
+    tokio::fs::create_dir_all(path.parent().context("no parent")?).await?;
+    tokio::fs::rename(&temp_path, &path).await?;
+    Ok(path)
+}
+
+impl DemGrid {
+    /// Bilinear interpolation at fractional pixel coordinates, pixel
+    /// centers at +0.5, clamped to the grid. Nodata neighbours are left
+    /// out of the weights.
+    pub fn sample(&self, px: f64, py: f64) -> Option<f32> {
+        let fx = (px - 0.5).clamp(0.0, (self.width - 1) as f64);
+        let fy = (py - 0.5).clamp(0.0, (self.height - 1) as f64);
+        let (x0, y0) = (fx.floor() as u32, fy.floor() as u32);
+        let (x1, y1) =
+            ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (tx, ty) = (fx - x0 as f64, fy - y0 as f64);
+        let mut sum = 0.0;
+        let mut weight_sum = 0.0;
+        for (x, y, weight) in [
+            (x0, y0, (1.0 - tx) * (1.0 - ty)),
+            (x1, y0, tx * (1.0 - ty)),
+            (x0, y1, (1.0 - tx) * ty),
+            (x1, y1, tx * ty),
+        ] {
+            let value = self.values[(y * self.width + x) as usize];
+            if !value.is_nan() && weight > 0.0 {
+                sum += value as f64 * weight;
+                weight_sum += weight;
+            }
+        }
+        (weight_sum > 0.0).then(|| (sum / weight_sum) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_dem_round_trip() {
+        let grid = DemGrid {
+            width: 2,
+            height: 2,
+            values: vec![-412.5, 0.0, 8848.25, f32::NAN],
+        };
+        for format in DEM_FORMATS {
+            let decoded =
+                decode_dem(&encode_dem(&grid, format).unwrap(), format)
+                    .unwrap();
+            assert_eq!((decoded.width, decoded.height), (2, 2));
+            for (a, b) in grid.values[..3].iter().zip(decoded.values.iter()) {
+                assert!((a - b).abs() < 0.1, "{}: {} != {}", format, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_scaled_source_pixel() {
+        let grid = DemGrid {
+            width: 256,
+            height: 256,
+            values: vec![],
+        };
+        // bottom right child two levels down covers the last quarter
+        let (px, py) = scaled_source_pixel(
+            "terrarium",
+            &grid,
+            (1, 1, 1),
+            (7, 7, 3),
+            (0, 0),
+        );
+        assert!((px - 192.125).abs() < 1e-9 && (py - 192.125).abs() < 1e-9);
+    }
+}
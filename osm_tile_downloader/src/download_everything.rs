@@ -72,13 +72,18 @@ async fn download_all_tiles(
                     y = y,
                     z = z,
                     extension = ext.clone(),
+                    overzoom = _,
+                    format = _,
+                    quality = _,
+                    nodata = _,
+                    as_of = _,
                 ))
                 .path()
                 .to_string();
 
                 let rv = get_tile(&server_name, x, y, z, &ext).await;
-                let file_size_mb = if let Ok(p) = &rv {
-                    tokio::fs::metadata(p).await?.file_size() as f64
+                let file_size_mb = if let Ok(tile) = &rv {
+                    tile.size().await? as f64
                         / 1024.0
                         / 1024.0
                 } else {
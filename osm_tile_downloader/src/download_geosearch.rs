@@ -1,4 +1,6 @@
-use crate::geo_trig::{GeoBBOX, GeoPoint};
+use crate::geo_trig::{
+    line_centroid, polygon_bbox, polygon_centroid, GeoBBOX, GeoPoint,
+};
 use anyhow::Result;
 use std::collections::HashMap;
 use std::path::Path;
@@ -6,34 +8,189 @@ use std::path::PathBuf;
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use geojson::FeatureCollection;
 
 use crate::config::LINKS_CONFIG;
+use crate::offline_geocoder;
 use crate::proxy_manager::download2;
 use crate::proxy_manager::DownloadId;
 
+/// Most results Nominatim returns per request.
+pub const GEO_SEARCH_MAX_LIMIT: u32 = 40;
+
+/// Filters and paging passed to the `geo_search_url` template, both as
+/// `{limit}`, `{countrycodes}`, ... placeholders and all set ones as a
+/// ready `{filters}` query string suffix.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq, Default)]
+pub struct GeoSearchOptions {
+    pub limit: Option<u32>,
+    /// Nominatim pages by excluding the `place_id`s already seen.
+    pub exclude_place_ids: Option<String>,
+    /// Comma separated ISO 3166-1 alpha-2 codes.
+    pub countrycodes: Option<String>,
+    pub viewbox: Option<GeoBBOX>,
+    /// Ask for and keep the full result geometry.
+    pub polygon: bool,
+}
+
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 struct OSMGeolocationSearchQuery {
     query_str: String,
+    options: GeoSearchOptions,
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct OSMGeolocationSearchResult {
     pub display_name: String,
+    /// The point itself, or the centroid of a line or polygon result.
     pub geo_point: GeoPoint,
     pub bbox: GeoBBOX,
+    pub place_id: Option<u64>,
+    pub osm_type: Option<String>,
+    pub osm_id: Option<u64>,
+    pub class: Option<String>,
+    #[serde(rename = "type")]
+    pub place_type: Option<String>,
+    pub importance: Option<f64>,
+    /// Only with [`GeoSearchOptions::polygon`].
+    pub geometry: Option<geojson::Geometry>,
+}
+
+impl GeoSearchOptions {
+    fn validate(&self) -> Result<()> {
+        if let Some(limit) = self.limit {
+            if limit == 0 || limit > GEO_SEARCH_MAX_LIMIT {
+                anyhow::bail!(
+                    "limit must be 1 to {}, got {}",
+                    GEO_SEARCH_MAX_LIMIT,
+                    limit
+                );
+            }
+        }
+        let is_list = |s: &str, item: fn(&str) -> bool| s.split(',').all(item);
+        if let Some(ids) = &self.exclude_place_ids {
+            if ids.len() > 1024 || !is_list(ids, |id| id.parse::<u64>().is_ok())
+            {
+                anyhow::bail!("bad exclude_place_ids: {}", ids);
+            }
+        }
+        if let Some(codes) = &self.countrycodes {
+            if !is_list(codes, |c| {
+                c.len() == 2 && c.chars().all(|c| c.is_ascii_alphabetic())
+            }) {
+                anyhow::bail!("bad countrycodes: {}", codes);
+            }
+        }
+        Ok(())
+    }
+
+    fn template_values(&self) -> Vec<(&'static str, String)> {
+        let mut values = vec![];
+        if let Some(limit) = self.limit {
+            values.push(("limit", limit.to_string()));
+        }
+        if let Some(ids) = &self.exclude_place_ids {
+            values.push(("exclude_place_ids", ids.clone()));
+        }
+        if let Some(codes) = &self.countrycodes {
+            values.push(("countrycodes", codes.to_lowercase()));
+        }
+        if let Some(b) = &self.viewbox {
+            values.push((
+                "viewbox",
+                format!("{},{},{},{}", b.x_min, b.y_min, b.x_max, b.y_max),
+            ));
+        }
+        if self.polygon {
+            values.push(("polygon_geojson", "1".to_owned()));
+        }
+        values
+    }
+
+    /// `&key=value` for every set option.
+    fn filters(&self) -> String {
+        self.template_values()
+            .iter()
+            .map(|(k, v)| format!("&{}={}", k, urlencoding::encode(v)))
+            .collect()
+    }
+}
+
+/// Point, or the centroid of the largest part of a line or polygon
+/// geometry, with the bbox of all its coordinates.
+fn geometry_point_bbox(value: &geojson::Value) -> Result<(GeoPoint, GeoBBOX)> {
+    fn to_points(positions: &[geojson::Position]) -> Vec<GeoPoint> {
+        positions
+            .iter()
+            .filter(|c| c.len() >= 2)
+            .map(|c| GeoPoint {
+                x_lon: c[0],
+                y_lat: c[1],
+            })
+            .collect()
+    }
+    let (points, lines, rings) = match value {
+        geojson::Value::Point(c) => {
+            (to_points(std::slice::from_ref(c)), vec![], vec![])
+        }
+        geojson::Value::MultiPoint(c) => (to_points(c), vec![], vec![]),
+        geojson::Value::LineString(c) => (vec![], vec![to_points(c)], vec![]),
+        geojson::Value::MultiLineString(c) => {
+            (vec![], c.iter().map(|l| to_points(l)).collect(), vec![])
+        }
+        // outer rings only, holes barely move the centroid
+        geojson::Value::Polygon(c) => (
+            vec![],
+            vec![],
+            c.iter().take(1).map(|r| to_points(r)).collect(),
+        ),
+        geojson::Value::MultiPolygon(c) => (
+            vec![],
+            vec![],
+            c.iter()
+                .filter_map(|p| p.first())
+                .map(|r| to_points(r))
+                .collect(),
+        ),
+        geojson::Value::GeometryCollection(_) => {
+            anyhow::bail!("geometry collections are not supported")
+        }
+    };
+    let all: Vec<GeoPoint> = points
+        .iter()
+        .chain(lines.iter().flatten())
+        .chain(rings.iter().flatten())
+        .copied()
+        .collect();
+    let bbox = polygon_bbox(&all).context("geometry without coordinates")?;
+    let largest = |parts: Vec<Option<(GeoPoint, f64)>>| {
+        parts
+            .into_iter()
+            .flatten()
+            .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+            .map(|(p, _)| p)
+    };
+    let centroid = largest(rings.iter().map(|r| polygon_centroid(r)).collect())
+        .or_else(|| largest(lines.iter().map(|l| line_centroid(l)).collect()))
+        .unwrap_or(GeoPoint {
+            x_lon: (bbox.x_min + bbox.x_max) / 2.0,
+            y_lat: (bbox.y_min + bbox.y_max) / 2.0,
+        });
+    Ok((centroid, bbox))
 }
 
 impl DownloadId for OSMGeolocationSearchQuery {
     type TParseResult = Vec<OSMGeolocationSearchResult>;
     fn get_version() -> usize {
-        0
+        1
     }
     fn get_max_parallel() -> i64 {
         16
     }
     fn is_valid_request(&self) -> Result<()> {
+        self.options.validate()?;
         if self.query_str.len() >= 2 && self.query_str.len() <= 128 {
             return Ok(());
         }
@@ -46,8 +203,15 @@ impl DownloadId for OSMGeolocationSearchQuery {
         let query_urlencode =
             urlencoding::encode(self.query_str.as_str()).into_owned();
         let dir_path = LINKS_CONFIG.tile_location.join("geojson");
-        let path = dir_path.join(format!("{}.geo.json", query_urlencode));
-        Ok(path)
+        let filters = self.options.filters();
+        if filters.is_empty() {
+            return Ok(dir_path.join(format!("{}.geo.json", query_urlencode)));
+        }
+        // filters can get long, keep the file name short
+        let hash = Sha256::digest(filters.as_bytes());
+        let hash: String =
+            hash.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+        Ok(dir_path.join(format!("{}_{}.geo.json", query_urlencode, hash)))
     }
     fn get_random_url(&self) -> Result<String> {
         let url = {
@@ -55,6 +219,22 @@ impl DownloadId for OSMGeolocationSearchQuery {
                 urlencoding::encode(self.query_str.as_str()).into_owned();
             let mut map: HashMap<String, String> = HashMap::with_capacity(10);
             map.insert("q_urlencoded".to_owned(), query_urlencode.clone());
+            for key in [
+                "limit",
+                "exclude_place_ids",
+                "countrycodes",
+                "viewbox",
+                "polygon_geojson",
+            ] {
+                map.insert(key.to_owned(), "".to_owned());
+            }
+            for (key, value) in self.options.template_values() {
+                map.insert(
+                    key.to_owned(),
+                    urlencoding::encode(&value).into_owned(),
+                );
+            }
+            map.insert("filters".to_owned(), self.options.filters());
 
             strfmt::strfmt(&LINKS_CONFIG.geo_search_url, &map)
                 .context("failed strfmt on URL")?
@@ -70,40 +250,49 @@ impl DownloadId for OSMGeolocationSearchQuery {
         }
         let mut data = vec![];
         for feature in geo_collection.features.iter() {
-            let geo_point =
-                &feature.geometry.clone().context("no geometry?")?.value;
-            let geo_point = {
-                if let geojson::Value::Point(coords) = geo_point {
-                    (coords[0], coords[1])
-                } else {
-                    return Err(anyhow::anyhow!("geometry was not point - "));
-                }
-            };
-            let geo_point = GeoPoint {
-                x_lon: geo_point.0,
-                y_lat: geo_point.1,
-            };
+            let geometry = feature.geometry.clone().context("no geometry?")?;
+            let (geo_point, geometry_bbox) =
+                geometry_point_bbox(&geometry.value)?;
 
-            let bbox = feature.bbox.clone().context("no bbox")?;
-            let bbox = GeoBBOX {
-                x_min: bbox[0],
-                y_min: bbox[1],
-                x_max: bbox[2],
-                y_max: bbox[3],
+            let bbox = match feature.bbox.as_deref() {
+                Some([x_min, y_min, x_max, y_max, ..]) => GeoBBOX {
+                    x_min: *x_min,
+                    y_min: *y_min,
+                    x_max: *x_max,
+                    y_max: *y_max,
+                },
+                _ => geometry_bbox,
             };
 
-            let display_name = feature
-                .properties
-                .clone()
-                .context("no properties")?
-                .get("display_name")
-                .context("no display name?")?
-                .clone()
-                .to_string();
+            let properties =
+                feature.properties.clone().context("no properties")?;
+            let text = |key: &str| {
+                properties
+                    .get(key)
+                    .and_then(|v| v.as_str())
+                    .map(|v| v.to_owned())
+            };
+            let display_name =
+                text("display_name").context("no display name?")?;
+            let number = |key: &str| {
+                properties.get(key).and_then(|v| {
+                    v.as_u64().or_else(|| v.as_str()?.parse().ok())
+                })
+            };
             data.push(OSMGeolocationSearchResult {
                 bbox,
                 geo_point,
                 display_name,
+                place_id: number("place_id"),
+                osm_type: text("osm_type"),
+                osm_id: number("osm_id"),
+                // nominatim's geojson calls the class "category"
+                class: text("class").or_else(|| text("category")),
+                place_type: text("type"),
+                importance: properties
+                    .get("importance")
+                    .and_then(|v| v.as_f64()),
+                geometry: self.options.polygon.then_some(geometry),
             });
         }
         Ok(data)
@@ -112,20 +301,200 @@ impl DownloadId for OSMGeolocationSearchQuery {
 
 pub async fn search_geojson_to_disk(
     query_str: &str,
+    options: &GeoSearchOptions,
 ) -> Result<std::path::PathBuf> {
     let download_id = OSMGeolocationSearchQuery {
         query_str: query_str.to_string(),
+        options: options.clone(),
     };
     download2(&download_id).await?;
     download_id.get_final_path()
 }
 
+/// Online search, falling back to the offline geocoder over cached
+/// Overture data when the online one fails or is still pending.
 pub async fn search_geojson(
     query_str: &str,
+    options: &GeoSearchOptions,
 ) -> Result<Vec<OSMGeolocationSearchResult>> {
     let download_id = OSMGeolocationSearchQuery {
         query_str: query_str.to_string(),
+        options: options.clone(),
     };
-    let res = download2(&download_id).await?;
-    Ok(res)
+    match download2(&download_id).await {
+        Ok(res) => Ok(res),
+        Err(online_err) => {
+            eprintln!(
+                "geo search {:?} failed online, trying offline: {}",
+                query_str, online_err
+            );
+            let res = offline_geocoder::search_offline(
+                query_str,
+                options.viewbox.as_ref(),
+            )
+            .await?;
+            if res.is_empty() {
+                return Err(online_err);
+            }
+            Ok(res)
+        }
+    }
+}
+
+/// Default decimals for `reverse_geo_precision`.
+pub const DEFAULT_REVERSE_GEO_PRECISION: u32 = 4;
+
+/// Reverse geocoding of a coordinate rounded to `precision` decimals,
+/// stored as integers so equal lookups share one cache entry.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+struct ReverseGeocodeQuery {
+    lat_scaled: i64,
+    lon_scaled: i64,
+    precision: u32,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq, Default)]
+pub struct ReverseGeocodeAddress {
+    pub house_number: Option<String>,
+    pub road: Option<String>,
+    pub suburb: Option<String>,
+    /// City, town, village or hamlet, whichever the geocoder returns.
+    pub city: Option<String>,
+    pub county: Option<String>,
+    pub state: Option<String>,
+    pub postcode: Option<String>,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ReverseGeocodeResult {
+    pub display_name: String,
+    /// The matched feature, not the queried coordinate.
+    pub geo_point: Option<GeoPoint>,
+    pub address: ReverseGeocodeAddress,
+}
+
+impl ReverseGeocodeQuery {
+    fn new(point: &GeoPoint, precision: u32) -> Self {
+        let scale = 10f64.powi(precision as i32);
+        Self {
+            lat_scaled: (point.y_lat * scale).round() as i64,
+            lon_scaled: (point.x_lon * scale).round() as i64,
+            precision,
+        }
+    }
+    fn coordinate_strings(&self) -> (String, String) {
+        let scale = 10f64.powi(self.precision as i32);
+        let digits = self.precision as usize;
+        (
+            format!("{:.*}", digits, self.lat_scaled as f64 / scale),
+            format!("{:.*}", digits, self.lon_scaled as f64 / scale),
+        )
+    }
+}
+
+impl DownloadId for ReverseGeocodeQuery {
+    /// None where the geocoder found nothing, e.g. in the ocean.
+    type TParseResult = Option<ReverseGeocodeResult>;
+    fn get_version() -> usize {
+        0
+    }
+    fn get_max_parallel() -> i64 {
+        16
+    }
+    fn is_valid_request(&self) -> Result<()> {
+        let scale = 10f64.powi(self.precision as i32);
+        let (lat, lon) = (
+            self.lat_scaled as f64 / scale,
+            self.lon_scaled as f64 / scale,
+        );
+        if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+            return Ok(());
+        }
+        anyhow::bail!("coordinate out of range: lat={}, lon={}", lat, lon)
+    }
+    fn get_final_path(&self) -> Result<PathBuf> {
+        let (lat, lon) = self.coordinate_strings();
+        Ok(LINKS_CONFIG
+            .tile_location
+            .join("geojson")
+            .join("reverse")
+            .join(format!("{}_{}.geo.json", lat, lon)))
+    }
+    fn get_random_url(&self) -> Result<String> {
+        let (lat, lon) = self.coordinate_strings();
+        let mut map: HashMap<String, String> = HashMap::with_capacity(10);
+        map.insert("lat".to_owned(), lat);
+        map.insert("lon".to_owned(), lon);
+        let url = LINKS_CONFIG
+            .reverse_geo_url
+            .as_ref()
+            .context("no reverse_geo_url configured")?;
+        strfmt::strfmt(url, &map).context("failed strfmt on URL")
+    }
+    fn parse_respose(&self, tmp_file: &Path) -> Result<Self::TParseResult> {
+        let bytes = std::fs::read(tmp_file)?;
+        let value: serde_json::Value = serde_json::from_slice(&bytes)?;
+        // nominatim answers {"error": "Unable to geocode"} for no match
+        if value.get("error").is_some() {
+            return Ok(None);
+        }
+        let geo_collection = FeatureCollection::try_from(value)?;
+        let Some(feature) = geo_collection.features.first() else {
+            return Ok(None);
+        };
+        let properties = feature.properties.clone().context("no properties")?;
+        let display_name = properties
+            .get("display_name")
+            .and_then(|n| n.as_str())
+            .context("no display name?")?
+            .to_owned();
+        let geo_point = match feature.geometry.as_ref().map(|g| &g.value) {
+            Some(geojson::Value::Point(coords)) => Some(GeoPoint {
+                x_lon: coords[0],
+                y_lat: coords[1],
+            }),
+            _ => None,
+        };
+        let empty = serde_json::Map::new();
+        let address = properties
+            .get("address")
+            .and_then(|a| a.as_object())
+            .unwrap_or(&empty);
+        let field = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| address.get(*k).and_then(|v| v.as_str()))
+                .map(|v| v.to_owned())
+        };
+        Ok(Some(ReverseGeocodeResult {
+            display_name,
+            geo_point,
+            address: ReverseGeocodeAddress {
+                house_number: field(&["house_number"]),
+                road: field(&["road", "pedestrian", "footway", "path"]),
+                suburb: field(&["suburb", "neighbourhood", "quarter"]),
+                city: field(&["city", "town", "village", "hamlet"]),
+                county: field(&["county"]),
+                state: field(&["state"]),
+                postcode: field(&["postcode"]),
+                country: field(&["country"]),
+                country_code: field(&["country_code"]),
+            },
+        }))
+    }
+}
+
+/// Address at a point, through the `reverse_geo_url` geocoder; the point
+/// is rounded to `reverse_geo_precision` decimals first.
+pub async fn reverse_geocode(
+    point: &GeoPoint,
+) -> Result<Option<ReverseGeocodeResult>> {
+    if LINKS_CONFIG.reverse_geo_url.is_none() {
+        anyhow::bail!("reverse geocoding is off: no reverse_geo_url");
+    }
+    let precision = LINKS_CONFIG
+        .reverse_geo_precision
+        .unwrap_or(DEFAULT_REVERSE_GEO_PRECISION);
+    download2(&ReverseGeocodeQuery::new(point, precision)).await
 }
@@ -3,17 +3,28 @@ use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::Context;
+use image::imageops::FilterType;
 use image::io::Reader as ImageReader;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 
 use crate::config;
 use crate::config::{TileServerConfig, LINKS_CONFIG};
+use crate::contours;
+use crate::dem_render;
 use crate::geo_trig::tile_index_float;
+use crate::geo_trig::tile_range;
 use crate::geo_trig::xyz_to_bing_quadkey;
 use crate::geo_trig::{GeoBBOX, GeoPoint};
 use crate::proxy_manager;
 use crate::proxy_manager::DownloadId;
+use crate::stat_counter;
+use crate::tile_freshness;
+use crate::tile_placeholder;
+use crate::tile_provenance;
+use crate::tile_store;
+use crate::tile_store::StoredTile;
 
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct TileFetchId {
@@ -24,19 +35,52 @@ pub struct TileFetchId {
     pub extension: String,
 }
 
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub enum TileParseResult {
+    Imagery,
+    /// Valid image, but a provider placeholder or blank tile.
+    NoData(String),
+}
+
+/// Error for tiles stored as [`TileParseResult::NoData`], so the http
+/// layer can answer 404 instead of 500.
+#[derive(Debug)]
+pub struct NoDataTile {
+    pub reason: String,
+}
+
+impl std::fmt::Display for NoDataTile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no imagery in tile: {}", self.reason)
+    }
+}
+
+impl std::error::Error for NoDataTile {}
+
 impl TileFetchId {
     fn get_server_config(&self) -> Result<TileServerConfig> {
         config::get_tile_server(&self.server_name)
     }
+
+    /// Stored, and not classified as a placeholder.
+    async fn is_cached_imagery(&self) -> Result<bool> {
+        if !tile_store::contains_tile(self).await? {
+            return Ok(false);
+        }
+        Ok(!matches!(
+            proxy_manager::get_download_result(self)?,
+            Some(TileParseResult::NoData(_))
+        ))
+    }
 }
 
 impl DownloadId for TileFetchId {
-    type TParseResult = ();
+    type TParseResult = TileParseResult;
     fn get_max_parallel() -> i64 {
         777
     }
     fn get_version() -> usize {
-        0
+        1
     }
 
     fn is_valid_request(&self) -> Result<()> {
@@ -50,6 +94,17 @@ impl DownloadId for TileFetchId {
             );
         };
 
+        if server_config.dem_server.is_some() {
+            let min_z = dem_render::get_dem_render_min_zoom(&server_config)?;
+            if self.z < min_z {
+                anyhow::bail!(
+                    "got z = {} when min for dem server is {}",
+                    self.z,
+                    min_z
+                );
+            }
+        }
+
         if !(self.extension.eq(&server_config.img_type)) {
             anyhow::bail!(
                 "got extension = {} when server img_type is {}",
@@ -86,6 +141,32 @@ impl DownloadId for TileFetchId {
         Ok(target)
     }
 
+    fn is_stored(&self) -> Result<bool> {
+        tile_store::get_tile_store(&self.server_name)?.contains(self)
+    }
+
+    fn get_stored_file(&self, tmp_file: &Path) -> Result<Option<PathBuf>> {
+        let store = tile_store::get_tile_store(&self.server_name)?;
+        if let Some(path) = store.file_path(self)? {
+            return Ok(Some(path));
+        }
+        match store.read(self)? {
+            Some(bytes) => {
+                std::fs::write(tmp_file, bytes)?;
+                Ok(Some(tmp_file.to_owned()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn store_download(&self, tmp_file: &Path) -> Result<()> {
+        tile_store::get_tile_store(&self.server_name)?.put_file(self, tmp_file)
+    }
+
+    fn remove_stored(&self) -> Result<()> {
+        tile_store::get_tile_store(&self.server_name)?.remove(self)
+    }
+
     fn get_random_url(&self) -> anyhow::Result<String> {
         use rand::seq::SliceRandom;
         use std::collections::HashMap;
@@ -123,10 +204,7 @@ impl DownloadId for TileFetchId {
         let server_config = self.get_server_config()?;
         // let bytes = tokio::fs::read(img_path).await?;
         let bytes = std::fs::read(img_path)?;
-        let img_reader =
-            ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
-        let img_format = img_reader
-            .format()
+        let img_format = image::guess_format(&bytes)
             .context("auto-guesser failed to get img format")?;
         let found_extension = img_format
             .extensions_str()
@@ -135,22 +213,70 @@ impl DownloadId for TileFetchId {
         if !found_extension {
             anyhow::bail!("did not find our expected tile server extension = {:?} \n in list of auto-detected extensions = {:?}", server_config.img_type, img_format.extensions_str());
         }
-        let img = img_reader.decode()?;
-        if img.width() != server_config.width {
+        let img = decode_tile_image(&bytes)?;
+        let (width, height) = (img.width(), img.height());
+        if width != server_config.width {
             anyhow::bail!(
                 "image width not correct, expected {}, got {}",
                 server_config.width,
-                img.width()
+                width
             );
         }
-        if img.height() != server_config.height {
+        if height != server_config.height {
             anyhow::bail!(
                 "image width not correct, expected {}, got {}",
                 server_config.height,
-                img.height()
+                height
             );
         }
-        Ok(())
+        // rendered tiles are flat on purpose (sea, plains), not placeholders
+        let rendered = server_config.dem_server.is_some()
+            || config::is_pyramid_tile_server(&self.server_name);
+        if rendered {
+            return Ok(TileParseResult::Imagery);
+        }
+        tile_placeholder::classify_tile_image(&server_config, &img)
+    }
+
+    fn download_into(
+        &self,
+        tmp_file: &Path,
+    ) -> impl std::future::Future<Output = Result<Self::TParseResult>>
+           + std::marker::Send {
+        let tmp_file = PathBuf::from(tmp_file);
+        async move {
+            if !config::is_pyramid_tile_server(&self.server_name)
+                && self.get_server_config()?.dem_server.is_none()
+            {
+                let result =
+                    proxy_manager::download_in_parallel(self, &tmp_file)
+                        .await?;
+                if let Some(fetch_record) =
+                    proxy_manager::read_fetch_record(&tmp_file).await?
+                {
+                    tile_freshness::record_response(
+                        self,
+                        &fetch_record.response,
+                    )?;
+                    tile_provenance::record_provenance(
+                        self,
+                        &fetch_record,
+                        &tokio::fs::read(&tmp_file).await?,
+                    )?;
+                }
+                return Ok(result);
+            }
+            let img_bytes = if config::is_pyramid_tile_server(&self.server_name)
+            {
+                render_pyramid_tile(self).await?
+            } else {
+                dem_render::render_dem_tile(self).await?
+            };
+            tokio::fs::write(&tmp_file, &img_bytes).await?;
+            let download_id = self.clone();
+            spawn_blocking(move || download_id.parse_respose(&tmp_file))
+                .await?
+        }
     }
 }
 
@@ -160,7 +286,7 @@ pub async fn get_tile(
     y: u64,
     z: u8,
     extension: &str,
-) -> Result<PathBuf> {
+) -> Result<StoredTile> {
     let fetch_info = TileFetchId {
         x,
         y,
@@ -168,8 +294,531 @@ pub async fn get_tile(
         server_name: server_name.to_owned(),
         extension: extension.to_owned(),
     };
-    proxy_manager::download2(&fetch_info).await?;
-    fetch_info.get_final_path()
+    let parse_result = proxy_manager::download2(&fetch_info).await?;
+    // stale tiles are still served, the refresh runs in the background
+    tile_freshness::refresh_if_stale(&fetch_info)?;
+    if let TileParseResult::NoData(reason) = parse_result {
+        return Err(NoDataTile { reason }.into());
+    }
+    tile_store::get_stored_tile(&fetch_info)
+        .await?
+        .with_context(|| format!("tile missing from store: {:?}", &fetch_info))
+}
+
+/// Cached imagery tiles of one zoom level inside the tile range, as listed
+/// by the server's tile store. Placeholder tiles are left out. Blocking;
+/// run from `spawn_blocking`.
+pub fn list_cached_imagery(
+    server_name: &str,
+    z: u8,
+    range: ((u64, u64), (u64, u64)),
+) -> Result<Vec<TileFetchId>> {
+    let server_config = config::get_tile_server(server_name)?;
+    let store = tile_store::get_tile_store(server_name)?;
+    let mut tiles = vec![];
+    for (x, y) in store.list(server_name, z, range)? {
+        let tile = TileFetchId {
+            x,
+            y,
+            z,
+            server_name: server_name.to_owned(),
+            extension: server_config.img_type.clone(),
+        };
+        if !matches!(
+            proxy_manager::get_download_result(&tile)?,
+            Some(TileParseResult::NoData(_))
+        ) {
+            tiles.push(tile);
+        }
+    }
+    tiles.sort_by_key(|t| (t.x, t.y));
+    Ok(tiles)
+}
+
+pub const MAX_OVERZOOM_LEVELS: u8 = 8;
+
+pub fn parse_overzoom_filter(name: &str) -> Result<FilterType> {
+    Ok(match name {
+        "nearest" => FilterType::Nearest,
+        "triangle" => FilterType::Triangle,
+        "catmullrom" => FilterType::CatmullRom,
+        "gaussian" => FilterType::Gaussian,
+        "lanczos3" => FilterType::Lanczos3,
+        _ => anyhow::bail!(
+            "bad overzoom filter '{}', expected one of: \
+            nearest, triangle, catmullrom, gaussian, lanczos3",
+            name
+        ),
+    })
+}
+
+/// No avif: the image crate can only decode it with the native dav1d
+/// feature, and a format we cannot decode cannot be overlaid, stitched or
+/// transcoded.
+pub const TILE_IMG_TYPES: [&str; 3] = ["png", "jpg", "webp"];
+
+pub fn tile_image_format(img_type: &str) -> Result<image::ImageFormat> {
+    Ok(match img_type {
+        "png" => image::ImageFormat::Png,
+        "jpg" => image::ImageFormat::Jpeg,
+        "webp" => image::ImageFormat::WebP,
+        _ => anyhow::bail!("bad format: {}", img_type),
+    })
+}
+
+pub fn encode_tile_image(
+    img: image::DynamicImage,
+    img_type: &str,
+) -> Result<Vec<u8>> {
+    encode_tile_image_with_quality(img, img_type, None)
+}
+
+/// Encode with an optional 1-100 quality. Without quality, webp is lossless
+/// and png ignores it anyway.
+pub fn encode_tile_image_with_quality(
+    img: image::DynamicImage,
+    img_type: &str,
+    quality: Option<u8>,
+) -> Result<Vec<u8>> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::ImageFormat;
+
+    if let Some(quality) = quality {
+        if !(1..=100).contains(&quality) {
+            anyhow::bail!("quality {} not in 1..=100", quality);
+        }
+    }
+    let image_format = tile_image_format(img_type)?;
+    let mut img_bytes: Vec<u8> = Vec::new();
+    match (image_format, quality) {
+        (ImageFormat::Jpeg, quality) => {
+            // jpeg encoder refuses alpha channels
+            let img = image::DynamicImage::ImageRgb8(img.into_rgb8());
+            let encoder = match quality {
+                Some(quality) => {
+                    JpegEncoder::new_with_quality(&mut img_bytes, quality)
+                }
+                None => JpegEncoder::new(&mut img_bytes),
+            };
+            img.write_with_encoder(encoder)?;
+        }
+        (ImageFormat::WebP, Some(quality)) => {
+            let img = img.into_rgba8();
+            let webp_bytes =
+                webp::Encoder::from_rgba(&img, img.width(), img.height())
+                    .encode(quality as f32);
+            img_bytes.extend_from_slice(&webp_bytes);
+        }
+        (image_format, _) => {
+            img.write_to(&mut Cursor::new(&mut img_bytes), image_format)?;
+        }
+    }
+    Ok(img_bytes)
+}
+
+/// Decode a cached or downloaded tile. AVIF is refused up front, since
+/// without the dav1d decoder the image crate fails with a vague error;
+/// see [`TILE_IMG_TYPES`].
+pub fn decode_tile_image(bytes: &[u8]) -> Result<image::DynamicImage> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    if reader.format() == Some(image::ImageFormat::Avif) {
+        anyhow::bail!("cannot decode avif tiles: no avif decoder built in");
+    }
+    Ok(reader.decode()?)
+}
+
+/// Re-encode a cached tile into another format, cached next to the others
+/// under `tile_location/transcoded/<format>_q<quality>/...`. The variant is
+/// rebuilt when the source tile is newer.
+pub async fn get_transcoded_tile(
+    source_path: &Path,
+    img_type: &str,
+    quality: Option<u8>,
+) -> Result<PathBuf> {
+    tile_image_format(img_type)?;
+    let relative = source_path
+        .strip_prefix(&LINKS_CONFIG.tile_location)
+        .context("tile not inside tile_location")?;
+    let variant = match quality {
+        Some(quality) => format!("{}_q{}", img_type, quality),
+        None => img_type.to_owned(),
+    };
+    let final_path = LINKS_CONFIG
+        .tile_location
+        .join("transcoded")
+        .join(variant)
+        .join(relative)
+        .with_extension(img_type);
+
+    let source_meta = tokio::fs::metadata(source_path).await?;
+    if let Ok(final_meta) = tokio::fs::metadata(&final_path).await {
+        if final_meta.modified()? >= source_meta.modified()? {
+            return Ok(final_path);
+        }
+    }
+
+    let bytes = tokio::fs::read(source_path).await?;
+    let img_bytes = transcode_tile_bytes(bytes, img_type, quality).await?;
+    write_tile_file(&final_path, &img_bytes).await?;
+    Ok(final_path)
+}
+
+/// Re-encode tile bytes without caching, for tiles that are not files.
+pub async fn transcode_tile_bytes(
+    bytes: Vec<u8>,
+    img_type: &str,
+    quality: Option<u8>,
+) -> Result<Vec<u8>> {
+    let img_type = img_type.to_owned();
+    spawn_blocking(move || {
+        let img = decode_tile_image(&bytes)?;
+        encode_tile_image_with_quality(img, &img_type, quality)
+    })
+    .await?
+}
+
+/// Write through a temp file, so readers never see half-written tiles.
+async fn write_tile_file(final_path: &Path, img_bytes: &[u8]) -> Result<()> {
+    let rand_name =
+        format!("{}.tile_final", rand::thread_rng().gen::<u128>());
+    let temp_path = config::tmpdir().join(rand_name);
+    tokio::fs::write(&temp_path, img_bytes).await?;
+    let final_parent = final_path.parent().expect("final path has no parent");
+    tokio::fs::create_dir_all(&final_parent).await?;
+    tokio::fs::rename(&temp_path, final_path).await?;
+    Ok(())
+}
+
+/// Fully transparent png with the server tile size, for placeholder tiles.
+pub async fn get_transparent_tile(
+    server_config: &TileServerConfig,
+) -> Result<PathBuf> {
+    let (width, height) = (server_config.width, server_config.height);
+    let path = LINKS_CONFIG
+        .tile_location
+        .join("nodata")
+        .join(format!("{}x{}.png", width, height));
+    if tokio::fs::metadata(&path).await.is_ok() {
+        return Ok(path);
+    }
+    let img = image::DynamicImage::ImageRgba8(image::RgbaImage::new(
+        width, height,
+    ));
+    let img_bytes = spawn_blocking(move || encode_tile_image(img, "png"))
+        .await??;
+    write_tile_file(&path, &img_bytes).await?;
+    Ok(path)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverzoomTile {
+    pub path: PathBuf,
+    /// Stored tile the overzoomed one was cut from.
+    pub ancestor: TileFetchId,
+}
+
+fn get_overzoom_path(
+    server_config: &TileServerConfig,
+    x: u64,
+    y: u64,
+    z: u8,
+    ancestor_z: u8,
+    filter_name: &str,
+) -> PathBuf {
+    let mut target = LINKS_CONFIG
+        .tile_location
+        .join("overzoom")
+        .join(&server_config.map_type)
+        .join(&server_config.name)
+        .join(filter_name)
+        .join(format!("from_z{}", ancestor_z))
+        .join(z.to_string())
+        .join(x.to_string());
+    target.push(format!("{}.{}", y, server_config.img_type));
+    target
+}
+
+enum OverzoomSource {
+    /// Already cut from this ancestor.
+    Cached(TileFetchId),
+    /// Cut it from this stored ancestor.
+    Ancestor(TileFetchId),
+}
+
+/// Walk the ancestors of (x, y, z) from max_level up. At each level the
+/// cached overzoom copy is checked before the tile store, so a cached
+/// tile cut from max_level costs one stat. If nothing is cached, queue
+/// the ancestor at max_level and return its error.
+async fn find_overzoom_source(
+    server_config: &TileServerConfig,
+    x: u64,
+    y: u64,
+    z: u8,
+    filter_name: &str,
+) -> Result<OverzoomSource> {
+    let ancestor_at = |ancestor_z: u8| TileFetchId {
+        x: x >> (z - ancestor_z),
+        y: y >> (z - ancestor_z),
+        z: ancestor_z,
+        server_name: server_config.name.clone(),
+        extension: server_config.img_type.clone(),
+    };
+    for ancestor_z in (0..=server_config.max_level).rev() {
+        let ancestor = ancestor_at(ancestor_z);
+        let path =
+            get_overzoom_path(server_config, x, y, z, ancestor_z, filter_name);
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(OverzoomSource::Cached(ancestor));
+        }
+        if ancestor.is_cached_imagery().await? {
+            return Ok(OverzoomSource::Ancestor(ancestor));
+        }
+    }
+    let ancestor = ancestor_at(server_config.max_level);
+    if let TileParseResult::NoData(reason) =
+        proxy_manager::download2(&ancestor).await?
+    {
+        return Err(NoDataTile { reason }.into());
+    }
+    Ok(OverzoomSource::Ancestor(ancestor))
+}
+
+pub async fn get_overzoom_tile(
+    server_name: &str,
+    x: u64,
+    y: u64,
+    z: u8,
+    extension: &str,
+    filter_name: &str,
+) -> Result<OverzoomTile> {
+    let server_config = config::get_tile_server(server_name)?;
+    let filter = parse_overzoom_filter(filter_name)?;
+    if !extension.eq(&server_config.img_type) {
+        anyhow::bail!(
+            "got extension = {} when server img_type is {}",
+            extension,
+            &server_config.img_type
+        );
+    }
+    if z <= server_config.max_level
+        || z - server_config.max_level > MAX_OVERZOOM_LEVELS
+    {
+        anyhow::bail!(
+            "overzoom z = {} must be in {}..={} for server {}",
+            z,
+            server_config.max_level + 1,
+            server_config.max_level as u16 + MAX_OVERZOOM_LEVELS as u16,
+            server_name
+        );
+    }
+    let max_extent = 2u64.pow(z.into()) - 1;
+    if !(x <= max_extent && y <= max_extent) {
+        anyhow::bail!(
+            "x={}, y={} not inside extent={} for z={}",
+            x,
+            y,
+            max_extent,
+            z
+        );
+    }
+
+    let ancestor =
+        match find_overzoom_source(&server_config, x, y, z, filter_name)
+            .await?
+        {
+            OverzoomSource::Cached(ancestor) => {
+                let path = get_overzoom_path(
+                    &server_config,
+                    x,
+                    y,
+                    z,
+                    ancestor.z,
+                    filter_name,
+                );
+                return Ok(OverzoomTile { path, ancestor });
+            }
+            OverzoomSource::Ancestor(ancestor) => ancestor,
+        };
+    let final_path =
+        get_overzoom_path(&server_config, x, y, z, ancestor.z, filter_name);
+
+    let dz = z - ancestor.z;
+    let crop_w = server_config.width >> dz;
+    let crop_h = server_config.height >> dz;
+    if crop_w == 0 || crop_h == 0 {
+        anyhow::bail!(
+            "cannot overzoom {} levels from z={}: tile smaller than 1px",
+            dz,
+            ancestor.z
+        );
+    }
+    let crop_x = (x - (ancestor.x << dz)) as u32 * crop_w;
+    let crop_y = (y - (ancestor.y << dz)) as u32 * crop_h;
+
+    let bytes = tile_store::read_tile(&ancestor)
+        .await?
+        .with_context(|| format!("tile missing from store: {:?}", &ancestor))?;
+    let img_type = server_config.img_type.clone();
+    let (width, height) = (server_config.width, server_config.height);
+    let img_bytes = spawn_blocking(move || {
+        let img = decode_tile_image(&bytes)?;
+        let img = img
+            .crop_imm(crop_x, crop_y, crop_w, crop_h)
+            .resize_exact(width, height, filter);
+        encode_tile_image(img, &img_type)
+    })
+    .await??;
+
+    write_tile_file(&final_path, &img_bytes).await?;
+
+    Ok(OverzoomTile {
+        path: final_path,
+        ancestor,
+    })
+}
+
+const PYRAMID_FILTER: FilterType = FilterType::Triangle;
+pub const PYRAMID_MAX_TILES: u64 = 1 << 16;
+
+/// Stitch the four cached z+1 children of a pyramid tile and downsample.
+/// Children are taken from the pyramid itself first, then from the source
+/// server. Missing children are left transparent (black for jpg).
+async fn render_pyramid_tile(tile: &TileFetchId) -> Result<Vec<u8>> {
+    let server_config = tile.get_server_config()?;
+    let source_name = tile
+        .server_name
+        .strip_suffix(config::PYRAMID_SUFFIX)
+        .context("not a pyramid tile server")?;
+
+    let mut children = vec![];
+    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        for server_name in [tile.server_name.as_str(), source_name] {
+            let child = TileFetchId {
+                x: tile.x * 2 + dx,
+                y: tile.y * 2 + dy,
+                z: tile.z + 1,
+                server_name: server_name.to_owned(),
+                extension: tile.extension.clone(),
+            };
+            if !child.is_cached_imagery().await? {
+                continue;
+            }
+            if let Ok(Some(bytes)) = tile_store::read_tile(&child).await {
+                children.push((dx as u32, dy as u32, bytes));
+                break;
+            }
+        }
+    }
+    if children.is_empty() {
+        anyhow::bail!("no cached children for pyramid tile {:?}", tile);
+    }
+
+    let (width, height) = (server_config.width, server_config.height);
+    let img_type = server_config.img_type.clone();
+    spawn_blocking(move || {
+        let mut canvas = image::RgbaImage::new(width * 2, height * 2);
+        for (dx, dy, bytes) in children {
+            let child = decode_tile_image(&bytes)?.into_rgba8();
+            image::imageops::replace(
+                &mut canvas,
+                &child,
+                (dx * width) as i64,
+                (dy * height) as i64,
+            );
+        }
+        let img =
+            image::imageops::resize(&canvas, width, height, PYRAMID_FILTER);
+        encode_tile_image(image::DynamicImage::ImageRgba8(img), &img_type)
+    })
+    .await?
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct PyramidBuildSummary {
+    pub server_name: String,
+    pub built_count: u64,
+    pub skipped_count: u64,
+    /// (z, built, skipped) for every level, bottom-up
+    pub levels: Vec<(u8, u64, u64)>,
+}
+
+/// Build pyramid levels `min_z..max_z` over the bbox, bottom-up, from the
+/// cached `max_z` tiles of the source server.
+pub async fn build_pyramid(
+    source_name: &str,
+    bbox: &GeoBBOX,
+    min_z: u8,
+    max_z: u8,
+) -> Result<PyramidBuildSummary> {
+    let source_config = config::get_tile_server(source_name)?;
+    let pyramid_config = config::pyramid_tile_server(&source_config)?;
+    if !(min_z < max_z && max_z <= source_config.max_level) {
+        anyhow::bail!(
+            "bad pyramid zoom range {}..{} for server {} with max_level {}",
+            min_z,
+            max_z,
+            source_name,
+            source_config.max_level
+        );
+    }
+    let tile_count: u64 = (min_z..max_z)
+        .map(|z| {
+            let ((x0, y0), (x1, y1)) = tile_range(bbox, z);
+            (x1 - x0 + 1) * (y1 - y0 + 1)
+        })
+        .sum();
+    if tile_count > PYRAMID_MAX_TILES {
+        anyhow::bail!(
+            "too many tiles for pyramid z={}..{}: {} > {}",
+            min_z,
+            max_z - 1,
+            tile_count,
+            PYRAMID_MAX_TILES
+        );
+    }
+
+    let mut summary = PyramidBuildSummary {
+        server_name: pyramid_config.name.clone(),
+        built_count: 0,
+        skipped_count: 0,
+        levels: vec![],
+    };
+    for z in (min_z..max_z).rev() {
+        let ((x0, y0), (x1, y1)) = tile_range(bbox, z);
+        let (mut built, mut skipped) = (0, 0);
+        for x in x0..=x1 {
+            for y in y0..=y1 {
+                let tile = TileFetchId {
+                    x,
+                    y,
+                    z,
+                    server_name: pyramid_config.name.clone(),
+                    extension: pyramid_config.img_type.clone(),
+                };
+                let event = if let Ok(img_bytes) =
+                    render_pyramid_tile(&tile).await
+                {
+                    tile_store::write_tile(&tile, &img_bytes).await?;
+                    proxy_manager::clear_download_entry(&tile)?;
+                    built += 1;
+                    "built"
+                } else {
+                    skipped += 1;
+                    "skipped"
+                };
+                stat_counter::stat_counter_increment(
+                    "pyramid_build",
+                    event,
+                    &pyramid_config.name,
+                    &z.to_string(),
+                )?;
+            }
+        }
+        summary.built_count += built;
+        summary.skipped_count += skipped;
+        summary.levels.push((z, built, skipped));
+    }
+    Ok(summary)
 }
 
 use tokio::task::spawn_blocking;
@@ -178,6 +827,12 @@ use tokio::task::spawn_blocking;
 pub struct OverlayDrawCoordinates {
     pub point: Option<GeoPoint>,
     pub bbox: Option<GeoBBOX>,
+    /// Draw contour lines every this many meters.
+    pub contour_interval: Option<f64>,
+    /// Topography server for the contours, default the finest one.
+    pub contour_server: Option<String>,
+    /// Reverse geocode `point`; the address goes into a response header.
+    pub reverse_geocode: Option<bool>,
 }
 
 pub async fn draw_overlay_on_tile(
@@ -185,22 +840,19 @@ pub async fn draw_overlay_on_tile(
     y: u64,
     z: u8,
     img_type: &str,
-    path: &Path,
+    bytes: Vec<u8>,
     overlay_coordinates: &OverlayDrawCoordinates,
     server_config: &TileServerConfig,
 ) -> Result<Vec<u8>> {
-    let bytes = tokio::fs::read(path).await?;
-
-    let img_reader =
-        ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
-    let img = img_reader.decode()?;
-    let image_format = match img_type {
-        "png" => image::ImageFormat::Png,
-        "jpg" => image::ImageFormat::Jpeg,
-        _ => anyhow::bail!("bad format: {}", img_type),
-    };
-    let b_px = overlay_coordinates.point.context("no point coord!")?;
-    let b_px = tile_index_float(z, b_px.x_lon, b_px.y_lat);
+    let img = decode_tile_image(&bytes)?;
+    tile_image_format(img_type)?;
+    let img_type = img_type.to_owned();
+    if overlay_coordinates.point.is_none()
+        && overlay_coordinates.bbox.is_none()
+        && overlay_coordinates.contour_interval.is_none()
+    {
+        anyhow::bail!("nothing to draw: need point, bbox or contour_interval");
+    }
 
     let tile2pixel = |point: (f64, f64)| {
         (
@@ -208,35 +860,76 @@ pub async fn draw_overlay_on_tile(
             ((point.1 - y as f64) * server_config.width as f64) as i32,
         )
     };
-    let b_px = tile2pixel(b_px);
-
-    let b_bbox = overlay_coordinates.bbox.context("no bbox")?;
-    let bbox0 = tile_index_float(z, b_bbox.x_min, b_bbox.y_min);
-    let bbox1 = tile_index_float(z, b_bbox.x_max, b_bbox.y_max);
-    let bbox0 = tile2pixel(bbox0);
-    let bbox1 = tile2pixel(bbox1);
-    let b_bbox = [bbox0, bbox1, (bbox1.0, bbox0.1), (bbox0.0, bbox1.1)];
+    let b_px = overlay_coordinates
+        .point
+        .map(|p| tile2pixel(tile_index_float(z, p.x_lon, p.y_lat)));
+    let b_bbox = overlay_coordinates.bbox.map(|b_bbox| {
+        let bbox0 = tile_index_float(z, b_bbox.x_min, b_bbox.y_min);
+        let bbox1 = tile_index_float(z, b_bbox.x_max, b_bbox.y_max);
+        [tile2pixel(bbox0), tile2pixel(bbox1)]
+    });
 
     // eprintln!("point: {:?}  bbox: {:?}", b_px, b_bbox);
 
+    let contour_size = server_config.width;
+    let contour_grid = match overlay_coordinates.contour_interval {
+        Some(interval) => Some((
+            interval,
+            contours::get_tile_corner_elevations(
+                overlay_coordinates.contour_server.as_deref(),
+                z,
+                x,
+                y,
+                contour_size,
+            )
+            .await?,
+        )),
+        None => None,
+    };
+
     let img_bytes = spawn_blocking(move || {
         let mut img = img.into_rgb8();
-        // let b_px: (i32, i32) = (127, 127);
-        // let b_bbox: (i32, i32, i32, i32) = (32, 32, 172, 172);
-        let line_len: i32 = 10;
-        for pixel in img.enumerate_pixels_mut() {
-            let current_pixel = (pixel.0 as i32, pixel.1 as i32);
-
-            let hit_point_cross = |cxx: (i32, i32)| {
-                (current_pixel.0 - cxx.0 == current_pixel.1 - cxx.1
+        if let Some((interval, grid)) = contour_grid {
+            let lines = contours::marching_squares(
+                &grid,
+                contour_size as usize,
+                interval,
+            )?;
+            contours::draw_contour_lines(&mut img, &lines, contour_size);
+        }
+        draw_overlay_pixels(&mut img, b_px, b_bbox);
+        encode_tile_image(image::DynamicImage::ImageRgb8(img), &img_type)
+    })
+    .await??;
+    Ok(img_bytes)
+}
+
+/// Draw a cross at `point` and the lines through the corners of `bbox`,
+/// both already in image pixel coordinates.
+pub fn draw_overlay_pixels(
+    img: &mut image::RgbImage,
+    point: Option<(i32, i32)>,
+    bbox: Option<[(i32, i32); 2]>,
+) {
+    // let b_px: (i32, i32) = (127, 127);
+    // let b_bbox: (i32, i32, i32, i32) = (32, 32, 172, 172);
+    let line_len: i32 = 10;
+    for pixel in img.enumerate_pixels_mut() {
+        let current_pixel = (pixel.0 as i32, pixel.1 as i32);
+
+        let hit_point_cross = |cxx: (i32, i32)| {
+            (current_pixel.0 - cxx.0 == current_pixel.1 - cxx.1
+                && (current_pixel.0 - cxx.0).abs() <= line_len)
+                || (current_pixel.0 - cxx.0 == -current_pixel.1 + cxx.1
                     && (current_pixel.0 - cxx.0).abs() <= line_len)
-                    || (current_pixel.0 - cxx.0 == -current_pixel.1 + cxx.1
-                        && (current_pixel.0 - cxx.0).abs() <= line_len)
-            };
+        };
 
+        if let Some(b_px) = point {
             if hit_point_cross(b_px) {
                 *pixel.2 = pixel_max_contrast(pixel.2);
             }
+        }
+        if let Some(b_bbox) = bbox {
             if current_pixel.0 == b_bbox[0].0
                 || current_pixel.0 == b_bbox[1].0
                 || current_pixel.1 == b_bbox[0].1
@@ -245,14 +938,7 @@ pub async fn draw_overlay_on_tile(
                 *pixel.2 = pixel_max_contrast(pixel.2);
             }
         }
-
-        let mut img_bytes: Vec<u8> = Vec::new();
-        img.write_to(&mut Cursor::new(&mut img_bytes), image_format)
-            .unwrap();
-        img_bytes
-    })
-    .await?;
-    Ok(img_bytes)
+    }
 }
 
 fn pixel_max_contrast(px: &image::Rgb<u8>) -> image::Rgb<u8> {
@@ -262,3 +948,19 @@ fn pixel_max_contrast(px: &image::Rgb<u8>) -> image::Rgb<u8> {
         if px.0[2] > 127 { 0 } else { 255 },
     ])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_avif_rejected() {
+        assert!(!TILE_IMG_TYPES.contains(&"avif"));
+        assert!(tile_image_format("avif").is_err());
+        let img = image::RgbImage::new(8, 8);
+        let err =
+            encode_tile_image(image::DynamicImage::ImageRgb8(img), "avif")
+                .unwrap_err();
+        assert!(err.to_string().contains("bad format"));
+    }
+}
@@ -0,0 +1,393 @@
+use anyhow::Context;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::task::spawn_blocking;
+
+use crate::config;
+use crate::config::{TopographyServerConfig, LINKS_CONFIG};
+use crate::download_dem;
+use crate::download_dem::{DemGrid, DemTileId};
+use crate::geo_trig::{geo_bbox, tile_index_float, GeoPoint};
+use crate::proxy_manager;
+use crate::proxy_manager::DownloadsPending;
+
+/// Same limit as the Open-Meteo elevation API.
+pub const ELEVATION_MAX_POINTS: usize = 100;
+/// Suggested wait before retrying a request whose DEM tiles were queued.
+const ELEVATION_RETRY_AFTER_SECS: u64 = 2;
+/// Decoded DEM tiles kept in memory; GeoTIFF tiles can be large.
+const DEM_GRID_CACHE_SIZE: usize = 16;
+/// Distinct DEM tiles one request may touch, so a single request cannot
+/// queue a whole region for download.
+pub const ELEVATION_MAX_DEM_TILES: usize = 256;
+
+/// (server_name, x, y, z)
+type DemGridKey = (String, u64, u64, u8);
+/// (DEM tile x/y, pixel x/y in it, weight)
+type SampleTap = ((u64, u64), (u32, u32), f64);
+
+lazy_static::lazy_static! {
+    /// Recently used DEM grids, oldest first.
+    static ref DEM_GRID_CACHE: Mutex<VecDeque<(DemGridKey, Arc<DemGrid>)>>
+        = Mutex::new(VecDeque::new());
+}
+
+/// Open-Meteo style batch request: parallel coordinate lists.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ElevationRequest {
+    pub latitude: Vec<f64>,
+    pub longitude: Vec<f64>,
+}
+
+/// Open-Meteo style response; null where the DEM has no data.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ElevationResponse {
+    pub elevation: Vec<Option<f32>>,
+}
+
+impl ElevationRequest {
+    pub fn points(&self) -> Result<Vec<GeoPoint>> {
+        if self.latitude.len() != self.longitude.len() {
+            anyhow::bail!(
+                "got {} latitudes but {} longitudes",
+                self.latitude.len(),
+                self.longitude.len()
+            );
+        }
+        if self.latitude.is_empty()
+            || self.latitude.len() > ELEVATION_MAX_POINTS
+        {
+            anyhow::bail!(
+                "need 1 to {} points, got {}",
+                ELEVATION_MAX_POINTS,
+                self.latitude.len()
+            );
+        }
+        let points: Vec<GeoPoint> = self
+            .latitude
+            .iter()
+            .zip(self.longitude.iter())
+            .map(|(lat, lon)| GeoPoint {
+                x_lon: *lon,
+                y_lat: *lat,
+            })
+            .collect();
+        for point in points.iter() {
+            if !(-90.0..=90.0).contains(&point.y_lat)
+                || !(-180.0..=180.0).contains(&point.x_lon)
+            {
+                anyhow::bail!("point out of range: {:?}", point);
+            }
+        }
+        Ok(points)
+    }
+}
+
+/// The named topography server, else `default_topography_server`, else
+/// the first one configured.
+pub fn get_elevation_server(
+    server_name: Option<&str>,
+) -> Result<TopographyServerConfig> {
+    match server_name.or(LINKS_CONFIG.default_topography_server.as_deref()) {
+        Some(server_name) => config::get_topography_server(server_name),
+        None => LINKS_CONFIG
+            .topography_servers
+            .first()
+            .cloned()
+            .context("no topography servers configured"),
+    }
+}
+
+/// Fractional pixel of a point inside a DEM tile of `width` x `height`
+/// pixels. XYZ tiles are in web mercator, GeoTIFF tiles are plain lat/lon
+/// over the tile bounds.
+fn get_dem_pixel(
+    server_config: &TopographyServerConfig,
+    (width, height): (u32, u32),
+    (x, y, z): (u64, u64, u8),
+    point: &GeoPoint,
+) -> (f64, f64) {
+    if download_dem::get_dem_format(server_config) == "geotiff" {
+        let bbox = geo_bbox(x, y, z);
+        return (
+            (point.x_lon - bbox.x_min) / (bbox.x_max - bbox.x_min)
+                * width as f64,
+            (bbox.y_max - point.y_lat) / (bbox.y_max - bbox.y_min)
+                * height as f64,
+        );
+    }
+    let (tx, ty) = tile_index_float(z, point.x_lon, point.y_lat);
+    (
+        (tx - x as f64) * width as f64,
+        (ty - y as f64) * height as f64,
+    )
+}
+
+/// Decoded grid of a stored DEM tile, through a small cache of recently
+/// used tiles. Blocking.
+fn read_dem_grid(
+    server_config: &TopographyServerConfig,
+    (x, y, z): (u64, u64, u8),
+    path: &Path,
+) -> Result<Arc<DemGrid>> {
+    let key = (server_config.name.clone(), x, y, z);
+    let mut cache = DEM_GRID_CACHE.lock().expect("poisoned dem cache");
+    if let Some(i) = cache.iter().position(|(k, _)| *k == key) {
+        let entry = cache.remove(i).context("dem cache entry")?;
+        let grid = entry.1.clone();
+        cache.push_back(entry);
+        return Ok(grid);
+    }
+    drop(cache);
+    let grid = Arc::new(download_dem::decode_dem(
+        &std::fs::read(path)?,
+        download_dem::get_dem_format(server_config),
+    )?);
+    let mut cache = DEM_GRID_CACHE.lock().expect("poisoned dem cache");
+    cache.push_back((key, grid.clone()));
+    if cache.len() > DEM_GRID_CACHE_SIZE {
+        cache.pop_front();
+    }
+    Ok(grid)
+}
+
+/// Decoded DEM tiles at the server's `download_zoomlevel`, queueing
+/// missing ones. Fails with [`DownloadsPending`] while any of them is
+/// still queued; tiles whose download failed are left out.
+pub async fn fetch_dem_grids(
+    server_config: &TopographyServerConfig,
+    tiles: &[(u64, u64)],
+) -> Result<HashMap<(u64, u64), Arc<DemGrid>>> {
+    if tiles.len() > ELEVATION_MAX_DEM_TILES {
+        anyhow::bail!(
+            "request needs {} dem tiles from {}, max is {}",
+            tiles.len(),
+            server_config.name,
+            ELEVATION_MAX_DEM_TILES
+        );
+    }
+    let z = server_config.download_zoomlevel as u8;
+    let results = futures::future::join_all(tiles.iter().map(|(x, y)| {
+        download_dem::get_dem_tile(&server_config.name, *x, *y, z)
+    }))
+    .await;
+    let mut grids = HashMap::new();
+    let mut pending = 0;
+    let mut failed = 0;
+    for (&(x, y), result) in tiles.iter().zip(results) {
+        let path = match result {
+            Ok(path) => path,
+            Err(_) => {
+                let download_id = DemTileId {
+                    server_name: server_config.name.clone(),
+                    x,
+                    y,
+                    z,
+                };
+                if proxy_manager::is_download_pending(&download_id)? {
+                    pending += 1;
+                } else {
+                    failed += 1;
+                }
+                continue;
+            }
+        };
+        let server_config = server_config.clone();
+        let grid = spawn_blocking(move || {
+            read_dem_grid(&server_config, (x, y, z), &path)
+        })
+        .await??;
+        grids.insert((x, y), grid);
+    }
+    if pending > 0 {
+        return Err(DownloadsPending {
+            what: format!("{} z={} dem tile", server_config.name, z),
+            count: pending,
+            retry_after_secs: ELEVATION_RETRY_AFTER_SECS,
+        }
+        .into());
+    }
+    if failed > 0 {
+        eprintln!(
+            "elevation {} z={}: {} dem tiles failed",
+            server_config.name, z, failed
+        );
+    }
+    Ok(grids)
+}
+
+/// Decoded DEM tiles under the points, plus the neighbour tiles that
+/// points within half a pixel of a tile edge interpolate with.
+pub async fn fetch_dem_grids_for_points(
+    server_config: &TopographyServerConfig,
+    points: &[GeoPoint],
+) -> Result<HashMap<(u64, u64), Arc<DemGrid>>> {
+    let tiles: HashSet<(u64, u64)> = points
+        .iter()
+        .map(|p| get_dem_tile_index(server_config, p))
+        .collect();
+    let tiles: Vec<(u64, u64)> = tiles.into_iter().collect();
+    let mut grids = fetch_dem_grids(server_config, &tiles).await?;
+    let mut neighbours = HashSet::new();
+    for point in points {
+        let tile = get_dem_tile_index(server_config, point);
+        let grid = match grids.get(&tile) {
+            Some(grid) => grid,
+            None => continue,
+        };
+        let size = (grid.width, grid.height);
+        for (neighbour, _, weight) in sample_taps(server_config, size, point) {
+            if weight > 0.0 && !grids.contains_key(&neighbour) {
+                neighbours.insert(neighbour);
+            }
+        }
+    }
+    let neighbours: Vec<(u64, u64)> = neighbours.into_iter().collect();
+    if !neighbours.is_empty() {
+        grids.extend(fetch_dem_grids(server_config, &neighbours).await?);
+    }
+    Ok(grids)
+}
+
+/// DEM tile at the server's `download_zoomlevel` holding the point.
+pub fn get_dem_tile_index(
+    server_config: &TopographyServerConfig,
+    point: &GeoPoint,
+) -> (u64, u64) {
+    let z = server_config.download_zoomlevel as u8;
+    let max_extent = 2u64.pow(z.into()) - 1;
+    let (tx, ty) = tile_index_float(z, point.x_lon, point.y_lat);
+    (
+        (tx.max(0.0) as u64).min(max_extent),
+        (ty.max(0.0) as u64).min(max_extent),
+    )
+}
+
+/// The four pixels a bilinear sample of the point reads, with pixel
+/// centers at +0.5. Pixels past a tile
+/// edge are read from the neighbour tile, wrapping around the
+/// antimeridian; past the poles the edge row is reused. Assumes all DEM
+/// tiles of a server have the same size.
+fn sample_taps(
+    server_config: &TopographyServerConfig,
+    (width, height): (u32, u32),
+    point: &GeoPoint,
+) -> [SampleTap; 4] {
+    let (x, y) = get_dem_tile_index(server_config, point);
+    let z = server_config.download_zoomlevel as u8;
+    let tiles = 1i64 << z;
+    let (px, py) =
+        get_dem_pixel(server_config, (width, height), (x, y, z), point);
+    let (fx, fy) = (px - 0.5, py - 0.5);
+    let (x0, y0) = (fx.floor() as i64, fy.floor() as i64);
+    let (tx, ty) = (fx - x0 as f64, fy - y0 as f64);
+    let (w, h) = (width as i64, height as i64);
+    let tap = |ix: i64, iy: i64, weight: f64| {
+        let tile_x = (x as i64 + ix.div_euclid(w)).rem_euclid(tiles);
+        let mut tile_y = y as i64 + iy.div_euclid(h);
+        let mut pixel_y = iy.rem_euclid(h);
+        if tile_y < 0 {
+            (tile_y, pixel_y) = (0, 0);
+        } else if tile_y >= tiles {
+            (tile_y, pixel_y) = (tiles - 1, h - 1);
+        }
+        (
+            (tile_x as u64, tile_y as u64),
+            (ix.rem_euclid(w) as u32, pixel_y as u32),
+            weight,
+        )
+    };
+    [
+        tap(x0, y0, (1.0 - tx) * (1.0 - ty)),
+        tap(x0 + 1, y0, tx * (1.0 - ty)),
+        tap(x0, y0 + 1, (1.0 - tx) * ty),
+        tap(x0 + 1, y0 + 1, tx * ty),
+    ]
+}
+
+/// Bilinear elevation of a point from already fetched DEM tiles, across
+/// tile edges. Nodata pixels and tiles not fetched are left out of the
+/// weights.
+pub fn sample_dem(
+    server_config: &TopographyServerConfig,
+    grids: &HashMap<(u64, u64), Arc<DemGrid>>,
+    point: &GeoPoint,
+) -> Option<f32> {
+    let grid = grids.get(&get_dem_tile_index(server_config, point))?;
+    let size = (grid.width, grid.height);
+    let mut sum = 0.0;
+    let mut weight_sum = 0.0;
+    for (tile, (px, py), weight) in sample_taps(server_config, size, point) {
+        if weight <= 0.0 {
+            continue;
+        }
+        let grid = match grids.get(&tile) {
+            Some(grid) if grid.width == size.0 && grid.height == size.1 => grid,
+            _ => continue,
+        };
+        let value = grid.values[(py * grid.width + px) as usize];
+        if !value.is_nan() {
+            sum += value as f64 * weight;
+            weight_sum += weight;
+        }
+    }
+    (weight_sum > 0.0).then(|| (sum / weight_sum) as f32)
+}
+
+/// Elevations in meters from cached DEM tiles, queueing missing ones
+/// (see [`fetch_dem_grids`]). Points without data are None.
+pub async fn get_elevations(
+    server_name: Option<&str>,
+    points: &[GeoPoint],
+) -> Result<Vec<Option<f32>>> {
+    let server_config = get_elevation_server(server_name)?;
+    let grids = fetch_dem_grids_for_points(&server_config, points).await?;
+    let points = points.to_vec();
+    Ok(spawn_blocking(move || {
+        points
+            .iter()
+            .map(|p| sample_dem(&server_config, &grids, p))
+            .collect()
+    })
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_dem_across_tile_edge() {
+        let server_config = TopographyServerConfig {
+            name: "test".to_owned(),
+            comment: "".to_owned(),
+            url: "".to_owned(),
+            download_zoomlevel: 1,
+            scale_zoomlevel: 1,
+            format: Some("terrarium".to_owned()),
+        };
+        let grid = |value| {
+            Arc::new(DemGrid {
+                width: 2,
+                height: 2,
+                values: vec![value; 4],
+            })
+        };
+        let grids = HashMap::from([
+            ((0, 0), grid(100.0)),
+            ((1, 0), grid(300.0)),
+            ((0, 1), grid(100.0)),
+            ((1, 1), grid(300.0)),
+        ]);
+        let at = |x_lon| GeoPoint { x_lon, y_lat: 45.0 };
+        // on the edge both tiles weigh the same
+        let edge = sample_dem(&server_config, &grids, &at(0.0)).unwrap();
+        assert!((edge - 200.0).abs() < 1e-3, "{}", edge);
+        // a pixel center away from the edge reads only its own tile
+        let inner = sample_dem(&server_config, &grids, &at(-45.0)).unwrap();
+        assert!((inner - 100.0).abs() < 1e-3, "{}", inner);
+    }
+}
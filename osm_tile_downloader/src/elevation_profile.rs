@@ -0,0 +1,252 @@
+use anyhow::Context;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::download_tile;
+use crate::elevation;
+use crate::geo_trig::{
+    decode_polyline, great_circle_point, haversine_meters, GeoPoint,
+    EARTH_MEAN_RADIUS,
+};
+
+pub const PROFILE_DEFAULT_SPACING: f64 = 30.0;
+pub const PROFILE_MAX_SAMPLES: usize = 10_000;
+/// Segments whose ends are closer than this to antipodal are refused:
+/// there the great circle between them is not unique.
+const ANTIPODAL_MIN_GAP: f64 = 1_000.0;
+const PROFILE_CHART_WIDTH: u32 = 800;
+const PROFILE_CHART_HEIGHT: u32 = 300;
+
+/// The path is either a GeoJSON LineString (bare geometry or feature) or
+/// an encoded polyline with `polyline_precision` decimals (default 5).
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ProfileRequest {
+    pub geojson: Option<serde_json::Value>,
+    pub polyline: Option<String>,
+    pub polyline_precision: Option<u32>,
+    /// Meters between samples, default [`PROFILE_DEFAULT_SPACING`].
+    pub spacing: Option<f64>,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ProfileSample {
+    pub distance: f64,
+    pub lon: f64,
+    pub lat: f64,
+    /// None where the DEM has no data.
+    pub elevation: Option<f32>,
+    /// Cumulative, in meters.
+    pub ascent: f64,
+    pub descent: f64,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ElevationProfile {
+    pub server_name: String,
+    pub spacing: f64,
+    pub distance: f64,
+    pub ascent: f64,
+    pub descent: f64,
+    pub min_elevation: Option<f32>,
+    pub max_elevation: Option<f32>,
+    /// Steepest grade between neighbouring samples, in percent.
+    pub max_grade: f64,
+    pub samples: Vec<ProfileSample>,
+}
+
+impl ProfileRequest {
+    pub fn path(&self) -> Result<Vec<GeoPoint>> {
+        let path = match (&self.geojson, &self.polyline) {
+            (Some(geojson), None) => parse_geojson_line(geojson.clone())?,
+            (None, Some(polyline)) => {
+                decode_polyline(polyline, self.polyline_precision.unwrap_or(5))?
+            }
+            _ => anyhow::bail!("need either geojson or polyline"),
+        };
+        if path.len() < 2 {
+            anyhow::bail!("path needs at least 2 points, got {}", path.len());
+        }
+        Ok(path)
+    }
+}
+
+fn parse_geojson_line(value: serde_json::Value) -> Result<Vec<GeoPoint>> {
+    let geometry = match geojson::GeoJson::from_json_value(value)? {
+        geojson::GeoJson::Geometry(geometry) => geometry,
+        geojson::GeoJson::Feature(feature) => {
+            feature.geometry.context("feature has no geometry")?
+        }
+        geojson::GeoJson::FeatureCollection(_) => {
+            anyhow::bail!("expected a LineString, got a FeatureCollection")
+        }
+    };
+    match geometry.value {
+        geojson::Value::LineString(coords) => coords
+            .iter()
+            .map(|c| match c[..] {
+                [x_lon, y_lat, ..] => Ok(GeoPoint { x_lon, y_lat }),
+                _ => anyhow::bail!("bad coordinate {:?}", c),
+            })
+            .collect(),
+        _ => anyhow::bail!("expected a LineString geometry"),
+    }
+}
+
+/// Points every `spacing` meters along the great-circle path, with their
+/// distance from the start; vertices are kept so corners are not cut.
+pub fn sample_path(
+    path: &[GeoPoint],
+    spacing: f64,
+) -> Result<Vec<(f64, GeoPoint)>> {
+    if spacing.is_nan() || spacing < 1.0 {
+        anyhow::bail!("spacing must be at least 1 meter, got {}", spacing);
+    }
+    let mut samples = vec![(0.0, path[0])];
+    let mut distance = 0.0;
+    for segment in path.windows(2) {
+        let length = haversine_meters(&segment[0], &segment[1]);
+        let antipodal_gap = std::f64::consts::PI * EARTH_MEAN_RADIUS - length;
+        if antipodal_gap < ANTIPODAL_MIN_GAP {
+            anyhow::bail!(
+                "{:?} and {:?} are nearly antipodal, add a point between",
+                segment[0],
+                segment[1]
+            );
+        }
+        let steps = (length / spacing).ceil().max(1.0) as usize;
+        if samples.len() + steps > PROFILE_MAX_SAMPLES {
+            anyhow::bail!(
+                "more than {} samples, use a larger spacing",
+                PROFILE_MAX_SAMPLES
+            );
+        }
+        for step in 1..=steps {
+            let fraction = step as f64 / steps as f64;
+            samples.push((
+                distance + length * fraction,
+                great_circle_point(&segment[0], &segment[1], fraction),
+            ));
+        }
+        distance += length;
+    }
+    Ok(samples)
+}
+
+pub async fn get_elevation_profile(
+    server_name: Option<&str>,
+    request: &ProfileRequest,
+) -> Result<ElevationProfile> {
+    let server_config = elevation::get_elevation_server(server_name)?;
+    let spacing = request.spacing.unwrap_or(PROFILE_DEFAULT_SPACING);
+    let samples = sample_path(&request.path()?, spacing)?;
+    let points: Vec<GeoPoint> = samples.iter().map(|s| s.1).collect();
+    let elevations =
+        elevation::get_elevations(Some(&server_config.name), &points).await?;
+
+    let mut profile = ElevationProfile {
+        server_name: server_config.name,
+        spacing,
+        distance: samples.last().map(|s| s.0).unwrap_or(0.0),
+        ascent: 0.0,
+        descent: 0.0,
+        min_elevation: None,
+        max_elevation: None,
+        max_grade: 0.0,
+        samples: vec![],
+    };
+    // last sample with data, so gaps do not break the totals
+    let mut previous: Option<(f64, f32)> = None;
+    for ((distance, point), elevation) in samples.into_iter().zip(elevations) {
+        if let Some(elevation) = elevation {
+            if let Some((prev_distance, prev_elevation)) = previous {
+                let climb = (elevation - prev_elevation) as f64;
+                if climb > 0.0 {
+                    profile.ascent += climb;
+                } else {
+                    profile.descent -= climb;
+                }
+                let run = distance - prev_distance;
+                if run > 0.0 {
+                    profile.max_grade =
+                        profile.max_grade.max(climb.abs() / run * 100.0);
+                }
+            }
+            previous = Some((distance, elevation));
+            profile.min_elevation = Some(
+                profile
+                    .min_elevation
+                    .map_or(elevation, |e| e.min(elevation)),
+            );
+            profile.max_elevation = Some(
+                profile
+                    .max_elevation
+                    .map_or(elevation, |e| e.max(elevation)),
+            );
+        }
+        profile.samples.push(ProfileSample {
+            distance,
+            lon: point.x_lon,
+            lat: point.y_lat,
+            elevation,
+            ascent: profile.ascent,
+            descent: profile.descent,
+        });
+    }
+    Ok(profile)
+}
+
+/// Area chart of elevation over distance, scaled to the elevation range.
+/// No axis labels; the JSON profile has the numbers.
+pub fn render_profile_png(profile: &ElevationProfile) -> Result<Vec<u8>> {
+    let (w, h) = (PROFILE_CHART_WIDTH, PROFILE_CHART_HEIGHT);
+    let mut img =
+        image::RgbaImage::from_pixel(w, h, image::Rgba([255, 255, 255, 255]));
+    let (min_e, max_e) = match (profile.min_elevation, profile.max_elevation) {
+        (Some(min_e), Some(max_e)) => (min_e as f64, max_e as f64),
+        _ => anyhow::bail!("no elevation data along the path"),
+    };
+    // 10% headroom, and at least 10 m so flat paths stay flat
+    let pad = ((max_e - min_e) * 0.1).max(5.0);
+    let (low, high) = (min_e - pad, max_e + pad);
+    let distance = profile.distance.max(1.0);
+    let to_y = |e: f64| ((high - e) / (high - low) * (h - 1) as f64) as i64;
+
+    // elevation at each column, interpolated between samples with data
+    let known: Vec<(f64, f64)> = profile
+        .samples
+        .iter()
+        .filter_map(|s| s.elevation.map(|e| (s.distance, e as f64)))
+        .collect();
+    let mut k = 0;
+    let mut last_y: Option<i64> = None;
+    for px in 0..w {
+        let d = px as f64 / (w - 1) as f64 * distance;
+        while k + 1 < known.len() && known[k + 1].0 < d {
+            k += 1;
+        }
+        let e = match (known.get(k), known.get(k + 1)) {
+            (Some(a), Some(b)) if b.0 > a.0 && d >= a.0 => {
+                a.1 + (b.1 - a.1) * ((d - a.0) / (b.0 - a.0)).min(1.0)
+            }
+            (Some(a), _) => a.1,
+            _ => continue,
+        };
+        let y = to_y(e).clamp(0, h as i64 - 1);
+        for py in y..h as i64 {
+            img.put_pixel(px, py as u32, image::Rgba([196, 220, 196, 255]));
+        }
+        // line, joined vertically to the previous column
+        let (y0, y1) = match last_y {
+            Some(prev) => (prev.min(y), prev.max(y)),
+            None => (y, y),
+        };
+        for py in y0..=y1 {
+            img.put_pixel(px, py as u32, image::Rgba([40, 100, 40, 255]));
+        }
+        last_y = Some(y);
+    }
+    download_tile::encode_tile_image(
+        image::DynamicImage::ImageRgba8(img),
+        "png",
+    )
+}
@@ -0,0 +1,175 @@
+use anyhow::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::PathBuf;
+
+use crate::config::get_current_timestamp;
+use crate::config::SLED_DB;
+use crate::geo_trig::{
+    geo_bbox, polygon_bbox, polygon_intersects_bbox, tile_range, GeoBBOX,
+    GeoPoint, WEB_MERCATOR_MAX_LAT,
+};
+
+lazy_static::lazy_static! {
+    pub static ref DB_EXPORT_JOBS: typed_sled::Tree::<String, ExportJob>
+        = typed_sled::Tree::<String, ExportJob>::open(
+            &SLED_DB,
+            "export_jobs_v1");
+}
+
+/// Region of an export: a bbox, or a polygon ring of lon/lat points.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ExportRegion {
+    pub bbox: Option<GeoBBOX>,
+    pub polygon: Option<Vec<GeoPoint>>,
+}
+
+impl ExportRegion {
+    /// Bounds clamped to what web mercator tiles can show.
+    pub fn bounds(&self) -> Result<GeoBBOX> {
+        let bbox = match (&self.bbox, &self.polygon) {
+            (Some(bbox), None) => *bbox,
+            (None, Some(polygon)) if polygon.len() >= 3 => {
+                polygon_bbox(polygon).expect("non-empty polygon")
+            }
+            _ => anyhow::bail!("export needs either a bbox or a polygon"),
+        };
+        Ok(GeoBBOX {
+            x_min: bbox.x_min.max(-180.0),
+            y_min: bbox.y_min.max(-WEB_MERCATOR_MAX_LAT),
+            x_max: bbox.x_max.min(180.0),
+            y_max: bbox.y_max.min(WEB_MERCATOR_MAX_LAT),
+        })
+    }
+
+    pub fn tile_range(&self, z: u8) -> Result<((u64, u64), (u64, u64))> {
+        Ok(tile_range(&self.bounds()?, z))
+    }
+
+    pub fn contains_tile(&self, x: u64, y: u64, z: u8) -> bool {
+        match &self.polygon {
+            Some(polygon) => {
+                polygon_intersects_bbox(polygon, &geo_bbox(x, y, z))
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct TileExportRequest {
+    pub servers: Vec<String>,
+    #[serde(flatten)]
+    pub region: ExportRegion,
+    pub min_z: u8,
+    pub max_z: u8,
+}
+
+impl TileExportRequest {
+    pub fn check(&self) -> Result<()> {
+        if self.servers.is_empty() {
+            anyhow::bail!("export needs at least one server");
+        }
+        if self.min_z > self.max_z {
+            anyhow::bail!("min_z {} > max_z {}", self.min_z, self.max_z);
+        }
+        self.region.bounds()?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct TileExportSummary {
+    pub server_name: String,
+    pub path: PathBuf,
+    pub tile_count: u64,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub enum ExportJobStatus {
+    Running,
+    Done,
+    Failed(String),
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct ExportJob {
+    pub id: String,
+    pub kind: String,
+    pub request: TileExportRequest,
+    pub status: ExportJobStatus,
+    pub created_at: f64,
+    pub finished_at: Option<f64>,
+    pub results: Vec<TileExportSummary>,
+}
+
+/// Run the export in the background; poll with [`get_export_job`].
+pub fn start_export_job<F>(
+    kind: &str,
+    request: TileExportRequest,
+    export: F,
+) -> Result<ExportJob>
+where
+    F: Future<Output = Result<Vec<TileExportSummary>>> + Send + 'static,
+{
+    request.check()?;
+    let job = ExportJob {
+        id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+        kind: kind.to_owned(),
+        request,
+        status: ExportJobStatus::Running,
+        created_at: get_current_timestamp(),
+        finished_at: None,
+        results: vec![],
+    };
+    DB_EXPORT_JOBS.insert(&job.id, &job)?;
+    let mut finished = job.clone();
+    tokio::spawn(async move {
+        match export.await {
+            Ok(results) => {
+                finished.status = ExportJobStatus::Done;
+                finished.results = results;
+            }
+            Err(err) => {
+                eprintln!("export job {} failed: {:?}", finished.id, err);
+                finished.status = ExportJobStatus::Failed(format!("{:?}", err));
+            }
+        }
+        finished.finished_at = Some(get_current_timestamp());
+        if let Err(err) = DB_EXPORT_JOBS.insert(&finished.id, &finished) {
+            eprintln!("cannot save export job {}: {:?}", finished.id, err);
+        }
+    });
+    Ok(job)
+}
+
+pub fn get_export_job(id: &str) -> Result<ExportJob> {
+    match DB_EXPORT_JOBS.get(&id.to_owned())? {
+        Some(job) => Ok(job),
+        None => anyhow::bail!("no export job with id {}", id),
+    }
+}
+
+pub fn get_export_job_file(id: &str, index: usize) -> Result<PathBuf> {
+    let job = get_export_job(id)?;
+    if job.status != ExportJobStatus::Done {
+        anyhow::bail!("export job {} not done: {:?}", id, job.status);
+    }
+    match job.results.get(index) {
+        Some(result) => Ok(result.path.clone()),
+        None => anyhow::bail!("export job {} has no file {}", id, index),
+    }
+}
+
+/// Jobs still running when the server stopped will never finish.
+pub fn fail_interrupted_jobs() -> Result<()> {
+    for k in DB_EXPORT_JOBS.iter() {
+        let (id, mut job) = k?;
+        if job.status == ExportJobStatus::Running {
+            job.status = ExportJobStatus::Failed("interrupted".to_owned());
+            DB_EXPORT_JOBS.insert(&id, &job)?;
+        }
+    }
+    Ok(())
+}
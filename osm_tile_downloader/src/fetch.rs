@@ -1,8 +1,9 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::config::*;
 use anyhow::Context;
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
 // lazy_static::lazy_static! {
 //     pub static ref DB_FETCH_READY:
@@ -142,21 +143,76 @@ pub async fn fetch_with_socks5_curl(
     path: &Path,
     socks5_proxy: &str,
 ) -> Result<()> {
+    fetch_with_socks5_curl_headers(url, path, socks5_proxy, &[]).await?;
+    Ok(())
+}
+
+/// Where `fetch_with_socks5_curl_headers` has curl dump the headers.
+fn get_headers_path(path: &Path) -> PathBuf {
+    let mut headers_path = path.as_os_str().to_owned();
+    headers_path.push(".headers");
+    PathBuf::from(headers_path)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CurlResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl CurlResponse {
+    /// Parse a `curl -D` dump; only the last response block counts.
+    pub fn parse(dump: &str) -> Result<Self> {
+        let block = dump
+            .split("\r\n\r\n")
+            .filter(|b| b.starts_with("HTTP/"))
+            .last()
+            .context("no http response in header dump")?;
+        let mut lines = block.lines();
+        let status = lines
+            .next()
+            .and_then(|l| l.split_whitespace().nth(1))
+            .context("no status line")?
+            .parse()?;
+        let headers = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_owned()))
+            .collect();
+        Ok(Self { status, headers })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Fetch `url` into `path` with curl through a socks5 proxy, sending extra
+/// request headers, returning the response status and headers.
+pub async fn fetch_with_socks5_curl_headers(
+    url: &str,
+    path: &Path,
+    socks5_proxy: &str,
+    request_headers: &[String],
+) -> Result<CurlResponse> {
     use rand::seq::SliceRandom;
     let user_agent = LINKS_CONFIG
         .user_agents
         .choose(&mut rand::thread_rng())
         .context("no user-agent")?;
+    let headers_path = get_headers_path(path);
 
     let mut curl_cmd =
         tokio::process::Command::new(LINKS_CONFIG.curl_path.clone());
     curl_cmd
         .arg("-s")
-        // .arg("-L")
-        // KV ARGS
         .arg("--insecure")
         .arg("-o")
         .arg(path)
+        .arg("-D")
+        .arg(&headers_path)
         .arg("--user-agent")
         .arg(user_agent)
         .arg("--socks5-hostname")
@@ -164,21 +220,23 @@ pub async fn fetch_with_socks5_curl(
         .arg("--connect-timeout")
         .arg((LINKS_CONFIG.timeout_secs - 2).to_string())
         .arg("--max-time")
-        .arg((LINKS_CONFIG.timeout_secs - 1).to_string())
-        // URL
-        .arg(url);
-    // eprintln!("running curl proxy = {}; url = {}", socks5_proxy, url);
+        .arg((LINKS_CONFIG.timeout_secs - 1).to_string());
+    for header in request_headers {
+        curl_cmd.arg("-H").arg(header);
+    }
+    curl_cmd.arg(url);
     let mut curl = curl_cmd.spawn()?;
     let curl_status = curl.wait().await?;
-    if curl_status.success() {
-        Ok(())
-    } else {
+    let dump = tokio::fs::read(&headers_path).await;
+    let _ = tokio::fs::remove_file(&headers_path).await;
+    if !curl_status.success() {
         anyhow::bail!(
             "curl fail to get file using socks proxy = {:?}  url = {:?}",
             socks5_proxy,
             url
         )
     }
+    CurlResponse::parse(&String::from_utf8_lossy(&dump?))
 }
 
 pub async fn fetch_with_socks5_impersonate(
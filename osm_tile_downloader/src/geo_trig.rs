@@ -46,6 +46,50 @@ impl GeoBBOX {
     }
 }
 
+fn parse_floats(s: &str, count: usize) -> anyhow::Result<Vec<f64>> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() != count {
+        anyhow::bail!(
+            "expected {} comma separated numbers, got '{}'",
+            count,
+            s
+        );
+    }
+    Ok(values)
+}
+
+/// `lon,lat`
+impl std::str::FromStr for GeoPoint {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let v = parse_floats(s, 2)?;
+        Ok(Self {
+            x_lon: v[0],
+            y_lat: v[1],
+        })
+    }
+}
+
+/// `x_min,y_min,x_max,y_max`, so west,south,east,north
+impl std::str::FromStr for GeoBBOX {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let v = parse_floats(s, 4)?;
+        if !(v[0] < v[2] && v[1] < v[3]) {
+            anyhow::bail!("malformed bbox: '{}'", s);
+        }
+        Ok(Self {
+            x_min: v[0],
+            y_min: v[1],
+            x_max: v[2],
+            y_max: v[3],
+        })
+    }
+}
+
 pub fn tile_index(zoom: u8, lon_deg: f64, lat_deg: f64) -> (u64, u64) {
     let (tile_x, tile_y) = tile_index_float(zoom, lon_deg, lat_deg);
 
@@ -71,6 +115,18 @@ pub fn tile_index_float(zoom: u8, lon_deg: f64, lat_deg: f64) -> (f64, f64) {
     (tile_x, tile_y)
 }
 
+/// Inverse of [`tile_index_float`].
+pub fn tile_float_to_geo_point(zoom: u8, tile_x: f64, tile_y: f64) -> GeoPoint {
+    let n = 2f64.powi(zoom as i32);
+    GeoPoint {
+        x_lon: tile_x / n * 360.0 - 180.0,
+        y_lat: (std::f64::consts::PI * (1.0 - 2.0 * tile_y / n))
+            .sinh()
+            .atan()
+            .to_degrees(),
+    }
+}
+
 // https://stackoverflow.com/questions/32454234/using-bing-maps-quadkeys-as-openlayers-3-tile-source
 pub fn xyz_to_bing_quadkey(x: u64, y: u64, z: u8) -> String {
     // let y = -(y as i64) - 1;
@@ -89,6 +145,29 @@ pub fn xyz_to_bing_quadkey(x: u64, y: u64, z: u8) -> String {
     quad_key.iter().collect()
 }
 
+/// PMTiles v3 tile id: tiles of all lower zooms, then the position of the
+/// tile along the hilbert curve of its zoom level.
+pub fn pmtiles_tile_id(z: u8, x: u64, y: u64) -> u64 {
+    let lower_zoom_tiles = ((1u64 << (2 * z as u32)) - 1) / 3;
+    let (mut x, mut y) = (x, y);
+    let mut d = 0;
+    let mut s = (1u64 << z) / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    lower_zoom_tiles + d
+}
+
 pub fn geo_bbox(x: u64, y: u64, z: u8) -> GeoBBOX {
     use std::f64::consts::PI;
     GeoBBOX {
@@ -107,10 +186,360 @@ pub fn geo_bbox(x: u64, y: u64, z: u8) -> GeoBBOX {
     }
 }
 
+/// Even-odd rule, polygon given as a ring of lon/lat points.
+pub fn polygon_contains(polygon: &[GeoPoint], point: &GeoPoint) -> bool {
+    let mut inside = false;
+    for (i, a) in polygon.iter().enumerate() {
+        let b = &polygon[(i + 1) % polygon.len()];
+        if (a.y_lat > point.y_lat) != (b.y_lat > point.y_lat) {
+            let x_cross = a.x_lon
+                + (point.y_lat - a.y_lat) / (b.y_lat - a.y_lat)
+                    * (b.x_lon - a.x_lon);
+            if point.x_lon < x_cross {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+fn segments_cross(
+    a: &GeoPoint,
+    b: &GeoPoint,
+    c: &GeoPoint,
+    d: &GeoPoint,
+) -> bool {
+    let orient = |p: &GeoPoint, q: &GeoPoint, r: &GeoPoint| {
+        (q.x_lon - p.x_lon) * (r.y_lat - p.y_lat)
+            - (q.y_lat - p.y_lat) * (r.x_lon - p.x_lon)
+    };
+    let (o1, o2) = (orient(a, b, c), orient(a, b, d));
+    let (o3, o4) = (orient(c, d, a), orient(c, d, b));
+    o1 * o2 < 0.0 && o3 * o4 < 0.0
+}
+
+pub fn polygon_intersects_bbox(polygon: &[GeoPoint], bbox: &GeoBBOX) -> bool {
+    let corners = [
+        GeoPoint {
+            x_lon: bbox.x_min,
+            y_lat: bbox.y_min,
+        },
+        GeoPoint {
+            x_lon: bbox.x_max,
+            y_lat: bbox.y_min,
+        },
+        GeoPoint {
+            x_lon: bbox.x_max,
+            y_lat: bbox.y_max,
+        },
+        GeoPoint {
+            x_lon: bbox.x_min,
+            y_lat: bbox.y_max,
+        },
+    ];
+    let in_bbox = |p: &GeoPoint| {
+        p.x_lon >= bbox.x_min
+            && p.x_lon <= bbox.x_max
+            && p.y_lat >= bbox.y_min
+            && p.y_lat <= bbox.y_max
+    };
+    if polygon.iter().any(in_bbox)
+        || corners.iter().any(|c| polygon_contains(polygon, c))
+    {
+        return true;
+    }
+    (0..polygon.len()).any(|i| {
+        let (a, b) = (&polygon[i], &polygon[(i + 1) % polygon.len()]);
+        (0..4).any(|j| segments_cross(a, b, &corners[j], &corners[(j + 1) % 4]))
+    })
+}
+
+pub fn polygon_bbox(polygon: &[GeoPoint]) -> Option<GeoBBOX> {
+    let first = polygon.first()?;
+    let mut bbox = GeoBBOX {
+        x_min: first.x_lon,
+        y_min: first.y_lat,
+        x_max: first.x_lon,
+        y_max: first.y_lat,
+    };
+    for p in polygon {
+        bbox.x_min = bbox.x_min.min(p.x_lon);
+        bbox.y_min = bbox.y_min.min(p.y_lat);
+        bbox.x_max = bbox.x_max.max(p.x_lon);
+        bbox.y_max = bbox.y_max.max(p.y_lat);
+    }
+    Some(bbox)
+}
+
+/// Area centroid of a ring in plain lon/lat, with its signed area in
+/// square degrees; None for rings without area.
+pub fn polygon_centroid(ring: &[GeoPoint]) -> Option<(GeoPoint, f64)> {
+    let (mut area, mut cx, mut cy) = (0.0, 0.0, 0.0);
+    for (i, a) in ring.iter().enumerate() {
+        let b = &ring[(i + 1) % ring.len()];
+        let cross = a.x_lon * b.y_lat - b.x_lon * a.y_lat;
+        area += cross;
+        cx += (a.x_lon + b.x_lon) * cross;
+        cy += (a.y_lat + b.y_lat) * cross;
+    }
+    if area.abs() < 1e-18 {
+        return None;
+    }
+    let centroid = GeoPoint {
+        x_lon: cx / (3.0 * area),
+        y_lat: cy / (3.0 * area),
+    };
+    Some((centroid, area / 2.0))
+}
+
+/// Length weighted center of a line's segments in plain lon/lat, with
+/// the line length in degrees; None for empty or zero length lines.
+pub fn line_centroid(line: &[GeoPoint]) -> Option<(GeoPoint, f64)> {
+    let (mut length, mut cx, mut cy) = (0.0, 0.0, 0.0);
+    for segment in line.windows(2) {
+        let (a, b) = (&segment[0], &segment[1]);
+        let l = (b.x_lon - a.x_lon).hypot(b.y_lat - a.y_lat);
+        length += l;
+        cx += (a.x_lon + b.x_lon) / 2.0 * l;
+        cy += (a.y_lat + b.y_lat) / 2.0 * l;
+    }
+    if length <= 0.0 {
+        return None;
+    }
+    let centroid = GeoPoint {
+        x_lon: cx / length,
+        y_lat: cy / length,
+    };
+    Some((centroid, length))
+}
+
+/// `lon,lat;lon,lat;...`
+pub fn parse_polygon(s: &str) -> anyhow::Result<Vec<GeoPoint>> {
+    let polygon = s
+        .split(';')
+        .map(|p| p.parse::<GeoPoint>())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if polygon.len() < 3 {
+        anyhow::bail!("polygon needs at least 3 points, got '{}'", s);
+    }
+    Ok(polygon)
+}
+
+/// EPSG:3857 easting, northing in meters.
+pub fn web_mercator_meters(lon_deg: f64, lat_deg: f64) -> (f64, f64) {
+    const EARTH_RADIUS: f64 = 6_378_137.0;
+    let easting = EARTH_RADIUS * lon_deg.to_radians();
+    let northing = EARTH_RADIUS
+        * (std::f64::consts::FRAC_PI_4 + lat_deg.to_radians() / 2.0)
+            .tan()
+            .ln();
+    (easting, northing)
+}
+
+pub const WEB_MERCATOR_MAX_LAT: f64 = 85.051_128_779_806_59;
+
+/// Inclusive range of tiles `((x_min, y_min), (x_max, y_max))` covering
+/// the bbox at zoom `z`. Tile y grows southwards, so y_min is from the
+/// bbox top edge.
+pub fn tile_range(bbox: &GeoBBOX, z: u8) -> ((u64, u64), (u64, u64)) {
+    let max_extent = 2u64.pow(z.into()) - 1;
+    let clamp_lon = |lon: f64| lon.clamp(-180.0, 180.0);
+    let clamp_lat =
+        |lat: f64| lat.clamp(-WEB_MERCATOR_MAX_LAT, WEB_MERCATOR_MAX_LAT);
+    let (x0, y0) =
+        tile_index_float(z, clamp_lon(bbox.x_min), clamp_lat(bbox.y_max));
+    let (x1, y1) =
+        tile_index_float(z, clamp_lon(bbox.x_max), clamp_lat(bbox.y_min));
+    let to_tile = |t: f64| (t.max(0.0) as u64).min(max_extent);
+    ((to_tile(x0), to_tile(y0)), (to_tile(x1), to_tile(y1)))
+}
+
+/// Mean earth radius for great-circle math.
+pub const EARTH_MEAN_RADIUS: f64 = 6_371_008.8;
+
+/// Great-circle distance in meters.
+pub fn haversine_meters(a: &GeoPoint, b: &GeoPoint) -> f64 {
+    let (lat1, lat2) = (a.y_lat.to_radians(), b.y_lat.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (b.x_lon - a.x_lon).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_MEAN_RADIUS * h.sqrt().min(1.0).asin()
+}
+
+/// Point at `fraction` of the great-circle path from `a` to `b`. NaN for
+/// antipodal points, which have no single path.
+pub fn great_circle_point(
+    a: &GeoPoint,
+    b: &GeoPoint,
+    fraction: f64,
+) -> GeoPoint {
+    let delta = haversine_meters(a, b) / EARTH_MEAN_RADIUS;
+    if delta < 1e-12 {
+        return *a;
+    }
+    let to_xyz = |p: &GeoPoint| {
+        let (lat, lon) = (p.y_lat.to_radians(), p.x_lon.to_radians());
+        [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+    };
+    let (pa, pb) = (to_xyz(a), to_xyz(b));
+    let wa = ((1.0 - fraction) * delta).sin() / delta.sin();
+    let wb = (fraction * delta).sin() / delta.sin();
+    let v: Vec<f64> = (0..3).map(|i| wa * pa[i] + wb * pb[i]).collect();
+    GeoPoint {
+        x_lon: v[1].atan2(v[0]).to_degrees(),
+        y_lat: v[2].atan2(v[0].hypot(v[1])).to_degrees(),
+    }
+}
+
+/// Decode an encoded polyline (Google algorithm, lat/lon pairs) with
+/// `precision` decimals, 5 for Google and OSRM, 6 for Valhalla.
+pub fn decode_polyline(
+    s: &str,
+    precision: u32,
+) -> anyhow::Result<Vec<GeoPoint>> {
+    let factor = 10f64.powi(precision as i32);
+    let mut bytes = s.bytes();
+    let mut next_value = || -> anyhow::Result<Option<i64>> {
+        let (mut result, mut shift) = (0i64, 0);
+        loop {
+            let b = match bytes.next() {
+                Some(b) => b as i64 - 63,
+                None if shift == 0 => return Ok(None),
+                None => anyhow::bail!("truncated polyline"),
+            };
+            if !(0..64).contains(&b) || shift > 60 {
+                anyhow::bail!("bad polyline character");
+            }
+            result |= (b & 0x1f) << shift;
+            shift += 5;
+            if b < 0x20 {
+                break;
+            }
+        }
+        Ok(Some(if result & 1 == 1 {
+            !(result >> 1)
+        } else {
+            result >> 1
+        }))
+    };
+    let (mut lat, mut lon) = (0i64, 0i64);
+    let mut points = vec![];
+    while let Some(d_lat) = next_value()? {
+        let d_lon =
+            next_value()?.ok_or(anyhow::anyhow!("truncated polyline"))?;
+        lat += d_lat;
+        lon += d_lon;
+        points.push(GeoPoint {
+            x_lon: lon as f64 / factor,
+            y_lat: lat as f64 / factor,
+        });
+    }
+    Ok(points)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_bbox() {
+        let bbox: GeoBBOX = "6.0, 50.7,6.1,50.8".parse().unwrap();
+        assert_eq!(bbox.x_min, 6.0);
+        assert_eq!(bbox.y_max, 50.8);
+        assert!("6.1,50.7,6.0,50.8".parse::<GeoBBOX>().is_err());
+        assert!("6.0,50.7".parse::<GeoBBOX>().is_err());
+    }
+
+    #[test]
+    fn test_tile_range() {
+        let world = GeoBBOX {
+            x_min: -180.0,
+            y_min: -90.0,
+            x_max: 180.0,
+            y_max: 90.0,
+        };
+        assert_eq!(tile_range(&world, 2), ((0, 0), (3, 3)));
+
+        let inner = geo_bbox(135470, 87999, 18).expand_relative(-0.1);
+        assert_eq!(tile_range(&inner, 18), ((135470, 87999), (135470, 87999)));
+    }
+
+    #[test]
+    fn test_decode_polyline() {
+        let points = decode_polyline("_p~iF~ps|U_ulLnnqC_mqNvxq`@", 5).unwrap();
+        assert_eq!(points.len(), 3);
+        assert!((points[0].y_lat - 38.5).abs() < 1e-9);
+        assert!((points[0].x_lon + 120.2).abs() < 1e-9);
+        assert!((points[2].y_lat - 43.252).abs() < 1e-9);
+        assert!((points[2].x_lon + 126.453).abs() < 1e-9);
+        assert!(decode_polyline("_p~iF~ps|U_", 5).is_err());
+    }
+
+    #[test]
+    fn test_great_circle() {
+        let a = GeoPoint {
+            x_lon: 0.0,
+            y_lat: 0.0,
+        };
+        let b = GeoPoint {
+            x_lon: 90.0,
+            y_lat: 0.0,
+        };
+        let quarter = std::f64::consts::FRAC_PI_2 * EARTH_MEAN_RADIUS;
+        assert!((haversine_meters(&a, &b) - quarter).abs() < 1e-3);
+        let mid = great_circle_point(&a, &b, 0.5);
+        assert!((mid.x_lon - 45.0).abs() < 1e-9 && mid.y_lat.abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_web_mercator_meters() {
+        let (x, y) = web_mercator_meters(180.0, WEB_MERCATOR_MAX_LAT);
+        assert!((x - 20_037_508.342_789_244).abs() < 1e-6);
+        assert!((y - 20_037_508.342_789_244).abs() < 1e-3);
+        let (x, y) = web_mercator_meters(0.0, 0.0);
+        assert!(x.abs() < 1e-6 && y.abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_polygon_intersects_bbox() {
+        let triangle = parse_polygon("0,0;10,0;0,10").unwrap();
+        let bbox = |s: &str| s.parse::<GeoBBOX>().unwrap();
+        assert!(polygon_intersects_bbox(&triangle, &bbox("1,1,2,2")));
+        assert!(polygon_intersects_bbox(&triangle, &bbox("-5,-5,20,20")));
+        assert!(polygon_intersects_bbox(&triangle, &bbox("4,-1,5,11")));
+        assert!(!polygon_intersects_bbox(&triangle, &bbox("6,6,9,9")));
+        assert!(!polygon_intersects_bbox(&triangle, &bbox("-3,-3,-1,-1")));
+    }
+
+    #[test]
+    fn test_centroids() {
+        let square = parse_polygon("0,0;2,0;2,2;0,2").unwrap();
+        let (c, area) = polygon_centroid(&square).unwrap();
+        assert!((c.x_lon - 1.0).abs() < 1e-12 && (c.y_lat - 1.0).abs() < 1e-12);
+        assert!((area - 4.0).abs() < 1e-12);
+        let line = parse_polygon("0,0;4,0;4,2").unwrap();
+        let (c, length) = line_centroid(&line).unwrap();
+        assert!((length - 6.0).abs() < 1e-12);
+        assert!((c.x_lon - 8.0 / 3.0).abs() < 1e-12);
+        assert!((c.y_lat - 1.0 / 3.0).abs() < 1e-12);
+        assert!(polygon_centroid(&line[..2]).is_none());
+    }
+
+    #[test]
+    fn test_pmtiles_tile_id() {
+        assert_eq!(pmtiles_tile_id(0, 0, 0), 0);
+        assert_eq!(pmtiles_tile_id(1, 0, 0), 1);
+        assert_eq!(pmtiles_tile_id(1, 0, 1), 2);
+        assert_eq!(pmtiles_tile_id(1, 1, 1), 3);
+        assert_eq!(pmtiles_tile_id(1, 1, 0), 4);
+        assert_eq!(pmtiles_tile_id(2, 0, 0), 5);
+        assert_eq!(pmtiles_tile_id(3, 0, 0), 21);
+        assert_eq!(pmtiles_tile_id(3, 7, 0), 84);
+        assert_eq!(pmtiles_tile_id(12, 3423, 1290), 18745070);
+        assert_eq!(pmtiles_tile_id(20, 0, 0), 366503875925);
+    }
+
     #[test]
     fn test_tile_index() {
         assert_eq!(
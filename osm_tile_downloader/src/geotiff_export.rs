@@ -0,0 +1,394 @@
+use anyhow::Result;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::config;
+use crate::config::LINKS_CONFIG;
+use crate::download_tile;
+use crate::export_job::TileExportSummary;
+use crate::geo_trig::{geo_bbox, tile_range, web_mercator_meters, GeoBBOX};
+use crate::static_map;
+
+pub const GEOTIFF_MAX_TILES: u64 = 1 << 18;
+/// Tiles fetched, decoded and compressed at once, along one tile row.
+const GEOTIFF_BATCH_TILES: usize = 64;
+
+const TIFF_SHORT: u16 = 3;
+const TIFF_LONG: u16 = 4;
+const TIFF_DOUBLE: u16 = 12;
+const TIFF_LONG8: u16 = 16;
+/// Room at the start of the file for either header; the real one is
+/// written last, once we know if the file needs BigTIFF offsets.
+const TIFF_HEADER_SIZE: u64 = 16;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct GeoTiffExportSummary {
+    pub server_name: String,
+    pub z: u8,
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub tile_count: u64,
+    pub missing_count: u64,
+    pub bigtiff: bool,
+}
+
+struct TiffEntry {
+    tag: u16,
+    typ: u16,
+    count: u64,
+    data: Vec<u8>,
+}
+
+impl TiffEntry {
+    fn shorts(tag: u16, values: &[u16]) -> Self {
+        Self {
+            tag,
+            typ: TIFF_SHORT,
+            count: values.len() as u64,
+            data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+    fn longs(tag: u16, values: &[u32]) -> Self {
+        Self {
+            tag,
+            typ: TIFF_LONG,
+            count: values.len() as u64,
+            data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+    fn long8s(tag: u16, values: &[u64]) -> Self {
+        Self {
+            tag,
+            typ: TIFF_LONG8,
+            count: values.len() as u64,
+            data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+    fn doubles(tag: u16, values: &[f64]) -> Self {
+        Self {
+            tag,
+            typ: TIFF_DOUBLE,
+            count: values.len() as u64,
+            data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+}
+
+/// Serialize one IFD placed at `ifd_offset`, with out-of-line values
+/// right after it.
+fn encode_ifd(
+    mut entries: Vec<TiffEntry>,
+    ifd_offset: u64,
+    bigtiff: bool,
+) -> Vec<u8> {
+    entries.sort_by_key(|e| e.tag);
+    let (entry_size, inline_size, count_size) =
+        if bigtiff { (20, 8, 8) } else { (12, 4, 2) };
+    // entry count, entries, next IFD offset
+    let ifd_size = count_size + entries.len() * entry_size + inline_size;
+    let mut ifd = vec![];
+    let mut extra = vec![];
+    if bigtiff {
+        ifd.extend((entries.len() as u64).to_le_bytes());
+    } else {
+        ifd.extend((entries.len() as u16).to_le_bytes());
+    }
+    for entry in entries {
+        ifd.extend(entry.tag.to_le_bytes());
+        ifd.extend(entry.typ.to_le_bytes());
+        if bigtiff {
+            ifd.extend(entry.count.to_le_bytes());
+        } else {
+            ifd.extend((entry.count as u32).to_le_bytes());
+        }
+        let mut value = if entry.data.len() <= inline_size {
+            entry.data
+        } else {
+            let offset = ifd_offset + (ifd_size + extra.len()) as u64;
+            extra.extend(entry.data);
+            if extra.len() % 2 == 1 {
+                extra.push(0);
+            }
+            if bigtiff {
+                offset.to_le_bytes().to_vec()
+            } else {
+                (offset as u32).to_le_bytes().to_vec()
+            }
+        };
+        value.resize(inline_size, 0);
+        ifd.extend(value);
+    }
+    // no next IFD
+    ifd.extend(vec![0u8; inline_size]);
+    assert_eq!(ifd.len(), ifd_size);
+    ifd.extend(extra);
+    ifd
+}
+
+/// GeoKeyDirectory for EPSG:3857, pixel-is-area.
+fn geo_key_directory() -> Vec<u16> {
+    let keys: [(u16, u16); 3] = [
+        // GTModelTypeGeoKey: projected
+        (1024, 1),
+        // GTRasterTypeGeoKey: PixelIsArea
+        (1025, 1),
+        // ProjectedCSTypeGeoKey
+        (3072, 3857),
+    ];
+    let mut dir = vec![1, 1, 0, keys.len() as u16];
+    for (key, value) in keys {
+        dir.extend([key, 0, 1, value]);
+    }
+    dir
+}
+
+/// RGB tile, horizontal differencing (predictor 2), then zlib.
+fn compress_tile(mut rgb: Vec<u8>, tile_width: u32) -> Result<Vec<u8>> {
+    let row_len = tile_width as usize * 3;
+    for row in rgb.chunks_mut(row_len) {
+        for i in (3..row.len()).rev() {
+            row[i] = row[i].wrapping_sub(row[i - 3]);
+        }
+    }
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(&rgb)?;
+    let mut compressed = encoder.finish()?;
+    if compressed.len() % 2 == 1 {
+        compressed.push(0);
+    }
+    Ok(compressed)
+}
+
+fn decode_tile(bytes: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let img = download_tile::decode_tile_image(bytes)?;
+    let img = if img.width() != width || img.height() != height {
+        img.resize_exact(width, height, image::imageops::FilterType::Triangle)
+    } else {
+        img
+    };
+    Ok(img.into_rgb8().into_raw())
+}
+
+pub fn get_geotiff_path(
+    server_name: &str,
+    z: u8,
+    ((x0, y0), (x1, y1)): ((u64, u64), (u64, u64)),
+) -> PathBuf {
+    LINKS_CONFIG
+        .tile_location
+        .join("export")
+        .join("geotiff")
+        .join(format!(
+            "{}_z{}_x{}-{}_y{}-{}.tif",
+            server_name, z, x0, x1, y0, y1
+        ))
+}
+
+/// Tile range of a GeoTIFF export, refusing exports that cannot be
+/// written, so they fail before a job is started.
+pub fn check_geotiff_export(
+    server_name: &str,
+    bbox: &GeoBBOX,
+    z: u8,
+) -> Result<((u64, u64), (u64, u64))> {
+    let server_config = config::get_tile_server(server_name)?;
+    if z > server_config.max_level {
+        anyhow::bail!(
+            "got z = {} when max for server is {}",
+            z,
+            server_config.max_level
+        );
+    }
+    let (tile_w, tile_h) = (server_config.width, server_config.height);
+    if tile_w % 16 != 0 || tile_h % 16 != 0 {
+        anyhow::bail!(
+            "tiff tiles must be multiples of 16, server has {}x{}",
+            tile_w,
+            tile_h
+        );
+    }
+    let range = tile_range(bbox, z);
+    let ((x0, y0), (x1, y1)) = range;
+    let tile_count = (x1 - x0 + 1) * (y1 - y0 + 1);
+    if tile_count > GEOTIFF_MAX_TILES {
+        anyhow::bail!(
+            "too many tiles for geotiff at z={}: {} > {}",
+            z,
+            tile_count,
+            GEOTIFF_MAX_TILES
+        );
+    }
+    Ok(range)
+}
+
+/// Write the mosaic of all tiles covering the bbox at zoom `z` as a
+/// tiled, deflate compressed GeoTIFF in EPSG:3857. One server tile is one
+/// TIFF tile, so tiles are written batch by batch as they arrive and only
+/// the tile offsets are kept in memory.
+pub async fn export_geotiff(
+    server_name: &str,
+    bbox: &GeoBBOX,
+    z: u8,
+) -> Result<GeoTiffExportSummary> {
+    let server_config = config::get_tile_server(server_name)?;
+    let (tile_w, tile_h) = (server_config.width, server_config.height);
+    let range = check_geotiff_export(server_name, bbox, z)?;
+    let ((x0, y0), (x1, y1)) = range;
+    let (tiles_across, tiles_down) = (x1 - x0 + 1, y1 - y0 + 1);
+    let tile_count = tiles_across * tiles_down;
+    let width = u32::try_from(tiles_across * tile_w as u64)?;
+    let height = u32::try_from(tiles_down * tile_h as u64)?;
+
+    let final_path = get_geotiff_path(server_name, z, range);
+    let temp_path = config::tmpdir().join(format!(
+        "{}.geotiff",
+        rand::Rng::gen::<u128>(&mut rand::thread_rng())
+    ));
+    let mut file = tokio::fs::File::create(&temp_path).await?;
+    file.write_all(&[0u8; TIFF_HEADER_SIZE as usize]).await?;
+    let mut position = TIFF_HEADER_SIZE;
+
+    let mut offsets = Vec::with_capacity(tile_count as usize);
+    let mut byte_counts = Vec::with_capacity(tile_count as usize);
+    // missing tiles all point at the same blank tile
+    let mut blank_tile: Option<(u64, u32)> = None;
+    let mut missing_count = 0;
+    for y in y0..=y1 {
+        let row = (x0..=x1).map(|x| (x, y)).collect::<Vec<_>>();
+        for batch in row.chunks(GEOTIFF_BATCH_TILES) {
+            let fetched =
+                static_map::fetch_tiles(&server_config, z, batch).await?;
+            let compressed = tokio::task::spawn_blocking(move || {
+                fetched
+                    .into_iter()
+                    .map(|bytes| match bytes {
+                        Some(bytes) => {
+                            let rgb = decode_tile(&bytes, tile_w, tile_h)?;
+                            Ok(Some(compress_tile(rgb, tile_w)?))
+                        }
+                        None => Ok(None),
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .await??;
+            for tile in compressed {
+                let tile = match tile {
+                    Some(tile) => tile,
+                    None => {
+                        missing_count += 1;
+                        if blank_tile.is_none() {
+                            let blank = vec![0u8; (tile_w * tile_h * 3) as _];
+                            let blank = compress_tile(blank, tile_w)?;
+                            file.write_all(&blank).await?;
+                            blank_tile = Some((position, blank.len() as u32));
+                            position += blank.len() as u64;
+                        }
+                        let (offset, byte_count) = blank_tile.unwrap();
+                        offsets.push(offset);
+                        byte_counts.push(byte_count);
+                        continue;
+                    }
+                };
+                file.write_all(&tile).await?;
+                offsets.push(position);
+                byte_counts.push(tile.len() as u32);
+                position += tile.len() as u64;
+            }
+        }
+    }
+
+    let top_left = geo_bbox(x0, y0, z);
+    let bottom_right = geo_bbox(x1, y1, z);
+    let (east_min, north_max) =
+        web_mercator_meters(top_left.x_min, top_left.y_max);
+    let (east_max, north_min) =
+        web_mercator_meters(bottom_right.x_max, bottom_right.y_min);
+    // generous bound on the IFD size, to stay below 4GB with classic tiff
+    let bigtiff = position + 16 * tile_count + 4096 > u32::MAX as u64;
+    let tile_offsets = if bigtiff {
+        TiffEntry::long8s(324, &offsets)
+    } else {
+        let offsets = offsets.iter().map(|o| *o as u32).collect::<Vec<_>>();
+        TiffEntry::longs(324, &offsets)
+    };
+    let entries = vec![
+        TiffEntry::longs(256, &[width]),
+        TiffEntry::longs(257, &[height]),
+        TiffEntry::shorts(258, &[8, 8, 8]),
+        // adobe deflate
+        TiffEntry::shorts(259, &[8]),
+        // rgb
+        TiffEntry::shorts(262, &[2]),
+        TiffEntry::shorts(277, &[3]),
+        TiffEntry::shorts(284, &[1]),
+        // horizontal differencing
+        TiffEntry::shorts(317, &[2]),
+        TiffEntry::longs(322, &[tile_w]),
+        TiffEntry::longs(323, &[tile_h]),
+        tile_offsets,
+        TiffEntry::longs(325, &byte_counts),
+        // ModelPixelScaleTag
+        TiffEntry::doubles(
+            33550,
+            &[
+                (east_max - east_min) / width as f64,
+                (north_max - north_min) / height as f64,
+                0.0,
+            ],
+        ),
+        // ModelTiepointTag: top left pixel corner
+        TiffEntry::doubles(33922, &[0.0, 0.0, 0.0, east_min, north_max, 0.0]),
+        TiffEntry::shorts(34735, &geo_key_directory()),
+    ];
+    let ifd = encode_ifd(entries, position, bigtiff);
+    file.write_all(&ifd).await?;
+
+    let mut header = b"II".to_vec();
+    if bigtiff {
+        header.extend(43u16.to_le_bytes());
+        header.extend(8u16.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(position.to_le_bytes());
+    } else {
+        header.extend(42u16.to_le_bytes());
+        header.extend((position as u32).to_le_bytes());
+    }
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+    file.write_all(&header).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    let final_parent = final_path.parent().expect("final path has no parent");
+    tokio::fs::create_dir_all(&final_parent).await?;
+    tokio::fs::rename(&temp_path, &final_path).await?;
+
+    Ok(GeoTiffExportSummary {
+        server_name: server_name.to_owned(),
+        z,
+        path: final_path,
+        width,
+        height,
+        tile_count,
+        missing_count,
+        bigtiff,
+    })
+}
+
+/// [`export_geotiff`] as the single result of an export job.
+pub async fn export_geotiff_job(
+    server_name: String,
+    bbox: GeoBBOX,
+    z: u8,
+) -> Result<Vec<TileExportSummary>> {
+    let summary = export_geotiff(&server_name, &bbox, z).await?;
+    Ok(vec![TileExportSummary {
+        server_name,
+        path: summary.path,
+        tile_count: summary.tile_count - summary.missing_count,
+    }])
+}
@@ -1,13 +1,48 @@
 use crate::config;
 use crate::config::TileServerConfig;
+use crate::contours;
+use crate::coverage;
+use crate::dem_render;
+use crate::download_dem;
 use crate::download_geoduck;
 use crate::download_geosearch;
+use crate::download_geosearch::{
+    GeoSearchOptions, OSMGeolocationSearchResult, ReverseGeocodeResult,
+};
 use crate::download_tile;
+use crate::download_tile::NoDataTile;
 use crate::download_tile::OverlayDrawCoordinates;
+use crate::elevation;
+use crate::elevation::{ElevationRequest, ElevationResponse};
+use crate::elevation_profile;
+use crate::elevation_profile::ProfileRequest;
+use crate::export_job;
+use crate::export_job::{ExportJob, ExportRegion, TileExportRequest};
+use crate::geo_trig::GeoBBOX;
+use crate::geo_trig::GeoPoint;
+use crate::geo_trig::WEB_MERCATOR_MAX_LAT;
+use crate::geotiff_export;
+use crate::mbtiles_export;
+use crate::offline_geocoder;
+use crate::pmtiles_export;
 use crate::rocket_anyhow;
+use crate::static_map;
+use crate::static_map::{StaticMapOverlay, StaticMapView};
+use crate::tile_placeholder;
+use crate::tile_placeholder::{MarkedPlaceholder, PlaceholderSample};
+use crate::tile_freshness;
+use crate::tile_history;
+use crate::tile_provenance;
+use crate::tile_store;
+use crate::tile_store::StoredTile;
+use crate::viewshed;
 use anyhow::Context;
+use rocket::fairing::{Fairing, Info, Kind};
 use rocket::fs::NamedFile;
 use rocket::http::ContentType;
+use rocket::http::Header;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
 use rocket::response::Responder;
 use rocket::Response;
 use std::io::Cursor;
@@ -17,7 +52,33 @@ pub fn get_api_routes() -> Vec<rocket::Route> {
     routes![
         get_tile,
         get_tile_with_overlay,
+        get_static_map,
+        build_tile_pyramid,
+        start_geotiff_export,
+        start_mbtiles_export,
+        start_pmtiles_export,
+        get_pmtiles,
+        get_export_job,
+        get_export_job_file,
+        get_placeholders,
+        mark_placeholder,
+        unmark_placeholder,
+        get_dedup_report,
+        get_coverage,
+        get_contours,
+        get_dem_tile,
+        get_elevation,
+        post_elevation,
+        post_elevation_profile,
+        get_line_of_sight,
+        get_viewshed,
+        get_tile_history,
+        get_xyz_tile,
+        get_tilejson,
+        start_history_diff,
         geo_search_json,
+        geo_reverse_json,
+        geo_search_offline,
         get_overt_geoduck,
         get_tileserver_config
     ]
@@ -47,38 +108,1267 @@ impl<'r> Responder<'r, 'static> for ImageResponse {
     }
 }
 
-#[get("/api/geo/<q_location>/json")]
-async fn geo_search_json(q_location: &str) -> rocket_anyhow::Result<NamedFile> {
+/// Image with the reverse geocoded address of the overlay point, URL
+/// encoded in `X-Reverse-Geocode`, when asked for with `reverse_geocode`.
+pub struct AnnotatedImageResponse {
+    image: ImageResponse,
+    address: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for AnnotatedImageResponse {
+    fn respond_to(
+        self,
+        _: &'r rocket::Request<'_>,
+    ) -> rocket::response::Result<'static> {
+        let img_bytes = self.image.img_bytes;
+        let mut response = Response::build();
+        response
+            .header(self.image.content_type)
+            .sized_body(img_bytes.len(), Cursor::new(img_bytes));
+        if let Some(address) = self.address {
+            response.raw_header(
+                "X-Reverse-Geocode",
+                urlencoding::encode(&address).into_owned(),
+            );
+        }
+        response.ok()
+    }
+}
+
+/// Display name of the address at `point` when `reverse_geocode` is set.
+/// None if the geocoder found nothing or failed; the image is still served.
+async fn get_annotation(
+    reverse_geocode: Option<bool>,
+    point: Option<GeoPoint>,
+) -> Option<String> {
+    if !reverse_geocode.unwrap_or(false) {
+        return None;
+    }
+    match download_geosearch::reverse_geocode(&point?).await {
+        Ok(result) => result.map(|r| r.display_name),
+        Err(err) => {
+            eprintln!("no reverse geocode annotation: {}", err);
+            None
+        }
+    }
+}
+
+/// Structured address at a point; 404 where the geocoder found nothing.
+#[get("/api/geo/reverse?<lat>&<lon>")]
+async fn geo_reverse_json(
+    lat: f64,
+    lon: f64,
+) -> rocket_anyhow::Result<Option<Json<ReverseGeocodeResult>>> {
+    let point = GeoPoint {
+        x_lon: lon,
+        y_lat: lat,
+    };
+    Ok(download_geosearch::reverse_geocode(&point).await?.map(Json))
+}
+
+#[derive(Responder)]
+pub enum GeoSearchResponse {
+    File(NamedFile),
+    Json(Json<serde_json::Value>),
+}
+
+/// Online search results as returned by the geocoder, or offline results
+/// from cached Overture data as GeoJSON when the online search fails.
+/// `limit`, `exclude_place_ids`, `countrycodes`, `viewbox` and `polygon`
+/// go to the geocoder, see `GeoSearchOptions`.
+#[get(
+    "/api/geo/<q_location>/json?<limit>&<exclude_place_ids>&<countrycodes>&<viewbox>&<polygon>"
+)]
+async fn geo_search_json(
+    q_location: &str,
+    limit: Option<u32>,
+    exclude_place_ids: Option<&str>,
+    countrycodes: Option<&str>,
+    viewbox: Option<&str>,
+    polygon: Option<bool>,
+) -> rocket_anyhow::Result<GeoSearchResponse> {
+    let options = GeoSearchOptions {
+        limit,
+        exclude_place_ids: exclude_place_ids.map(str::to_owned),
+        countrycodes: countrycodes.map(str::to_owned),
+        viewbox: viewbox.map(|b| b.parse::<GeoBBOX>()).transpose()?,
+        polygon: polygon.unwrap_or(false),
+    };
     let geojson_path =
-        download_geosearch::search_geojson_to_disk(q_location).await?;
+        match download_geosearch::search_geojson_to_disk(q_location, &options)
+            .await
+        {
+            Ok(path) => path,
+            Err(online_err) => {
+                let results = offline_geocoder::search_offline(
+                    q_location,
+                    options.viewbox.as_ref(),
+                )
+                .await?;
+                if results.is_empty() {
+                    return Err(online_err.into());
+                }
+                return Ok(GeoSearchResponse::Json(Json(
+                    offline_geocoder::results_geojson(&results),
+                )));
+            }
+        };
+
+    Ok(GeoSearchResponse::File(
+        NamedFile::open(&geojson_path).await.with_context(|| {
+            format!("file missing from disk: {:?}", &geojson_path)
+        })?,
+    ))
+}
+
+/// Search cached Overture places and divisions only, ranked by distance to
+/// the optional `viewbox=x_min,y_min,x_max,y_max`.
+#[get("/api/geo/offline?<q>&<viewbox>")]
+async fn geo_search_offline(
+    q: &str,
+    viewbox: Option<&str>,
+) -> rocket_anyhow::Result<Json<Vec<OSMGeolocationSearchResult>>> {
+    let viewbox = viewbox.map(|b| b.parse::<GeoBBOX>()).transpose()?;
+    Ok(Json(
+        offline_geocoder::search_offline(q, viewbox.as_ref()).await?,
+    ))
+}
 
-    Ok(NamedFile::open(&geojson_path).await.with_context(|| {
-        format!("file missing from disk: {:?}", &geojson_path)
-    })?)
+pub enum TileBody {
+    File(NamedFile),
+    Bytes(ImageResponse),
+    NotModified,
 }
 
-#[get("/api/tile/<server_name>/<z>/<x>/<y>/<extension>")]
+pub struct TileFileResponse {
+    body: TileBody,
+    headers: Vec<Header<'static>>,
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for TileFileResponse {
+    fn respond_to(
+        self,
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::response::Result<'static> {
+        let mut response = match self.body {
+            TileBody::File(file) => file.respond_to(request)?,
+            TileBody::Bytes(image) => image.respond_to(request)?,
+            TileBody::NotModified => {
+                Response::build().status(Status::NotModified).finalize()
+            }
+        };
+        for header in self.headers {
+            response.set_header(header);
+        }
+        Ok(response)
+    }
+}
+
+/// Raw `If-None-Match` request header.
+pub struct IfNoneMatchHeader(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatchHeader {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfNoneMatchHeader(
+            request.headers().get_one("If-None-Match").map(str::to_owned),
+        ))
+    }
+}
+
+/// Client cache lifetime for servers without `max_age`.
+const TILE_CACHE_SECONDS: u64 = 86400;
+
+/// ETag and provenance headers for the stored copy of a tile, or of the
+/// ancestor an overzoomed tile is cut from. The ETag is the content hash,
+/// plus the overzoom filter and source zoom, plus the encoding when the
+/// tile gets transcoded.
+fn get_tile_cache_headers(
+    tile: &download_tile::TileFetchId,
+    server_config: &TileServerConfig,
+    overzoom: Option<&str>,
+    transcoding: Option<(&str, Option<u8>)>,
+) -> anyhow::Result<(Option<String>, Vec<Header<'static>>)> {
+    let max_age = match (
+        server_config.max_age,
+        tile_freshness::get_tile_freshness(tile)?,
+    ) {
+        (Some(max_age), Some(freshness)) => {
+            let age = config::get_current_timestamp() - freshness.checked_at;
+            (max_age as f64 - age).max(0.0) as u64
+        }
+        (Some(_), None) => 0,
+        (None, _) => TILE_CACHE_SECONDS,
+    };
+    let mut headers = vec![Header::new(
+        "Cache-Control",
+        format!("public, max-age={}", max_age),
+    )];
+    let provenance = match tile_provenance::get_tile_provenance(tile)? {
+        Some(provenance) => provenance,
+        None => return Ok((None, headers)),
+    };
+    let mut etag = provenance.hash.clone();
+    if let Some(filter) = overzoom {
+        etag = format!("{}-{}-from_z{}", etag, filter, tile.z);
+    }
+    if let Some((format, quality)) = transcoding {
+        etag = format!(
+            "{}-{}-{}",
+            etag,
+            format,
+            quality.map_or("default".to_owned(), |q| q.to_string())
+        );
+    }
+    let etag = format!("\"{}\"", etag);
+    headers.push(Header::new("ETag", etag.clone()));
+    headers.push(Header::new(
+        "X-Tile-Fetched-At",
+        tile_provenance::http_date(provenance.fetched_at),
+    ));
+    headers.push(Header::new("X-Tile-Source", provenance.source_url));
+    Ok((Some(etag), headers))
+}
+
+/// `as_of` is unix seconds or `YYYY-MM-DD`, for the version of the tile
+/// current back then.
+#[get(
+    "/api/tile/<server_name>/<z>/<x>/<y>/<extension>?<overzoom>&<format>&<quality>&<nodata>&<as_of>",
+    rank = 2
+)]
+#[allow(clippy::too_many_arguments)]
 async fn get_tile(
     server_name: &str,
     x: u64,
     y: u64,
     z: u8,
     extension: &str,
-) -> rocket_anyhow::Result<Option<NamedFile>> {
+    overzoom: Option<&str>,
+    format: Option<&str>,
+    quality: Option<u8>,
+    nodata: Option<&str>,
+    as_of: Option<&str>,
+    if_none_match: IfNoneMatchHeader,
+) -> rocket_anyhow::Result<Option<TileFileResponse>> {
     let extension = extension.to_owned();
     let extension = if extension.contains('.') {
         extension.split('.').last().context("??")?
     } else {
         extension.as_str()
     };
-    if !extension.eq("png") && !extension.eq("jpg") {
+    if !download_tile::TILE_IMG_TYPES.contains(&extension) {
         return Ok(None);
     }
-    let path = download_tile::get_tile(server_name, x, y, z, extension).await?;
+    let server_config = config::get_tile_server(server_name)?;
+    let overzoom_filter =
+        overzoom.or(server_config.overzoom_filter.as_deref());
+    let mut headers = vec![];
+    let as_of = as_of.map(tile_history::parse_date).transpose()?;
+    // provenance to validate against: the stored tile itself, or the
+    // ancestor of an overzoomed tile
+    let mut stored_tile_id = None;
+    let mut overzoomed_with = None;
+    let path = match (as_of, overzoom_filter) {
+        (Some(as_of), _) => {
+            match tile_history::get_tile_as_of(
+                server_name,
+                x,
+                y,
+                z,
+                extension,
+                as_of,
+            )
+            .await?
+            {
+                Some(tile) => Ok(tile),
+                None => return Ok(None),
+            }
+        }
+        (None, Some(filter)) if z > server_config.max_level => {
+            download_tile::get_overzoom_tile(
+                server_name,
+                x,
+                y,
+                z,
+                extension,
+                filter,
+            )
+            .await
+            .map(|tile| {
+                headers.push(Header::new(
+                    "X-Tile-Overzoom",
+                    format!("{}; from_z={}", filter, tile.ancestor.z),
+                ));
+                stored_tile_id = Some(tile.ancestor);
+                overzoomed_with = Some(filter);
+                StoredTile::File(tile.path)
+            })
+        }
+        _ => {
+            stored_tile_id = Some(download_tile::TileFetchId {
+                x,
+                y,
+                z,
+                server_name: server_name.to_owned(),
+                extension: extension.to_owned(),
+            });
+            download_tile::get_tile(server_name, x, y, z, extension).await
+        }
+    };
+    // placeholder tiles are 404 so clients fall back to other sources,
+    // unless they ask for ?nodata=transparent
+    let tile = match path {
+        Ok(tile) => tile,
+        Err(err) => {
+            let no_data = match err.downcast_ref::<NoDataTile>() {
+                Some(no_data) => no_data,
+                None => return Err(err.into()),
+            };
+            if nodata != Some("transparent") {
+                return Ok(None);
+            }
+            stored_tile_id = None;
+            headers.push(Header::new("X-Tile-NoData", no_data.reason.clone()));
+            StoredTile::File(
+                download_tile::get_transparent_tile(&server_config).await?,
+            )
+        }
+    };
+    let transcoding = match format {
+        Some(format) if !format.eq(extension) || quality.is_some() => {
+            Some((format, quality))
+        }
+        _ => None,
+    };
+    if let Some(tile_id) = &stored_tile_id {
+        let (etag, cache_headers) = get_tile_cache_headers(
+            tile_id,
+            &server_config,
+            overzoomed_with,
+            transcoding,
+        )?;
+        headers.extend(cache_headers);
+        if etag.is_some()
+            && if_none_match.0.is_some_and(|inm| {
+                inm.split(',').any(|t| {
+                    t.trim() == "*"
+                        || t.trim().trim_start_matches("W/")
+                            == etag.as_deref().unwrap_or_default()
+                })
+            })
+        {
+            return Ok(Some(TileFileResponse {
+                body: TileBody::NotModified,
+                headers,
+            }));
+        }
+    }
+    let tile = match (format, tile) {
+        (Some(format), StoredTile::File(path))
+            if !format.eq(extension) || quality.is_some() =>
+        {
+            StoredTile::File(
+                download_tile::get_transcoded_tile(&path, format, quality)
+                    .await?,
+            )
+        }
+        (Some(format), StoredTile::Bytes(bytes))
+            if !format.eq(extension) || quality.is_some() =>
+        {
+            StoredTile::Bytes(
+                download_tile::transcode_tile_bytes(bytes, format, quality)
+                    .await?,
+            )
+        }
+        (_, tile) => tile,
+    };
 
-    Ok(Some(NamedFile::open(&path).await.with_context(|| {
-        format!("file missing from disk: {:?}", &path)
-    })?))
+    let body = match tile {
+        StoredTile::File(path) => {
+            TileBody::File(NamedFile::open(&path).await.with_context(
+                || format!("file missing from disk: {:?}", &path),
+            )?)
+        }
+        StoredTile::Bytes(img_bytes) => TileBody::Bytes(ImageResponse {
+            img_bytes,
+            content_type: ContentType::from_extension(
+                format.unwrap_or(extension),
+            )
+            .context("bad extension?")?,
+        }),
+    };
+    Ok(Some(TileFileResponse { body, headers }))
+}
+
+/// Standard `{z}/{x}/{y}.{ext}` layout for web map clients.
+#[get("/tiles/<server_name>/<z>/<x>/<y_ext>")]
+async fn get_xyz_tile(
+    server_name: &str,
+    x: u64,
+    y_ext: &str,
+    z: u8,
+    if_none_match: IfNoneMatchHeader,
+) -> rocket_anyhow::Result<Option<TileFileResponse>> {
+    let (y, extension) = match y_ext.split_once('.') {
+        Some((y, extension)) => match y.parse::<u64>() {
+            Ok(y) => (y, extension),
+            Err(_) => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    get_tile(
+        server_name,
+        x,
+        y,
+        z,
+        extension,
+        None,
+        None,
+        None,
+        None,
+        None,
+        if_none_match,
+    )
+    .await
+}
+
+/// Raw `Host` request header.
+pub struct HostHeader(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HostHeader {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> Outcome<Self, Self::Error> {
+        Outcome::Success(HostHeader(
+            request.headers().get_one("Host").map(str::to_owned),
+        ))
+    }
+}
+
+/// `X-Forwarded-Proto` set by a TLS terminating reverse proxy; only
+/// `http` and `https` are kept.
+pub struct ForwardedProtoHeader(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ForwardedProtoHeader {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> Outcome<Self, Self::Error> {
+        let proto = request
+            .headers()
+            .get_one("X-Forwarded-Proto")
+            .and_then(|v| v.split(',').next())
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| v == "http" || v == "https");
+        Outcome::Success(ForwardedProtoHeader(proto))
+    }
+}
+
+/// TileJSON 3.0 document for `/tiles/<server_name>.json`.
+#[get("/tiles/<file_name>")]
+async fn get_tilejson(
+    file_name: &str,
+    host: HostHeader,
+    proto: ForwardedProtoHeader,
+) -> rocket_anyhow::Result<Option<Json<serde_json::Value>>> {
+    let server_name = match file_name.strip_suffix(".json") {
+        Some(server_name) => server_name,
+        None => return Ok(None),
+    };
+    let server_config = config::get_tile_server(server_name)?;
+    let host = host.0.unwrap_or("localhost:8000".to_owned());
+    let scheme = proto.0.unwrap_or("http".to_owned());
+    let min_zoom = match server_config.dem_server {
+        Some(_) => dem_render::get_dem_render_min_zoom(&server_config)?,
+        None => 0,
+    };
+    Ok(Some(Json(serde_json::json!({
+        "tilejson": "3.0.0",
+        "name": server_config.name,
+        "description": server_config.comment,
+        "attribution": server_config.attribution,
+        "scheme": "xyz",
+        "tiles": [format!(
+            "{}://{}/tiles/{}/{{z}}/{{x}}/{{y}}.{}",
+            scheme, host, server_config.name, server_config.img_type
+        )],
+        "minzoom": min_zoom,
+        "maxzoom": server_config.max_level,
+        "bounds": [
+            -180.0,
+            -WEB_MERCATOR_MAX_LAT,
+            180.0,
+            WEB_MERCATOR_MAX_LAT
+        ],
+    }))))
+}
+
+/// Lets browser map clients on other origins read the tile, TileJSON,
+/// PMTiles and api endpoints, answering preflight requests too. Only
+/// GET and HEAD are allowed, so other origins cannot start jobs or
+/// change state through the POST routes.
+pub struct Cors;
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(
+        &self,
+        request: &'r rocket::Request<'_>,
+        response: &mut Response<'r>,
+    ) {
+        use rocket::http::Method;
+
+        let path = request.uri().path();
+        if !["/tiles/", "/pmtiles/", "/api/"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+        {
+            return;
+        }
+        let read_only = match request.method() {
+            Method::Get | Method::Head => true,
+            Method::Options => matches!(
+                request.headers().get_one("Access-Control-Request-Method"),
+                Some("GET") | Some("HEAD")
+            ),
+            _ => false,
+        };
+        if !read_only {
+            return;
+        }
+        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        response.set_header(Header::new(
+            "Access-Control-Allow-Methods",
+            "GET, HEAD, OPTIONS",
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Allow-Headers",
+            "Range, If-None-Match",
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Expose-Headers",
+            "ETag, Content-Range, Content-Length, X-Tile-Fetched-At, \
+            X-Tile-Source",
+        ));
+        if request.method() == Method::Options
+            && response.status() == Status::NotFound
+        {
+            response.set_status(Status::NoContent);
+            response.set_sized_body(0, Cursor::new(vec![]));
+        }
+    }
+}
+
+#[get("/api/tile/<server_name>/<z>/<x>/<y>/history", rank = 1)]
+async fn get_tile_history(
+    server_name: &str,
+    x: u64,
+    y: u64,
+    z: u8,
+) -> rocket_anyhow::Result<Json<tile_history::TileHistory>> {
+    let server_config = config::get_tile_server(server_name)?;
+    let tile = download_tile::TileFetchId {
+        x,
+        y,
+        z,
+        server_name: server_name.to_owned(),
+        extension: server_config.img_type,
+    };
+    Ok(Json(tile_history::get_tile_history(&tile).await?))
+}
+
+/// Flag tiles whose pixels changed by more than `threshold` (fraction of
+/// pixels, default 0.05) since `since` (default: all history).
+#[post("/api/history/diff?<threshold>&<since>", data = "<request>")]
+async fn start_history_diff(
+    request: Json<TileExportRequest>,
+    threshold: Option<f64>,
+    since: Option<&str>,
+) -> rocket_anyhow::Result<Json<ExportJob>> {
+    let request = request.into_inner();
+    let threshold = threshold.unwrap_or(0.05);
+    let since = since.map(tile_history::parse_date).transpose()?;
+    let job = export_job::start_export_job(
+        &format!("history_diff threshold={}", threshold),
+        request.clone(),
+        tile_history::diff_history(request, threshold, since.unwrap_or(0.0)),
+    )?;
+    Ok(Json(job))
+}
+
+/// Writes tiles, so POST; progress shows in the stat counters as
+/// `pyramid_build`.
+#[post("/api/pyramid/<server_name>/<min_z>/<max_z>?<bbox>")]
+async fn build_tile_pyramid(
+    server_name: &str,
+    min_z: u8,
+    max_z: u8,
+    bbox: GeoBBOX,
+) -> rocket_anyhow::Result<Json<download_tile::PyramidBuildSummary>> {
+    let summary =
+        download_tile::build_pyramid(server_name, &bbox, min_z, max_z).await?;
+    Ok(Json(summary))
+}
+
+/// The GeoTIFF is served from `/api/export/job/<id>/file/0` once done.
+#[post("/api/export/geotiff/<server_name>/<z>?<bbox>")]
+async fn start_geotiff_export(
+    server_name: &str,
+    z: u8,
+    bbox: GeoBBOX,
+) -> rocket_anyhow::Result<Json<ExportJob>> {
+    geotiff_export::check_geotiff_export(server_name, &bbox, z)?;
+    let request = TileExportRequest {
+        servers: vec![server_name.to_owned()],
+        region: ExportRegion {
+            bbox: Some(bbox),
+            polygon: None,
+        },
+        min_z: z,
+        max_z: z,
+    };
+    let job = export_job::start_export_job(
+        "geotiff",
+        request,
+        geotiff_export::export_geotiff_job(server_name.to_owned(), bbox, z),
+    )?;
+    Ok(Json(job))
+}
+
+#[post("/api/export/mbtiles", data = "<request>")]
+async fn start_mbtiles_export(
+    request: Json<TileExportRequest>,
+) -> rocket_anyhow::Result<Json<ExportJob>> {
+    let request = request.into_inner();
+    let job = export_job::start_export_job(
+        "mbtiles",
+        request.clone(),
+        mbtiles_export::export_mbtiles(request),
+    )?;
+    Ok(Json(job))
+}
+
+#[post("/api/export/pmtiles", data = "<request>")]
+async fn start_pmtiles_export(
+    request: Json<TileExportRequest>,
+) -> rocket_anyhow::Result<Json<ExportJob>> {
+    let request = request.into_inner();
+    let job = export_job::start_export_job(
+        "pmtiles",
+        request.clone(),
+        pmtiles_export::export_pmtiles(request),
+    )?;
+    Ok(Json(job))
+}
+
+/// Raw `Range` request header.
+pub struct RangeHeader(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RangeHeader(
+            request.headers().get_one("Range").map(str::to_owned),
+        ))
+    }
+}
+
+/// Ranges are read into memory, so longer ones are cut to this many
+/// bytes. The Content-Range header names the bytes actually sent, and
+/// clients (PMTiles readers included) request the rest from there.
+const MAX_RANGE_RESPONSE: u64 = 64 << 20;
+
+/// What to answer to a `Range` header.
+enum ByteRange {
+    /// Multiple ranges, other units or garbage: send the whole file.
+    Ignored,
+    Unsatisfiable,
+    /// Inclusive, at most [`MAX_RANGE_RESPONSE`] long.
+    Range(u64, u64),
+}
+
+/// Only single `bytes=` ranges are served, anything else gets the full
+/// body, as if there were no `Range` header.
+fn parse_byte_range(range: &str, len: u64) -> ByteRange {
+    let Some(spec) = range.strip_prefix("bytes=") else {
+        return ByteRange::Ignored;
+    };
+    if spec.contains(',') {
+        return ByteRange::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Ignored;
+    };
+    let (start, end) = if start.is_empty() {
+        match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.checked_sub(1)),
+            Err(_) => return ByteRange::Ignored,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return ByteRange::Ignored;
+        };
+        let end = match end {
+            "" => len.checked_sub(1),
+            end => match end.parse::<u64>() {
+                Ok(end) if end < start => return ByteRange::Ignored,
+                Ok(end) => len.checked_sub(1).map(|last| end.min(last)),
+                Err(_) => return ByteRange::Ignored,
+            },
+        };
+        (start, end)
+    };
+    match end {
+        Some(end) if start <= end => {
+            ByteRange::Range(start, end.min(start + MAX_RANGE_RESPONSE - 1))
+        }
+        _ => ByteRange::Unsatisfiable,
+    }
+}
+
+pub enum RangeFileResponse {
+    Full(NamedFile),
+    Partial {
+        bytes: Vec<u8>,
+        start: u64,
+        end: u64,
+        len: u64,
+    },
+    Unsatisfiable {
+        len: u64,
+    },
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for RangeFileResponse {
+    fn respond_to(
+        self,
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::response::Result<'static> {
+        let mut response = match self {
+            RangeFileResponse::Full(file) => file.respond_to(request)?,
+            RangeFileResponse::Partial {
+                bytes,
+                start,
+                end,
+                len,
+            } => Response::build()
+                .status(Status::PartialContent)
+                .raw_header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end, len),
+                )
+                .sized_body(bytes.len(), Cursor::new(bytes))
+                .finalize(),
+            RangeFileResponse::Unsatisfiable { len } => Response::build()
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", len))
+                .finalize(),
+        };
+        response.set_header(ContentType::Binary);
+        response.set_raw_header("Accept-Ranges", "bytes");
+        Ok(response)
+    }
+}
+
+#[get("/pmtiles/<file_name>")]
+async fn get_pmtiles(
+    file_name: &str,
+    range: RangeHeader,
+) -> rocket_anyhow::Result<Option<RangeFileResponse>> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let path = pmtiles_export::get_pmtiles_file(file_name)?;
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    let len = file.metadata().await?.len();
+    let range = match range.0 {
+        Some(range) => range,
+        None => {
+            return Ok(Some(RangeFileResponse::Full(
+                NamedFile::open(&path).await?,
+            )))
+        }
+    };
+    let (start, end) = match parse_byte_range(&range, len) {
+        ByteRange::Range(start, end) => (start, end),
+        ByteRange::Unsatisfiable => {
+            return Ok(Some(RangeFileResponse::Unsatisfiable { len }))
+        }
+        ByteRange::Ignored => {
+            return Ok(Some(RangeFileResponse::Full(
+                NamedFile::open(&path).await?,
+            )))
+        }
+    };
+    let mut bytes = vec![0u8; (end - start + 1) as usize];
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    file.read_exact(&mut bytes).await?;
+    Ok(Some(RangeFileResponse::Partial {
+        bytes,
+        start,
+        end,
+        len,
+    }))
+}
+
+#[get("/api/export/job/<id>")]
+async fn get_export_job(id: &str) -> rocket_anyhow::Result<Json<ExportJob>> {
+    Ok(Json(export_job::get_export_job(id)?))
+}
+
+#[get("/api/export/job/<id>/file/<index>")]
+async fn get_export_job_file(
+    id: &str,
+    index: usize,
+) -> rocket_anyhow::Result<NamedFile> {
+    let path = export_job::get_export_job_file(id, index)?;
+    Ok(NamedFile::open(&path)
+        .await
+        .with_context(|| format!("file missing from disk: {:?}", &path))?)
+}
+
+#[get("/api/placeholder/<server_name>")]
+async fn get_placeholders(
+    server_name: &str,
+) -> rocket_anyhow::Result<Json<Vec<PlaceholderSample>>> {
+    Ok(Json(tile_placeholder::get_placeholder_samples(server_name)?))
+}
+
+/// Learn a placeholder from a cached tile; cached tiles matching it are
+/// re-classified by the returned export job.
+#[post("/api/placeholder/<server_name>/<z>/<x>/<y>/mark")]
+async fn mark_placeholder(
+    server_name: &str,
+    x: u64,
+    y: u64,
+    z: u8,
+) -> rocket_anyhow::Result<Json<MarkedPlaceholder>> {
+    let marked =
+        tile_placeholder::mark_placeholder(server_name, x, y, z).await?;
+    Ok(Json(marked))
+}
+
+#[post("/api/placeholder/<server_name>/<dhash>/unmark")]
+async fn unmark_placeholder(
+    server_name: &str,
+    dhash: &str,
+) -> rocket_anyhow::Result<Json<Vec<PlaceholderSample>>> {
+    let server_name = server_name.to_owned();
+    let dhash = dhash.to_owned();
+    let samples = tokio::task::spawn_blocking(move || {
+        tile_placeholder::unmark_placeholder(&server_name, &dhash)?;
+        tile_placeholder::get_placeholder_samples(&server_name)
+    })
+    .await??;
+    Ok(Json(samples))
+}
+
+#[get("/api/tile_store/dedup")]
+async fn get_dedup_report(
+) -> rocket_anyhow::Result<Json<Vec<tile_store::DedupReport>>> {
+    let reports =
+        tokio::task::spawn_blocking(tile_store::get_dedup_report).await??;
+    Ok(Json(reports))
+}
+
+/// Heat-map with the covered tile range as `X-Coverage-Tiles:
+/// x0,y0,x1,y1` and the tiles per pixel side as `X-Coverage-Cell`.
+pub struct CoveragePng {
+    img_bytes: Vec<u8>,
+    range: ((u64, u64), (u64, u64)),
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for CoveragePng {
+    fn respond_to(
+        self,
+        _: &'r rocket::Request<'_>,
+    ) -> rocket::response::Result<'static> {
+        let ((x0, y0), (x1, y1)) = self.range;
+        Response::build()
+            .header(ContentType::PNG)
+            .raw_header(
+                "X-Coverage-Tiles",
+                format!("{},{},{},{}", x0, y0, x1, y1),
+            )
+            .raw_header(
+                "X-Coverage-Cell",
+                coverage::get_coverage_cell_size(self.range).to_string(),
+            )
+            .sized_body(self.img_bytes.len(), Cursor::new(self.img_bytes))
+            .ok()
+    }
+}
+
+#[derive(Responder)]
+pub enum CoverageResponse {
+    Png(CoveragePng),
+    Json(Json<serde_json::Value>),
+}
+
+/// Cached, placeholder and failed tiles of one zoom level, as a `png`
+/// heat-map (default), `geojson` tile polygons or a `summary`.
+#[get("/api/coverage/<server_name>/<z>?<format>&<bbox>")]
+async fn get_coverage(
+    server_name: &str,
+    z: u8,
+    format: Option<&str>,
+    bbox: Option<&str>,
+) -> rocket_anyhow::Result<CoverageResponse> {
+    if z > 30 {
+        return Err(anyhow::anyhow!("zoom {} out of range", z).into());
+    }
+    let bbox = bbox.map(|b| b.parse::<GeoBBOX>()).transpose()?;
+    let range = coverage::get_coverage_range(bbox.as_ref(), z);
+    let server_name = server_name.to_owned();
+    let format = format.unwrap_or("png").to_owned();
+    let response = tokio::task::spawn_blocking(
+        move || -> anyhow::Result<CoverageResponse> {
+            let tiles = coverage::get_coverage(&server_name, z, range)?;
+            Ok(match format.as_str() {
+                "png" => CoverageResponse::Png(CoveragePng {
+                    img_bytes: coverage::render_coverage_png(range, &tiles)?,
+                    range,
+                }),
+                "geojson" => CoverageResponse::Json(Json(
+                    coverage::coverage_geojson(z, &tiles)?,
+                )),
+                "summary" => CoverageResponse::Json(Json(
+                    serde_json::to_value(coverage::summarize_coverage(
+                        &server_name,
+                        z,
+                        range,
+                        &tiles,
+                    ))?,
+                )),
+                _ => anyhow::bail!("unknown coverage format {:?}", format),
+            })
+        },
+    )
+    .await??;
+    Ok(response)
+}
+
+/// Contour LineStrings of a web tile, every `interval` meters (default
+/// 10), tagged with `elevation` and `major` for every 5th line.
+#[get("/api/contours/<z>/<x>/<y_geojson>?<interval>&<server>")]
+async fn get_contours(
+    z: u8,
+    x: u64,
+    y_geojson: &str,
+    interval: Option<f64>,
+    server: Option<&str>,
+) -> rocket_anyhow::Result<Json<serde_json::Value>> {
+    let y: u64 = y_geojson
+        .strip_suffix(".geojson")
+        .context("expected <y>.geojson")?
+        .parse()?;
+    let max_extent = 2u64.pow(z.into()) - 1;
+    if x > max_extent || y > max_extent {
+        return Err(anyhow::anyhow!(
+            "x={}, y={} not inside extent={} for z={}",
+            x,
+            y,
+            max_extent,
+            z
+        )
+        .into());
+    }
+    Ok(Json(
+        contours::get_contours_geojson(
+            server,
+            z,
+            x,
+            y,
+            interval.unwrap_or(10.0),
+        )
+        .await?,
+    ))
+}
+
+/// Raw DEM tile of a topography server, at its `download_zoomlevel`, or
+/// resampled from it up to `scale_zoomlevel`.
+#[get("/api/dem/<server_name>/<z>/<x>/<y>")]
+async fn get_dem_tile(
+    server_name: &str,
+    z: u8,
+    x: u64,
+    y: u64,
+) -> rocket_anyhow::Result<NamedFile> {
+    let path = download_dem::get_dem_tile(server_name, x, y, z).await?;
+    Ok(NamedFile::open(&path)
+        .await
+        .with_context(|| format!("file missing from disk: {:?}", &path))?)
+}
+
+/// One point as `lat` and `lon`, or Open-Meteo style comma separated
+/// `latitude` and `longitude` lists. `server` picks the topography server.
+#[get("/api/elevation?<lat>&<lon>&<latitude>&<longitude>&<server>")]
+async fn get_elevation(
+    lat: Option<f64>,
+    lon: Option<f64>,
+    latitude: Option<&str>,
+    longitude: Option<&str>,
+    server: Option<&str>,
+) -> rocket_anyhow::Result<Json<ElevationResponse>> {
+    let parse_list = |list: &str| -> anyhow::Result<Vec<f64>> {
+        Ok(list
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<_, _>>()?)
+    };
+    let request = match (lat, lon, latitude, longitude) {
+        (Some(lat), Some(lon), None, None) => ElevationRequest {
+            latitude: vec![lat],
+            longitude: vec![lon],
+        },
+        (None, None, Some(latitude), Some(longitude)) => ElevationRequest {
+            latitude: parse_list(latitude)?,
+            longitude: parse_list(longitude)?,
+        },
+        _ => {
+            return Err(anyhow::anyhow!(
+                "need either lat and lon, or latitude and longitude"
+            )
+            .into())
+        }
+    };
+    let elevation =
+        elevation::get_elevations(server, &request.points()?).await?;
+    Ok(Json(ElevationResponse { elevation }))
+}
+
+/// Batch of up to `elevation::ELEVATION_MAX_POINTS` points, same request
+/// and response body as the Open-Meteo elevation API.
+#[post("/api/elevation?<server>", data = "<request>")]
+async fn post_elevation(
+    request: Json<ElevationRequest>,
+    server: Option<&str>,
+) -> rocket_anyhow::Result<Json<ElevationResponse>> {
+    let elevation =
+        elevation::get_elevations(server, &request.points()?).await?;
+    Ok(Json(ElevationResponse { elevation }))
+}
+
+#[derive(Responder)]
+pub enum ProfileResponse {
+    Json(Json<elevation_profile::ElevationProfile>),
+    Png(ImageResponse),
+}
+
+/// Elevation profile along a LineString or polyline, as `json` (default)
+/// or a `png` chart.
+#[post("/api/elevation/profile?<format>&<server>", data = "<request>")]
+async fn post_elevation_profile(
+    request: Json<ProfileRequest>,
+    format: Option<&str>,
+    server: Option<&str>,
+) -> rocket_anyhow::Result<ProfileResponse> {
+    let profile =
+        elevation_profile::get_elevation_profile(server, &request).await?;
+    Ok(match format.unwrap_or("json") {
+        "json" => ProfileResponse::Json(Json(profile)),
+        "png" => ProfileResponse::Png(ImageResponse {
+            img_bytes: tokio::task::spawn_blocking(move || {
+                elevation_profile::render_profile_png(&profile)
+            })
+            .await??,
+            content_type: ContentType::PNG,
+        }),
+        format => {
+            return Err(
+                anyhow::anyhow!("unknown profile format {:?}", format).into()
+            )
+        }
+    })
+}
+
+/// Whether `target` can be seen from `observer` (both `lon,lat`), with
+/// the first terrain obstruction if not. Heights are above the ground.
+#[get("/api/line_of_sight?<observer>&<target>&<observer_height>&<target_height>&<spacing>&<server>")]
+async fn get_line_of_sight(
+    observer: &str,
+    target: &str,
+    observer_height: Option<f64>,
+    target_height: Option<f64>,
+    spacing: Option<f64>,
+    server: Option<&str>,
+) -> rocket_anyhow::Result<Json<viewshed::LineOfSight>> {
+    Ok(Json(
+        viewshed::get_line_of_sight(
+            server,
+            &observer.parse::<GeoPoint>()?,
+            &target.parse::<GeoPoint>()?,
+            observer_height.unwrap_or(viewshed::DEFAULT_OBSERVER_HEIGHT),
+            target_height.unwrap_or(0.0),
+            spacing.unwrap_or(elevation_profile::PROFILE_DEFAULT_SPACING),
+        )
+        .await?,
+    ))
+}
+
+/// Viewshed raster with its lat/lon bounds as `X-Viewshed-Bbox:
+/// x_min,y_min,x_max,y_max`.
+pub struct ViewshedPng {
+    img_bytes: Vec<u8>,
+    bbox: GeoBBOX,
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for ViewshedPng {
+    fn respond_to(
+        self,
+        _: &'r rocket::Request<'_>,
+    ) -> rocket::response::Result<'static> {
+        let b = self.bbox;
+        Response::build()
+            .header(ContentType::PNG)
+            .raw_header(
+                "X-Viewshed-Bbox",
+                format!("{},{},{},{}", b.x_min, b.y_min, b.x_max, b.y_max),
+            )
+            .sized_body(self.img_bytes.len(), Cursor::new(self.img_bytes))
+            .ok()
+    }
+}
+
+#[derive(Responder)]
+pub enum ViewshedResponse {
+    Png(ViewshedPng),
+    Json(Json<serde_json::Value>),
+}
+
+/// Terrain visible from `observer` (`lon,lat`) within `radius` meters, as
+/// a `png` raster (default) or a `geojson` MultiPolygon.
+#[get("/api/viewshed?<observer>&<radius>&<observer_height>&<format>&<server>")]
+async fn get_viewshed(
+    observer: &str,
+    radius: f64,
+    observer_height: Option<f64>,
+    format: Option<&str>,
+    server: Option<&str>,
+) -> rocket_anyhow::Result<ViewshedResponse> {
+    let format = format.unwrap_or("png");
+    if !["png", "geojson"].contains(&format) {
+        return Err(
+            anyhow::anyhow!("unknown viewshed format {:?}", format).into()
+        );
+    }
+    let viewshed = viewshed::get_viewshed(
+        server,
+        &observer.parse::<GeoPoint>()?,
+        observer_height.unwrap_or(viewshed::DEFAULT_OBSERVER_HEIGHT),
+        radius,
+    )
+    .await?;
+    let format = format.to_owned();
+    let response = tokio::task::spawn_blocking(
+        move || -> anyhow::Result<ViewshedResponse> {
+            Ok(match format.as_str() {
+                "png" => ViewshedResponse::Png(ViewshedPng {
+                    img_bytes: viewshed::render_viewshed_png(&viewshed)?,
+                    bbox: viewshed.bbox,
+                }),
+                _ => ViewshedResponse::Json(Json(viewshed::viewshed_geojson(
+                    &viewshed,
+                ))),
+            })
+        },
+    )
+    .await??;
+    Ok(response)
+}
+
+/// `bbox=x_min,y_min,x_max,y_max` (optional `zoom`) or
+/// `center=lon,lat&zoom=z`; overlay point/bbox use the same syntax.
+/// `reverse_geocode=true` adds the address of the overlay point, or of
+/// the map center, as `X-Reverse-Geocode`.
+#[get(
+    "/api/static/<server_name>?<bbox>&<center>&<zoom>&<width>&<height>&<format>&<overlay_point>&<overlay_bbox>&<reverse_geocode>"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn get_static_map(
+    server_name: &str,
+    bbox: Option<&str>,
+    center: Option<&str>,
+    zoom: Option<u8>,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<&str>,
+    overlay_point: Option<&str>,
+    overlay_bbox: Option<&str>,
+    reverse_geocode: Option<bool>,
+) -> rocket_anyhow::Result<AnnotatedImageResponse> {
+    let view = match (bbox, center, zoom) {
+        (Some(bbox), None, zoom) => {
+            StaticMapView::BBox(bbox.parse::<GeoBBOX>()?, zoom)
+        }
+        (None, Some(center), Some(zoom)) => {
+            StaticMapView::Center(center.parse::<GeoPoint>()?, zoom)
+        }
+        _ => {
+            return Err(
+                anyhow::anyhow!("need either bbox, or center and zoom").into()
+            )
+        }
+    };
+    let overlay = StaticMapOverlay {
+        point: overlay_point.map(|p| p.parse::<GeoPoint>()).transpose()?,
+        bbox: overlay_bbox.map(|b| b.parse::<GeoBBOX>()).transpose()?,
+    };
+    let format = format.unwrap_or("png");
+    let content_type =
+        ContentType::from_extension(format).context("bad format?")?;
+    let img_bytes = static_map::render_static_map(
+        server_name,
+        view,
+        width.unwrap_or(800),
+        height.unwrap_or(600),
+        overlay,
+        format,
+    )
+    .await?;
+    // the overlay point, else the center of the map
+    let point = overlay.point.or(match view {
+        StaticMapView::Center(center, _) => Some(center),
+        StaticMapView::BBox(bbox, _) => Some(GeoPoint {
+            x_lon: (bbox.x_min + bbox.x_max) / 2.0,
+            y_lat: (bbox.y_min + bbox.y_max) / 2.0,
+        }),
+    });
+    Ok(AnnotatedImageResponse {
+        image: ImageResponse {
+            img_bytes,
+            content_type,
+        },
+        address: get_annotation(reverse_geocode, point).await,
+    })
 }
 
 #[get("/api/overt_geoduck/<theme>/<o_type>/<z>/<x>/<y>/overt.parquet")]
@@ -105,8 +1395,8 @@ async fn get_tile_with_overlay(
     z: u8,
     extension: &str,
     overlay_coordinates: OverlayDrawCoordinates,
-) -> rocket_anyhow::Result<ImageResponse> {
-    let path = download_tile::get_tile(server_name, x, y, z, extension).await?;
+) -> rocket_anyhow::Result<AnnotatedImageResponse> {
+    let tile = download_tile::get_tile(server_name, x, y, z, extension).await?;
     let server_config = config::get_tile_server(server_name)?;
     let img_type = server_config.img_type.clone();
     assert!(img_type.eq(extension));
@@ -119,15 +1409,23 @@ async fn get_tile_with_overlay(
         y,
         z,
         extension,
-        &path,
+        tile.read().await?,
         &overlay_coordinates,
         &server_config,
     )
     .await?;
+    let address = get_annotation(
+        overlay_coordinates.reverse_geocode,
+        overlay_coordinates.point,
+    )
+    .await;
 
     let img_response = ImageResponse {
         img_bytes,
         content_type,
     };
-    Ok(img_response)
+    Ok(AnnotatedImageResponse {
+        image: img_response,
+        address,
+    })
 }
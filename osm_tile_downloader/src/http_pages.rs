@@ -16,7 +16,7 @@ use crate::rocket_anyhow;
 use crate::stat_counter;
 
 pub fn get_page_routes() -> Vec<rocket::Route> {
-    routes![index, health_check, favicon, geo_index, proxy_info,]
+    routes![index, map_page, health_check, favicon, geo_index, proxy_info,]
 }
 
 #[get("/health_check")]
@@ -39,6 +39,12 @@ fn index() -> rocket_anyhow::Result<Template> {
     ))
 }
 
+/// Slippy map over the locally served tiles.
+#[get("/map")]
+fn map_page() -> Template {
+    Template::render("map", context! {})
+}
+
 #[get("/proxy")]
 async fn proxy_info() -> rocket_anyhow::Result<Template> {
     let scrapers = config::get_all_socks5_scrapers()?;
@@ -66,7 +72,11 @@ async fn proxy_info() -> rocket_anyhow::Result<Template> {
 #[get("/geo/<q_location>")]
 async fn geo_index(q_location: &str) -> rocket_anyhow::Result<Template> {
     let geo_search_results =
-        download_geosearch::search_geojson(q_location).await?;
+        download_geosearch::search_geojson(
+            q_location,
+            &download_geosearch::GeoSearchOptions::default(),
+        )
+        .await?;
     if geo_search_results.is_empty() {
         return Err(anyhow!(
             "no features found after searching for '{}'",
@@ -99,6 +109,9 @@ async fn geo_index(q_location: &str) -> rocket_anyhow::Result<Template> {
                         crate::download_tile::OverlayDrawCoordinates {
                             point: Some(feature.geo_point),
                             bbox: Some(feature.bbox),
+                            contour_interval: None,
+                            contour_server: None,
+                            reverse_geocode: None,
                         };
                     let the_uri = uri!(http_api::get_tile_with_overlay(
                         server_name = server_name,
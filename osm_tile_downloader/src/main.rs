@@ -1,18 +1,37 @@
 #![allow(clippy::assigning_clones)]
 #![allow(clippy::needless_borrows_for_generic_args)]
 
+pub(crate) mod cli;
 pub(crate) mod config;
+pub(crate) mod contours;
+pub(crate) mod coverage;
+pub(crate) mod dem_render;
+pub(crate) mod download_dem;
 pub(crate) mod download_everything;
 pub(crate) mod download_geoduck;
 pub(crate) mod download_geosearch;
 pub(crate) mod download_tile;
+pub(crate) mod elevation;
+pub(crate) mod elevation_profile;
+pub(crate) mod export_job;
 pub(crate) mod fetch;
 pub(crate) mod geo_trig;
+pub(crate) mod geotiff_export;
 pub(crate) mod http_api;
 pub(crate) mod http_pages;
+pub(crate) mod mbtiles_export;
+pub(crate) mod offline_geocoder;
+pub(crate) mod pmtiles_export;
 pub(crate) mod proxy_manager;
 pub(crate) mod rocket_anyhow;
 pub(crate) mod stat_counter;
+pub(crate) mod static_map;
+pub(crate) mod tile_freshness;
+pub(crate) mod tile_history;
+pub(crate) mod tile_placeholder;
+pub(crate) mod tile_provenance;
+pub(crate) mod tile_store;
+pub(crate) mod viewshed;
 
 #[macro_use]
 extern crate rocket;
@@ -46,6 +65,11 @@ use config::init_database;
 #[rocket::main]
 async fn main() -> rocket_anyhow::Result<()> {
     init_database().await?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if cli::run_cli(&args).await? {
+        return Ok(());
+    }
+    export_job::fail_interrupted_jobs()?;
     // overt_geo_duck::init_geoduck()?;
     // check we can run the manager once
     // let _fetch_manager = tokio::spawn(fetch::fetch_loop());
@@ -61,6 +85,7 @@ async fn main() -> rocket_anyhow::Result<()> {
         .mount("/", http_api::get_api_routes())
         .mount("/", http_pages::get_page_routes())
         .attach(Template::fairing())
+        .attach(http_api::Cors)
         .launch()
         .await?;
 
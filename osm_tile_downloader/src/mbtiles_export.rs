@@ -0,0 +1,139 @@
+use anyhow::Context;
+use anyhow::Result;
+use rand::Rng;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::config::{get_current_timestamp, LINKS_CONFIG};
+use crate::download_tile;
+use crate::export_job::{ExportRegion, TileExportRequest, TileExportSummary};
+use crate::tile_store;
+
+pub fn get_mbtiles_path(server_name: &str, min_z: u8, max_z: u8) -> PathBuf {
+    LINKS_CONFIG
+        .tile_location
+        .join("export")
+        .join("mbtiles")
+        .join(format!(
+            "{}_z{}-{}_{}.mbtiles",
+            server_name,
+            min_z,
+            max_z,
+            get_current_timestamp() as u64
+        ))
+}
+
+/// Write all cached tiles of one server in the region into a new MBTiles
+/// 1.3 file. Blocking; run from `spawn_blocking`.
+fn write_mbtiles(
+    server_name: &str,
+    region: &ExportRegion,
+    min_z: u8,
+    max_z: u8,
+) -> Result<TileExportSummary> {
+    let server_config = config::get_tile_server(server_name)?;
+    let bounds = region.bounds()?;
+    let final_path = get_mbtiles_path(server_name, min_z, max_z);
+    let temp_path = config::tmpdir()
+        .join(format!("{}.mbtiles", rand::thread_rng().gen::<u128>()));
+    let mut conn = rusqlite::Connection::open(&temp_path)?;
+    conn.execute_batch(
+        "CREATE TABLE metadata (name TEXT, value TEXT);
+        CREATE UNIQUE INDEX name ON metadata (name);
+        CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER,
+            tile_row INTEGER, tile_data BLOB);
+        CREATE UNIQUE INDEX tile_index ON tiles
+            (zoom_level, tile_column, tile_row);",
+    )?;
+
+    let tx = conn.transaction()?;
+    let mut tile_count = 0;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+            VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let store = tile_store::get_tile_store(server_name)?;
+        for z in min_z..=max_z.min(server_config.max_level) {
+            let range = region.tile_range(z)?;
+            for tile in
+                download_tile::list_cached_imagery(server_name, z, range)?
+            {
+                if !region.contains_tile(tile.x, tile.y, z) {
+                    continue;
+                }
+                let tile_data =
+                    store.read(&tile)?.context("tile missing from store")?;
+                // TMS rows count from the south
+                let tile_row = (1u64 << z) - 1 - tile.y;
+                insert.execute(rusqlite::params![
+                    z,
+                    tile.x as i64,
+                    tile_row as i64,
+                    tile_data
+                ])?;
+                tile_count += 1;
+            }
+        }
+        let attribution = server_config
+            .attribution
+            .clone()
+            .unwrap_or(server_config.comment.clone());
+        let metadata = [
+            ("name", server_config.name.clone()),
+            ("format", server_config.img_type.clone()),
+            (
+                "bounds",
+                format!(
+                    "{},{},{},{}",
+                    bounds.x_min, bounds.y_min, bounds.x_max, bounds.y_max
+                ),
+            ),
+            ("minzoom", min_z.to_string()),
+            ("maxzoom", max_z.min(server_config.max_level).to_string()),
+            ("attribution", attribution),
+            ("description", server_config.comment.clone()),
+            ("type", "baselayer".to_owned()),
+            ("version", "1.3".to_owned()),
+        ];
+        let mut insert =
+            tx.prepare("INSERT INTO metadata (name, value) VALUES (?1, ?2)")?;
+        for (name, value) in metadata {
+            insert.execute(rusqlite::params![name, value])?;
+        }
+    }
+    tx.commit()?;
+    drop(conn);
+
+    std::fs::create_dir_all(final_path.parent().expect("no parent"))?;
+    std::fs::rename(&temp_path, &final_path)?;
+    eprintln!(
+        "mbtiles export {}: {} tiles to {:?}",
+        server_name, tile_count, &final_path
+    );
+    Ok(TileExportSummary {
+        server_name: server_name.to_owned(),
+        path: final_path,
+        tile_count,
+    })
+}
+
+/// One MBTiles file per server, since a file holds a single tileset.
+pub async fn export_mbtiles(
+    request: TileExportRequest,
+) -> Result<Vec<TileExportSummary>> {
+    request.check()?;
+    let mut results = vec![];
+    for server_name in request.servers.iter() {
+        let server_name = server_name.clone();
+        let region = request.region.clone();
+        let (min_z, max_z) = (request.min_z, request.max_z);
+        results.push(
+            tokio::task::spawn_blocking(move || {
+                write_mbtiles(&server_name, &region, min_z, max_z)
+            })
+            .await??,
+        );
+    }
+    Ok(results)
+}
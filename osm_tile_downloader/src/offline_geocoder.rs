@@ -0,0 +1,381 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::LINKS_CONFIG;
+use crate::download_geoduck::GEODUCK_ZOOM_LEVEL;
+use crate::download_geosearch::OSMGeolocationSearchResult;
+use crate::geo_trig::{haversine_meters, GeoBBOX, GeoPoint};
+
+/// Overture (theme, type, column describing the feature) indexed for
+/// search.
+const OFFLINE_GEOCODER_TYPES: [(&str, &str, &str); 2] = [
+    ("places", "place", "categories.primary"),
+    ("divisions", "division", "subtype"),
+];
+/// Names exported next to each cached parquet segment.
+const NAMES_FILE_NAME: &str = "search_names.json";
+/// Rebuilt after this long, to pick up newly cached segments.
+const OFFLINE_INDEX_MAX_AGE: Duration = Duration::from_secs(600);
+pub const OFFLINE_SEARCH_MAX_RESULTS: usize = 20;
+
+lazy_static::lazy_static! {
+    /// Held across a rebuild, so concurrent searches wait for one build
+    /// instead of each starting their own.
+    static ref OFFLINE_INDEX:
+        tokio::sync::Mutex<Option<(Instant, Arc<OfflineIndex>)>>
+        = tokio::sync::Mutex::new(None);
+}
+
+/// One row of the exported names.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+struct OfflinePlace {
+    id: String,
+    name: String,
+    kind: Option<String>,
+    xmin: f64,
+    ymin: f64,
+    xmax: f64,
+    ymax: f64,
+}
+
+/// Named places and divisions with a token to place lookup. Tokens are
+/// sorted, so prefixes are a range scan.
+#[derive(Debug, Default)]
+pub struct OfflineIndex {
+    places: Vec<OfflinePlace>,
+    tokens: BTreeMap<String, Vec<usize>>,
+    /// Tokens by first letter and length, so typo matching only compares
+    /// words of about the same length.
+    fuzzy_buckets: HashMap<(char, usize), Vec<String>>,
+}
+
+/// Lowercase alphanumeric words.
+fn tokenize(s: &str) -> Vec<String> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// Levenshtein distance, giving up once it is above `max`.
+fn edit_distance_within(a: &str, b: &str, max: usize) -> Option<usize> {
+    let (a, b): (Vec<char>, Vec<char>) =
+        (a.chars().collect(), b.chars().collect());
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + (ca != cb) as usize;
+            current[j + 1] =
+                substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        if current.iter().min().copied().unwrap_or(0) > max {
+            return None;
+        }
+        previous = current;
+    }
+    (previous[b.len()] <= max).then_some(previous[b.len()])
+}
+
+/// Exported names of a cached segment, exporting them first if missing or
+/// older than the segment. Blocking.
+fn get_segment_names(parquet: &Path, kind_column: &str) -> Result<PathBuf> {
+    let names = parquet.with_file_name(NAMES_FILE_NAME);
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified());
+    let fresh = match (modified(&names), modified(parquet)) {
+        (Ok(names_time), Ok(parquet_time)) => names_time >= parquet_time,
+        _ => false,
+    };
+    if !fresh {
+        overt_geoduck::export_names_json(parquet, kind_column, &names)?;
+    }
+    Ok(names)
+}
+
+/// Cached top level segments of one Overture type.
+fn get_cached_segments(theme: &str, _type: &str) -> Result<Vec<PathBuf>> {
+    let z_dir = LINKS_CONFIG
+        .tile_location
+        .join("geoduck")
+        .join(theme)
+        .join(_type)
+        .join(GEODUCK_ZOOM_LEVEL.to_string());
+    if !z_dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut segments = vec![];
+    for x_entry in std::fs::read_dir(z_dir)? {
+        let x_entry = x_entry?;
+        if !x_entry.path().is_dir() {
+            continue;
+        }
+        for y_entry in std::fs::read_dir(x_entry.path())? {
+            let parquet = y_entry?.path().join("data.geo.parquet");
+            if parquet.is_file() {
+                segments.push(parquet);
+            }
+        }
+    }
+    Ok(segments)
+}
+
+impl OfflineIndex {
+    /// Index every cached places and divisions segment; segments that fail
+    /// to export and lines that fail to parse are skipped. Blocking, runs
+    /// duckdb for new segments.
+    pub fn build() -> Result<Self> {
+        let mut index = Self::default();
+        let mut seen_ids: HashSet<String> = HashSet::new();
+        for (theme, _type, kind_column) in OFFLINE_GEOCODER_TYPES {
+            for parquet in get_cached_segments(theme, _type)? {
+                let names = match get_segment_names(&parquet, kind_column) {
+                    Ok(names) => names,
+                    Err(e) => {
+                        eprintln!(
+                            "offline geocoder: skip {:?}: {}",
+                            parquet, e
+                        );
+                        continue;
+                    }
+                };
+                let file =
+                    std::io::BufReader::new(std::fs::File::open(&names)?);
+                for line in file.lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let parsed: serde_json::Result<OfflinePlace> =
+                        serde_json::from_str(&line);
+                    let place = match parsed {
+                        Ok(place) => place,
+                        Err(e) => {
+                            eprintln!(
+                                "offline geocoder: bad line in {:?}: {}",
+                                names, e
+                            );
+                            continue;
+                        }
+                    };
+                    if !seen_ids.insert(place.id.clone()) {
+                        continue;
+                    }
+                    let k = index.places.len();
+                    let mut tokens = tokenize(&place.name);
+                    tokens.sort();
+                    tokens.dedup();
+                    for token in tokens {
+                        index.tokens.entry(token).or_default().push(k);
+                    }
+                    index.places.push(place);
+                }
+            }
+        }
+        for token in index.tokens.keys() {
+            let first = token.chars().next().unwrap_or_default();
+            index
+                .fuzzy_buckets
+                .entry((first, token.chars().count()))
+                .or_default()
+                .push(token.clone());
+        }
+        eprintln!(
+            "offline geocoder: indexed {} names, {} tokens",
+            index.places.len(),
+            index.tokens.len()
+        );
+        Ok(index)
+    }
+
+    /// Score per place for one query token: 1 for an exact word, less for
+    /// a prefix, least for a typo (1 edit, 2 for long words). Typos in the
+    /// first letter are not matched.
+    fn match_token(&self, token: &str) -> HashMap<usize, f64> {
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+        let add =
+            |scores: &mut HashMap<usize, f64>, places: &[usize], score| {
+                for k in places {
+                    let entry = scores.entry(*k).or_insert(0.0);
+                    *entry = f64::max(*entry, score);
+                }
+            };
+        for (word, places) in self.tokens.range(token.to_owned()..) {
+            if !word.starts_with(token) {
+                break;
+            }
+            add(&mut scores, places, if word == token { 1.0 } else { 0.7 });
+        }
+        let length = token.chars().count();
+        let max_edits = match length {
+            0..=3 => 0,
+            4..=7 => 1,
+            _ => 2,
+        };
+        if scores.is_empty() && max_edits > 0 {
+            let first = token.chars().next().unwrap_or_default();
+            for bucket_length in length - max_edits..=length + max_edits {
+                let words =
+                    match self.fuzzy_buckets.get(&(first, bucket_length)) {
+                        Some(words) => words,
+                        None => continue,
+                    };
+                for word in words {
+                    if edit_distance_within(word, token, max_edits).is_some() {
+                        add(&mut scores, &self.tokens[word], 0.5);
+                    }
+                }
+            }
+        }
+        scores
+    }
+
+    /// Places whose names match every query word, best score first; ties
+    /// go to the place closest to the viewport center, and places inside
+    /// the viewport rank above the rest.
+    pub fn search(
+        &self,
+        query: &str,
+        viewport: Option<&GeoBBOX>,
+        limit: usize,
+    ) -> Vec<OSMGeolocationSearchResult> {
+        let query_tokens = tokenize(query);
+        let Some((first, rest)) = query_tokens.split_first() else {
+            return vec![];
+        };
+        let mut scores = self.match_token(first);
+        for token in rest {
+            let token_scores = self.match_token(token);
+            scores.retain(|k, score| match token_scores.get(k) {
+                Some(s) => {
+                    *score += s;
+                    true
+                }
+                None => false,
+            });
+        }
+
+        let center = viewport.map(|v| GeoPoint {
+            x_lon: (v.x_min + v.x_max) / 2.0,
+            y_lat: (v.y_min + v.y_max) / 2.0,
+        });
+        let mut ranked: Vec<(f64, f64, usize)> = scores
+            .into_iter()
+            .map(|(k, mut score)| {
+                let place = &self.places[k];
+                let point = GeoPoint {
+                    x_lon: (place.xmin + place.xmax) / 2.0,
+                    y_lat: (place.ymin + place.ymax) / 2.0,
+                };
+                // prefer names without extra words
+                score -= 0.01 * tokenize(&place.name).len() as f64;
+                let distance = match (viewport, &center) {
+                    (Some(v), Some(c)) => {
+                        let inside = (v.x_min..=v.x_max).contains(&point.x_lon)
+                            && (v.y_min..=v.y_max).contains(&point.y_lat);
+                        if inside {
+                            score += 1.0;
+                        }
+                        haversine_meters(c, &point)
+                    }
+                    _ => 0.0,
+                };
+                (score, distance, k)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.total_cmp(&b.1)));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(_, _, k)| {
+                let place = &self.places[k];
+                OSMGeolocationSearchResult {
+                    display_name: match &place.kind {
+                        Some(kind) => format!("{}, {}", place.name, kind),
+                        None => place.name.clone(),
+                    },
+                    geo_point: GeoPoint {
+                        x_lon: (place.xmin + place.xmax) / 2.0,
+                        y_lat: (place.ymin + place.ymax) / 2.0,
+                    },
+                    bbox: GeoBBOX {
+                        x_min: place.xmin,
+                        y_min: place.ymin,
+                        x_max: place.xmax,
+                        y_max: place.ymax,
+                    },
+                    place_id: None,
+                    osm_type: None,
+                    osm_id: None,
+                    class: None,
+                    place_type: place.kind.clone(),
+                    importance: None,
+                    geometry: None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The shared index, rebuilt when older than [`OFFLINE_INDEX_MAX_AGE`].
+pub async fn get_offline_index() -> Result<Arc<OfflineIndex>> {
+    let mut cached = OFFLINE_INDEX.lock().await;
+    if let Some((built, index)) = cached.as_ref() {
+        if built.elapsed() < OFFLINE_INDEX_MAX_AGE {
+            return Ok(index.clone());
+        }
+    }
+    let index =
+        Arc::new(tokio::task::spawn_blocking(OfflineIndex::build).await??);
+    *cached = Some((Instant::now(), index.clone()));
+    Ok(index)
+}
+
+/// Search the cached Overture places and divisions, without network.
+pub async fn search_offline(
+    query: &str,
+    viewport: Option<&GeoBBOX>,
+) -> Result<Vec<OSMGeolocationSearchResult>> {
+    let index = get_offline_index().await?;
+    let query = query.to_owned();
+    let viewport = viewport.copied();
+    Ok(tokio::task::spawn_blocking(move || {
+        index.search(&query, viewport.as_ref(), OFFLINE_SEARCH_MAX_RESULTS)
+    })
+    .await?)
+}
+
+/// Results as the GeoJSON the online geocoder returns: Point features
+/// with a bbox and a `display_name`.
+pub fn results_geojson(
+    results: &[OSMGeolocationSearchResult],
+) -> serde_json::Value {
+    let features: Vec<serde_json::Value> = results
+        .iter()
+        .map(|r| {
+            serde_json::json!({
+                "type": "Feature",
+                "bbox": [r.bbox.x_min, r.bbox.y_min, r.bbox.x_max, r.bbox.y_max],
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [r.geo_point.x_lon, r.geo_point.y_lat],
+                },
+                "properties": {
+                    "display_name": r.display_name,
+                    "source": "overture_offline",
+                },
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
@@ -0,0 +1,298 @@
+use anyhow::Context;
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::config;
+use crate::config::get_current_timestamp;
+use crate::download_tile;
+use crate::export_job::{ExportRegion, TileExportRequest, TileExportSummary};
+use crate::geo_trig::pmtiles_tile_id;
+use crate::tile_store;
+
+const PMTILES_HEADER_SIZE: usize = 127;
+/// Header and root directory must fit in the first 16 KiB, so clients can
+/// start with one request.
+const PMTILES_ROOT_MAX_SIZE: usize = 16384 - PMTILES_HEADER_SIZE;
+const PMTILES_COMPRESSION_NONE: u8 = 1;
+const PMTILES_COMPRESSION_GZIP: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PmtilesEntry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+fn pmtiles_tile_type(img_type: &str) -> u8 {
+    match img_type {
+        "png" => 2,
+        "jpg" => 3,
+        "webp" => 4,
+        _ => 0,
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn gzip(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+/// Column-wise directory encoding from the v3 spec, gzipped.
+fn encode_directory(entries: &[PmtilesEntry]) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    write_varint(&mut buf, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buf, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.run_length.into());
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.length.into());
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        // 0 means "right after the previous entry"
+        if i > 0
+            && entry.offset
+                == entries[i - 1].offset + entries[i - 1].length as u64
+        {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, entry.offset + 1);
+        }
+    }
+    gzip(&buf)
+}
+
+/// Root directory and leaf directories section. Leaves are only used when
+/// the root would not fit, growing leaf size until it does.
+fn build_directories(entries: &[PmtilesEntry]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let root = encode_directory(entries)?;
+    if root.len() <= PMTILES_ROOT_MAX_SIZE {
+        return Ok((root, vec![]));
+    }
+    let mut leaf_size = 4096.0f64;
+    loop {
+        let mut root_entries = vec![];
+        let mut leaves = vec![];
+        for chunk in entries.chunks(leaf_size as usize) {
+            let leaf = encode_directory(chunk)?;
+            root_entries.push(PmtilesEntry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend(leaf);
+        }
+        let root = encode_directory(&root_entries)?;
+        if root.len() <= PMTILES_ROOT_MAX_SIZE {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 1.2;
+    }
+}
+
+/// Write all cached tiles of one server in the region into a new PMTiles
+/// v3 archive, tiles clustered in tile id order, identical tiles stored
+/// once. Blocking; run from `spawn_blocking`.
+fn write_pmtiles(
+    server_name: &str,
+    region: &ExportRegion,
+    min_z: u8,
+    max_z: u8,
+) -> Result<TileExportSummary> {
+    let server_config = config::get_tile_server(server_name)?;
+    let bounds = region.bounds()?;
+    let max_z = max_z.min(server_config.max_level);
+
+    let mut tiles = vec![];
+    for z in min_z..=max_z {
+        for tile in download_tile::list_cached_imagery(
+            server_name,
+            z,
+            region.tile_range(z)?,
+        )? {
+            if region.contains_tile(tile.x, tile.y, z) {
+                tiles.push((pmtiles_tile_id(z, tile.x, tile.y), tile));
+            }
+        }
+    }
+    tiles.sort_by_key(|(tile_id, _)| *tile_id);
+
+    // tile data goes last in the archive, but its size is only known once
+    // written, so buffer it in a separate file first
+    let data_path = config::tmpdir()
+        .join(format!("{}.pmtiles_data", rand::thread_rng().gen::<u128>()));
+    let mut data_file = BufWriter::new(std::fs::File::create(&data_path)?);
+    let mut data_len = 0u64;
+    let mut contents: HashMap<[u8; 32], (u64, u32)> = HashMap::new();
+    let mut entries: Vec<PmtilesEntry> = vec![];
+    let store = tile_store::get_tile_store(server_name)?;
+    for (tile_id, tile) in tiles.iter() {
+        let bytes = store.read(tile)?.context("tile missing from store")?;
+        let hash: [u8; 32] = Sha256::digest(&bytes).into();
+        let (offset, length) = match contents.get(&hash) {
+            Some(existing) => *existing,
+            None => {
+                data_file.write_all(&bytes)?;
+                let content = (data_len, bytes.len() as u32);
+                data_len += bytes.len() as u64;
+                contents.insert(hash, content);
+                content
+            }
+        };
+        match entries.last_mut() {
+            Some(last)
+                if last.offset == offset
+                    && last.tile_id + last.run_length as u64 == *tile_id =>
+            {
+                last.run_length += 1;
+            }
+            _ => entries.push(PmtilesEntry {
+                tile_id: *tile_id,
+                offset,
+                length,
+                run_length: 1,
+            }),
+        }
+    }
+    data_file.flush()?;
+    drop(data_file);
+
+    let (root, leaves) = build_directories(&entries)?;
+    let metadata = serde_json::json!({
+        "name": server_config.name,
+        "description": server_config.comment,
+        "attribution": server_config
+            .attribution
+            .clone()
+            .unwrap_or(server_config.comment.clone()),
+        "type": "baselayer",
+        "format": server_config.img_type,
+    });
+    let metadata = gzip(metadata.to_string().as_bytes())?;
+
+    let root_offset = PMTILES_HEADER_SIZE as u64;
+    let metadata_offset = root_offset + root.len() as u64;
+    let leaves_offset = metadata_offset + metadata.len() as u64;
+    let data_offset = leaves_offset + leaves.len() as u64;
+    let e7 = |deg: f64| ((deg * 1e7) as i32).to_le_bytes();
+    let mut header = b"PMTiles".to_vec();
+    header.push(3);
+    for value in [
+        root_offset,
+        root.len() as u64,
+        metadata_offset,
+        metadata.len() as u64,
+        leaves_offset,
+        leaves.len() as u64,
+        data_offset,
+        data_len,
+        entries.iter().map(|e| e.run_length as u64).sum(),
+        entries.len() as u64,
+        contents.len() as u64,
+    ] {
+        header.extend(value.to_le_bytes());
+    }
+    header.extend([
+        // clustered
+        1,
+        PMTILES_COMPRESSION_GZIP,
+        PMTILES_COMPRESSION_NONE,
+        pmtiles_tile_type(&server_config.img_type),
+        min_z,
+        max_z,
+    ]);
+    header.extend(e7(bounds.x_min));
+    header.extend(e7(bounds.y_min));
+    header.extend(e7(bounds.x_max));
+    header.extend(e7(bounds.y_max));
+    header.push(min_z);
+    header.extend(e7((bounds.x_min + bounds.x_max) / 2.0));
+    header.extend(e7((bounds.y_min + bounds.y_max) / 2.0));
+    assert_eq!(header.len(), PMTILES_HEADER_SIZE);
+
+    let final_path = config::pmtiles_dir().join(format!(
+        "{}_z{}-{}_{}.pmtiles",
+        server_name,
+        min_z,
+        max_z,
+        get_current_timestamp() as u64
+    ));
+    let temp_path = config::tmpdir()
+        .join(format!("{}.pmtiles", rand::thread_rng().gen::<u128>()));
+    let mut archive = BufWriter::new(std::fs::File::create(&temp_path)?);
+    for section in [&header, &root, &metadata, &leaves] {
+        archive.write_all(section)?;
+    }
+    std::io::copy(&mut std::fs::File::open(&data_path)?, &mut archive)?;
+    archive.flush()?;
+    drop(archive);
+    std::fs::remove_file(&data_path)?;
+
+    std::fs::create_dir_all(final_path.parent().expect("no parent"))?;
+    std::fs::rename(&temp_path, &final_path)?;
+    eprintln!(
+        "pmtiles export {}: {} tiles, {} unique, to {:?}",
+        server_name,
+        tiles.len(),
+        contents.len(),
+        &final_path
+    );
+    Ok(TileExportSummary {
+        server_name: server_name.to_owned(),
+        path: final_path,
+        tile_count: tiles.len() as u64,
+    })
+}
+
+/// One PMTiles archive per server.
+pub async fn export_pmtiles(
+    request: TileExportRequest,
+) -> Result<Vec<TileExportSummary>> {
+    request.check()?;
+    let mut results = vec![];
+    for server_name in request.servers.iter() {
+        let server_name = server_name.clone();
+        let region = request.region.clone();
+        let (min_z, max_z) = (request.min_z, request.max_z);
+        results.push(
+            tokio::task::spawn_blocking(move || {
+                write_pmtiles(&server_name, &region, min_z, max_z)
+            })
+            .await??,
+        );
+    }
+    Ok(results)
+}
+
+/// Path of a file in the PMTiles directory, for serving.
+pub fn get_pmtiles_file(file_name: &str) -> Result<PathBuf> {
+    if !file_name.ends_with(".pmtiles")
+        || file_name.contains("..")
+        || file_name.contains('/')
+        || file_name.contains('\\')
+    {
+        anyhow::bail!("bad pmtiles file name: {:?}", file_name);
+    }
+    Ok(config::pmtiles_dir().join(file_name))
+}
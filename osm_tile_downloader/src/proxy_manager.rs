@@ -13,6 +13,9 @@ use serde::{Deserialize, Serialize};
 lazy_static::lazy_static! {
     pub static ref DB_SCRAPER_LAST_REFRESH:  typed_sled::Tree::<String, f64> = typed_sled::Tree::<String, f64>::open(&SLED_DB, "socks5_scraper_last_refresh_v7_f64");
     pub static ref DB_SOCKS5_PROXY_ENTRY:  typed_sled::Tree::<String, Socks5ProxyEntry> = typed_sled::Tree::<String, Socks5ProxyEntry>::open(&SLED_DB, "socks5_proxy_entry_v5");
+    /// Failed trees already filled from their final tree, by table name.
+    static ref DB_FAILED_TREE_BACKFILLED:  typed_sled::Tree::<String, f64> = typed_sled::Tree::<String, f64>::open(&SLED_DB, "download_failed_tree_backfilled_v1");
+    static ref FAILED_TREE_BACKFILL_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
 }
 const SCRAPER_REFRESH_SECONDS: f64 = 1200.0;
 const ENTRY_DELETE_SECONDS: f64 = 7200.0;
@@ -496,7 +499,7 @@ pub trait DownloadId:
     fn get_version() -> usize;
     fn prereq_satisfied(&self) -> Result<()> {
         if let Some(parent) = self.parent() {
-            if std::fs::metadata(parent.get_final_path()?).is_err() {
+            if !parent.is_stored()? {
                 anyhow::bail!(
                     "prereq failed: {:?} has parent {:?} missing from {:?}",
                     self,
@@ -513,6 +516,30 @@ pub trait DownloadId:
     fn is_valid_request(&self) -> Result<()>;
     fn get_random_url(&self) -> Result<String>;
     fn get_final_path(&self) -> Result<PathBuf>;
+    /// Whether a finished download is stored. The storage hooks default to
+    /// one file at `get_final_path`; they block.
+    fn is_stored(&self) -> Result<bool> {
+        Ok(std::fs::metadata(self.get_final_path()?).is_ok())
+    }
+    /// File with the stored download, for re-validation. Stores without
+    /// plain files copy it out to `tmp_file`.
+    fn get_stored_file(&self, _tmp_file: &Path) -> Result<Option<PathBuf>> {
+        let path = self.get_final_path()?;
+        Ok(std::fs::metadata(&path).is_ok().then_some(path))
+    }
+    /// Move a validated download from `tmp_file` into storage.
+    fn store_download(&self, tmp_file: &Path) -> Result<()> {
+        let final_path = self.get_final_path()?;
+        let final_parent =
+            final_path.parent().context("final path has no parent")?;
+        std::fs::create_dir_all(final_parent)?;
+        std::fs::rename(tmp_file, &final_path)?;
+        Ok(())
+    }
+    fn remove_stored(&self) -> Result<()> {
+        std::fs::remove_file(self.get_final_path()?)?;
+        Ok(())
+    }
     fn parse_respose(&self, tmp_file: &Path) -> Result<Self::TParseResult>;
     fn download_into(
         &self,
@@ -555,11 +582,82 @@ fn get_db_final_tree<T: DownloadId>(
     typed_sled::Tree::<_, _>::open(&SLED_DB, table_name.as_str())
 }
 
+/// Last error of ids whose download failed and has no result; kept next
+/// to the final tree so failures are listed without scanning every id.
+/// Failures recorded before the tree existed are copied in on first open.
+fn get_db_failed_tree<T: DownloadId>(
+) -> anyhow::Result<typed_sled::Tree<T, String>> {
+    let table_name = get_table_name::<T>("failed");
+    let failed_tree =
+        typed_sled::Tree::<_, _>::open(&SLED_DB, table_name.as_str());
+    if DB_FAILED_TREE_BACKFILLED.contains_key(&table_name)? {
+        return Ok(failed_tree);
+    }
+    let _lock = FAILED_TREE_BACKFILL_LOCK
+        .lock()
+        .map_err(|_| anyhow::anyhow!("poisoned failed tree lock"))?;
+    if DB_FAILED_TREE_BACKFILLED.contains_key(&table_name)? {
+        return Ok(failed_tree);
+    }
+    let mut count = 0;
+    for k in get_db_final_tree::<T>().iter() {
+        let (download_id, entry) = k?;
+        if entry.parse_result.is_none() && entry.fail_count > 0 {
+            failed_tree.insert(&download_id, &entry.error_txt)?;
+            count += 1;
+        }
+    }
+    DB_FAILED_TREE_BACKFILLED.insert(&table_name, &get_current_timestamp())?;
+    eprintln!("backfilled {} failed downloads into {}", count, table_name);
+    Ok(failed_tree)
+}
+
+/// Write a final entry, keeping the failed tree in step.
+fn insert_final_entry<T: DownloadId>(
+    download_id: &T,
+    entry: &DownloadEntry<T::TParseResult>,
+) -> anyhow::Result<()> {
+    get_db_final_tree::<T>().insert(download_id, entry)?;
+    let failed_tree = get_db_failed_tree::<T>()?;
+    if entry.parse_result.is_none() && entry.fail_count > 0 {
+        failed_tree.insert(download_id, &entry.error_txt)?;
+    } else {
+        failed_tree.remove(download_id)?;
+    }
+    Ok(())
+}
+
 fn get_db_pending_tree<T: DownloadId>() -> typed_sled::Tree<T, bool> {
     let table_name = get_table_name::<T>("pending");
     typed_sled::Tree::<_, _>::open(&SLED_DB, table_name.as_str())
 }
 
+/// How a download was fetched. Kept next to the temp file handed to
+/// `download_into`, at [`get_fetch_record_path`], until `do_download` ends.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct FetchRecord {
+    pub url: String,
+    pub socks_addr: String,
+    /// Scraper name of the proxy, "tor" for tor.
+    pub socks_category: String,
+    pub response: fetch::CurlResponse,
+    pub fetched_at: f64,
+}
+
+pub fn get_fetch_record_path(path: &Path) -> PathBuf {
+    let mut record_path = path.as_os_str().to_owned();
+    record_path.push(".fetch.json");
+    PathBuf::from(record_path)
+}
+
+pub async fn read_fetch_record(path: &Path) -> Result<Option<FetchRecord>> {
+    match tokio::fs::read(get_fetch_record_path(path)).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub async fn download_once_2<T: DownloadId>(
     download_id: T,
     path: PathBuf,
@@ -570,8 +668,13 @@ pub async fn download_once_2<T: DownloadId>(
     tokio::time::sleep(initial_delay).await;
     let url = download_id.get_random_url()?;
     let path2 = path.clone();
-    let res =
-        fetch::fetch_with_socks5_curl(url.as_str(), &path, &socks_addr).await;
+    let res = fetch::fetch_with_socks5_curl_headers(
+        url.as_str(),
+        &path,
+        &socks_addr,
+        &[],
+    )
+    .await;
     proxy_stat_increment(
         "download",
         url.as_str(),
@@ -579,7 +682,7 @@ pub async fn download_once_2<T: DownloadId>(
         socks_cat.as_str(),
         res.is_ok(),
     )?;
-    res.with_context(|| {
+    let response = res.with_context(|| {
         format!(
             "{}: download error, proxy {} ({}): ",
             type_name::<T>(),
@@ -587,6 +690,18 @@ pub async fn download_once_2<T: DownloadId>(
             socks_cat
         )
     })?;
+    let fetch_record = FetchRecord {
+        url: url.clone(),
+        socks_addr: socks_addr.clone(),
+        socks_category: socks_cat.clone(),
+        response,
+        fetched_at: get_current_timestamp(),
+    };
+    tokio::fs::write(
+        get_fetch_record_path(&path),
+        serde_json::to_vec(&fetch_record)?,
+    )
+    .await?;
 
     let res = spawn_blocking(move || download_id.parse_respose(&path)).await?;
     proxy_stat_increment(
@@ -609,7 +724,7 @@ pub async fn download_once_2<T: DownloadId>(
     ))
 }
 
-async fn download_in_parallel<T: DownloadId + 'static>(
+pub async fn download_in_parallel<T: DownloadId + 'static>(
     download_id: &T,
     target_temp: &Path,
 ) -> anyhow::Result<T::TParseResult> {
@@ -663,10 +778,17 @@ async fn download_in_parallel<T: DownloadId + 'static>(
         tokio::fs::rename(&good_path, target_temp)
             .await
             .context("cannot rename to final temp")?;
+        // keep the fetch record next to the final temp, for the caller
+        let _ = tokio::fs::rename(
+            get_fetch_record_path(&good_path),
+            get_fetch_record_path(target_temp),
+        )
+        .await;
 
         // delete all temps
         for t in all_temps.iter() {
             let _ = tokio::fs::remove_file(&t).await;
+            let _ = tokio::fs::remove_file(get_fetch_record_path(t)).await;
         }
 
         return Ok(check_result);
@@ -675,6 +797,7 @@ async fn download_in_parallel<T: DownloadId + 'static>(
     // delete all temps
     for t in all_temps.iter() {
         let _ = tokio::fs::remove_file(&t).await;
+        let _ = tokio::fs::remove_file(get_fetch_record_path(t)).await;
     }
 
     anyhow::bail!("err: cannot download. see below: \n {:#?}", _errors);
@@ -837,11 +960,11 @@ pub async fn download2_queue_item<T: DownloadId + 'static>(
     download_id: &T,
 ) -> anyhow::Result<T::TParseResult> {
     let final_tree = get_db_final_tree::<T>();
-    // if db entry exists, just return that, be it error or success. if success; check if path exists
+    // if db entry exists, just return that, be it error or success. if success; check if it is stored
     if let Some(existing_entry) = final_tree.get(download_id)? {
         if let Some(existing_result) = existing_entry.parse_result {
-            let path = download_id.get_final_path()?;
-            if tokio::fs::metadata(&path).await.is_ok() {
+            let download_id2 = download_id.clone();
+            if spawn_blocking(move || download_id2.is_stored()).await?? {
                 return Ok(existing_result);
             }
         } else {
@@ -852,35 +975,40 @@ pub async fn download2_queue_item<T: DownloadId + 'static>(
             )
         }
     }
-    // if path exists, check it, if failed delete it.
-    // path.exists() is sync, so do stat instead
+    // if stored, check it, if failed delete it.
     {
-        let path = download_id.get_final_path()?;
-        if tokio::fs::metadata(&path).await.is_ok() {
-            let path = path.clone();
-            let path2 = path.clone();
-            let download_id2 = download_id.clone();
-            {
-                if let Ok(result) =
-                    spawn_blocking(move || download_id2.parse_respose(&path))
-                        .await?
-                {
-                    // write result to db
-                    let db_value = DownloadEntry::<T::TParseResult> {
-                        parse_result: Some(result),
-                        error_txt: "".to_string(),
-                        fail_count: 0,
-                    };
-                    final_tree.insert(download_id, &db_value)?;
-                    return Ok(db_value.parse_result.unwrap());
-                } else {
-                    eprintln!(
-                        "DELETING existing file that failed verification: {:?}",
-                        path2.to_str()
-                    );
-                    tokio::fs::remove_file(path2).await?;
-                }
+        let rand_name =
+            format!("{}.download_check", rand::thread_rng().gen::<u128>());
+        let temp_copy = tmpdir().join(PathBuf::from(rand_name));
+        let download_id2 = download_id.clone();
+        let checked = spawn_blocking(move || {
+            let path = match download_id2.get_stored_file(&temp_copy)? {
+                Some(path) => path,
+                None => return anyhow::Ok(None),
+            };
+            let result = download_id2.parse_respose(&path);
+            if path == temp_copy {
+                let _ = std::fs::remove_file(&temp_copy);
+            }
+            if result.is_err() {
+                eprintln!(
+                    "DELETING existing download that failed verification: {:?}",
+                    &download_id2
+                );
+                download_id2.remove_stored()?;
             }
+            Ok(result.ok())
+        })
+        .await??;
+        if let Some(result) = checked {
+            // write result to db
+            let db_value = DownloadEntry::<T::TParseResult> {
+                parse_result: Some(result),
+                error_txt: "".to_string(),
+                fail_count: 0,
+            };
+            insert_final_entry(download_id, &db_value)?;
+            return Ok(db_value.parse_result.unwrap());
         }
     }
 
@@ -897,7 +1025,7 @@ pub async fn download2_queue_item<T: DownloadId + 'static>(
         } else {
             ("".to_string(), 0)
         };
-    final_tree.insert(
+    insert_final_entry(
         download_id,
         &DownloadEntry::<T::TParseResult> {
             parse_result: None,
@@ -913,6 +1041,93 @@ pub async fn download2_queue_item<T: DownloadId + 'static>(
     anyhow::bail!("just added to pending, plz wait. {}", old_err);
 }
 
+/// The last recorded parse result for this id, without queueing anything.
+pub fn get_download_result<T: DownloadId + 'static>(
+    download_id: &T,
+) -> anyhow::Result<Option<T::TParseResult>> {
+    Ok(get_db_final_tree::<T>()
+        .get(download_id)?
+        .and_then(|entry| entry.parse_result))
+}
+
+/// Ids whose downloads failed at least once and have no result, with the
+/// last error. Reads only the failed tree, not every download.
+pub fn get_failed_downloads<T: DownloadId + 'static>(
+) -> anyhow::Result<Vec<(T, String)>> {
+    let mut failed = vec![];
+    for k in get_db_failed_tree::<T>()?.iter() {
+        failed.push(k?);
+    }
+    Ok(failed)
+}
+
+/// Whether the id is queued for, or being fetched by, the download loop.
+pub fn is_download_pending<T: DownloadId + 'static>(
+    download_id: &T,
+) -> anyhow::Result<bool> {
+    Ok(get_db_pending_tree::<T>().contains_key(download_id)?)
+}
+
+/// Downloads a request needs are queued but not done yet. HTTP routes
+/// answer 503 with `Retry-After` instead of waiting for them.
+#[derive(Debug)]
+pub struct DownloadsPending {
+    pub what: String,
+    pub count: usize,
+    pub retry_after_secs: u64,
+}
+
+impl std::fmt::Display for DownloadsPending {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} downloads pending, retry in {}s",
+            self.count, self.what, self.retry_after_secs
+        )
+    }
+}
+
+impl std::error::Error for DownloadsPending {}
+
+/// Every id with a recorded parse result.
+pub fn get_download_results<T: DownloadId + 'static>(
+) -> anyhow::Result<Vec<(T, T::TParseResult)>> {
+    let mut results = vec![];
+    for k in get_db_final_tree::<T>().iter() {
+        let (download_id, entry) = k?;
+        if let Some(parse_result) = entry.parse_result {
+            results.push((download_id, parse_result));
+        }
+    }
+    Ok(results)
+}
+
+/// Record a result for a download refreshed outside the download loop.
+pub fn set_download_result<T: DownloadId + 'static>(
+    download_id: &T,
+    parse_result: T::TParseResult,
+) -> anyhow::Result<()> {
+    insert_final_entry(
+        download_id,
+        &DownloadEntry::<T::TParseResult> {
+            parse_result: Some(parse_result),
+            error_txt: "".to_string(),
+            fail_count: 0,
+        },
+    )?;
+    Ok(())
+}
+
+/// Drop the cached result for this id, so the next request re-checks the
+/// final path on disk instead of returning a stale error.
+pub fn clear_download_entry<T: DownloadId + 'static>(
+    download_id: &T,
+) -> anyhow::Result<()> {
+    get_db_final_tree::<T>().remove(download_id)?;
+    get_db_failed_tree::<T>()?.remove(download_id)?;
+    Ok(())
+}
+
 async fn do_download<T: DownloadId + 'static>(
     download_id: T,
 ) -> anyhow::Result<T::TParseResult> {
@@ -929,12 +1144,11 @@ async fn do_download<T: DownloadId + 'static>(
         format!("{}.download_final", rand::thread_rng().gen::<u128>());
     let temp_empty = tmpdir().join(PathBuf::from(rand_name));
     let parsed = download_id.download_into(&temp_empty).await;
+    let _ = tokio::fs::remove_file(get_fetch_record_path(&temp_empty)).await;
     if parsed.is_ok() {
-        let final_path = download_id.get_final_path()?;
-        let final_parent =
-            final_path.parent().expect("final path has no parent");
-        tokio::fs::create_dir_all(&final_parent).await?;
-        tokio::fs::rename(&temp_empty, &final_path).await?;
+        let download_id2 = download_id.clone();
+        spawn_blocking(move || download_id2.store_download(&temp_empty))
+            .await??;
     }
 
     let db_entry = match parsed {
@@ -954,7 +1168,7 @@ async fn do_download<T: DownloadId + 'static>(
             fail_count: old_fail_cnt + 1,
         },
     };
-    final_tree.insert(download_id, &db_entry)?;
+    insert_final_entry(download_id, &db_entry)?;
 
     // delete from pending tree OR set as not running
     {
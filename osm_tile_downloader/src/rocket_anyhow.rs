@@ -36,6 +36,17 @@ impl<'r> Responder<'r, 'static> for Error {
         use rocket::http::ContentType;
         use rocket::response::Response;
         // response::Debug(self.0).respond_to(request)
+        if let Some(pending) =
+            self.0.downcast_ref::<crate::proxy_manager::DownloadsPending>()
+        {
+            let err_str = format!("http 503 \n\n{}", pending);
+            return Response::build()
+                .header(ContentType::Plain)
+                .raw_header("Retry-After", pending.retry_after_secs.to_string())
+                .sized_body(err_str.len(), Cursor::new(err_str))
+                .status(rocket::http::Status::ServiceUnavailable)
+                .ok();
+        }
         let err_str = format!("http 500 \n\n{:?}", self.0);
         Response::build()
             .header(ContentType::Plain)
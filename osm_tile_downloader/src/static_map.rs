@@ -0,0 +1,243 @@
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::config;
+use crate::config::TileServerConfig;
+use crate::download_tile;
+use crate::download_tile::NoDataTile;
+use crate::geo_trig::tile_index_float;
+use crate::geo_trig::{GeoBBOX, GeoPoint};
+
+pub const STATIC_MAP_MAX_SIZE: u32 = 4096;
+/// Rounds of waiting for tiles that were just queued for download.
+const STATIC_MAP_FETCH_ROUNDS: u32 = 10;
+const STATIC_MAP_FETCH_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StaticMapView {
+    /// Fit the bbox into the image, at the highest zoom that fits
+    /// unless a zoom is given.
+    BBox(GeoBBOX, Option<u8>),
+    Center(GeoPoint, u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StaticMapOverlay {
+    pub point: Option<GeoPoint>,
+    pub bbox: Option<GeoBBOX>,
+}
+
+/// Pixel window of the stitched image, at one zoom level, in "global"
+/// pixels: tile index times tile size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StaticMapWindow {
+    pub z: u8,
+    pub left: f64,
+    pub top: f64,
+    pub width: u32,
+    pub height: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+}
+
+impl StaticMapWindow {
+    pub fn new(
+        server_config: &TileServerConfig,
+        view: StaticMapView,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        let (tile_w, tile_h) =
+            (server_config.width as f64, server_config.height as f64);
+        let (z, center) = match view {
+            StaticMapView::Center(point, z) => (z, point),
+            StaticMapView::BBox(bbox, z) => {
+                let (tx0, ty0) = tile_index_float(0, bbox.x_min, bbox.y_max);
+                let (tx1, ty1) = tile_index_float(0, bbox.x_max, bbox.y_min);
+                let z = z.unwrap_or_else(|| {
+                    let zx = (width as f64 / ((tx1 - tx0) * tile_w)).log2();
+                    let zy = (height as f64 / ((ty1 - ty0) * tile_h)).log2();
+                    zx.min(zy)
+                        .floor()
+                        .clamp(0.0, server_config.max_level as f64)
+                        as u8
+                });
+                // mercator midpoint, not the lat midpoint
+                let center = GeoPoint {
+                    x_lon: (bbox.x_min + bbox.x_max) / 2.0,
+                    y_lat: tile_y_to_lat((ty0 + ty1) / 2.0),
+                };
+                (z, center)
+            }
+        };
+        if z > server_config.max_level {
+            anyhow::bail!(
+                "got z = {} when max for server is {}",
+                z,
+                server_config.max_level
+            );
+        }
+        if width == 0
+            || height == 0
+            || width > STATIC_MAP_MAX_SIZE
+            || height > STATIC_MAP_MAX_SIZE
+        {
+            anyhow::bail!(
+                "static map size {}x{} not in 1..={}",
+                width,
+                height,
+                STATIC_MAP_MAX_SIZE
+            );
+        }
+        let (cx, cy) = tile_index_float(z, center.x_lon, center.y_lat);
+        Ok(Self {
+            z,
+            left: cx * tile_w - width as f64 / 2.0,
+            top: cy * tile_h - height as f64 / 2.0,
+            width,
+            height,
+            tile_width: server_config.width,
+            tile_height: server_config.height,
+        })
+    }
+
+    /// Tiles covering the window, as (x, y) with x wrapped around the
+    /// antimeridian, together with their pixel offset in the window.
+    pub fn tiles(&self) -> Vec<(u64, u64, i64, i64)> {
+        let extent = 2i64.pow(self.z.into());
+        let tx0 = (self.left / self.tile_width as f64).floor() as i64;
+        let ty0 = (self.top / self.tile_height as f64).floor() as i64;
+        let tx1 = ((self.left + self.width as f64) / self.tile_width as f64)
+            .ceil() as i64;
+        let ty1 = ((self.top + self.height as f64) / self.tile_height as f64)
+            .ceil() as i64;
+        let mut tiles = vec![];
+        for tx in tx0..tx1 {
+            for ty in ty0.max(0)..ty1.min(extent) {
+                let offset_x = (tx * self.tile_width as i64) as f64 - self.left;
+                let offset_y = (ty * self.tile_height as i64) as f64 - self.top;
+                tiles.push((
+                    tx.rem_euclid(extent) as u64,
+                    ty as u64,
+                    offset_x.round() as i64,
+                    offset_y.round() as i64,
+                ));
+            }
+        }
+        tiles
+    }
+
+    pub fn pixel_of(&self, lon: f64, lat: f64) -> (i32, i32) {
+        let (tx, ty) = tile_index_float(self.z, lon, lat);
+        (
+            (tx * self.tile_width as f64 - self.left) as i32,
+            (ty * self.tile_height as f64 - self.top) as i32,
+        )
+    }
+}
+
+fn tile_y_to_lat(tile_y_z0: f64) -> f64 {
+    use std::f64::consts::PI;
+    (PI - tile_y_z0 * 2.0 * PI).sinh().atan().to_degrees()
+}
+
+/// Fetch tiles through the download queue, in order. Tiles that are still
+/// pending get a few rounds to arrive; tiles that never do, and no-data
+/// tiles, come back as None.
+pub async fn fetch_tiles(
+    server_config: &TileServerConfig,
+    z: u8,
+    tiles: &[(u64, u64)],
+) -> Result<Vec<Option<Vec<u8>>>> {
+    let mut fetched = vec![None; tiles.len()];
+    let mut pending = (0..tiles.len()).collect::<Vec<_>>();
+    for round in 0..STATIC_MAP_FETCH_ROUNDS {
+        if round > 0 {
+            tokio::time::sleep(STATIC_MAP_FETCH_WAIT).await;
+        }
+        let results = futures::future::join_all(pending.iter().map(|i| {
+            download_tile::get_tile(
+                &server_config.name,
+                tiles[*i].0,
+                tiles[*i].1,
+                z,
+                &server_config.img_type,
+            )
+        }))
+        .await;
+        let mut still_pending = vec![];
+        for (i, result) in pending.into_iter().zip(results) {
+            match result {
+                Ok(tile) => fetched[i] = Some(tile.read().await?),
+                Err(err) if err.downcast_ref::<NoDataTile>().is_some() => {}
+                Err(_) => still_pending.push(i),
+            }
+        }
+        pending = still_pending;
+        if pending.is_empty() {
+            break;
+        }
+    }
+    if !pending.is_empty() {
+        eprintln!(
+            "fetch {} z={}: {} tiles missing",
+            server_config.name,
+            z,
+            pending.len()
+        );
+    }
+    Ok(fetched)
+}
+
+async fn fetch_window_tiles(
+    server_config: &TileServerConfig,
+    window: &StaticMapWindow,
+) -> Result<Vec<(Vec<u8>, i64, i64)>> {
+    let tiles = window.tiles();
+    let xy = tiles.iter().map(|t| (t.0, t.1)).collect::<Vec<_>>();
+    let fetched = fetch_tiles(server_config, window.z, &xy).await?;
+    Ok(tiles
+        .into_iter()
+        .zip(fetched)
+        .filter_map(|(tile, bytes)| Some((bytes?, tile.2, tile.3)))
+        .collect())
+}
+
+/// Stitch the tiles of one server into a single image of the given size.
+pub async fn render_static_map(
+    server_name: &str,
+    view: StaticMapView,
+    width: u32,
+    height: u32,
+    overlay: StaticMapOverlay,
+    img_type: &str,
+) -> Result<Vec<u8>> {
+    if !(img_type.eq("png") || img_type.eq("jpg")) {
+        anyhow::bail!("static map format must be png or jpg: {}", img_type);
+    }
+    let server_config = config::get_tile_server(server_name)?;
+    let window = StaticMapWindow::new(&server_config, view, width, height)?;
+    let tiles = fetch_window_tiles(&server_config, &window).await?;
+
+    let img_type = img_type.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut canvas = image::RgbImage::new(window.width, window.height);
+        for (bytes, offset_x, offset_y) in tiles {
+            let tile = download_tile::decode_tile_image(&bytes)?.into_rgb8();
+            image::imageops::replace(&mut canvas, &tile, offset_x, offset_y);
+        }
+        let point = overlay.point.map(|p| window.pixel_of(p.x_lon, p.y_lat));
+        let bbox = overlay.bbox.map(|b| {
+            [
+                window.pixel_of(b.x_min, b.y_min),
+                window.pixel_of(b.x_max, b.y_max),
+            ]
+        });
+        download_tile::draw_overlay_pixels(&mut canvas, point, bbox);
+        download_tile::encode_tile_image(
+            image::DynamicImage::ImageRgb8(canvas),
+            &img_type,
+        )
+    })
+    .await?
+}
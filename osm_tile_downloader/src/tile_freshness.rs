@@ -0,0 +1,233 @@
+use anyhow::Context;
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+
+use crate::config;
+use crate::config::{get_current_timestamp, LINKS_CONFIG, SLED_DB};
+use crate::download_tile::TileFetchId;
+use crate::fetch;
+use crate::fetch::CurlResponse;
+use crate::proxy_manager;
+use crate::proxy_manager::{DownloadId, FetchRecord};
+use crate::tile_history;
+use crate::tile_provenance;
+
+lazy_static::lazy_static! {
+    pub static ref DB_TILE_FRESHNESS:
+        typed_sled::Tree::<TileFetchId, TileFreshness>
+        = typed_sled::Tree::<TileFetchId, TileFreshness>::open(
+            &SLED_DB,
+            "tile_freshness_v1");
+
+    /// Tiles with a refresh running, by (server_name, z, x, y).
+    static ref REFRESHING: Mutex<HashSet<(String, u8, u64, u64)>>
+        = Mutex::new(HashSet::new());
+
+    static ref REFRESH_PERMITS: Arc<Semaphore>
+        = Arc::new(Semaphore::new(TILE_REFRESH_MAX_PARALLEL));
+}
+
+/// Background refreshes running at once; stale tiles requested while all
+/// are busy are refreshed on a later request.
+const TILE_REFRESH_MAX_PARALLEL: usize = 8;
+
+/// Response validators of the stored copy of a tile.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TileFreshness {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// When the stored content was downloaded.
+    pub fetched_at: f64,
+    /// When the server last confirmed it, by a download or a 304.
+    pub checked_at: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RefreshOutcome {
+    NotModified,
+    Updated,
+}
+
+pub fn get_tile_freshness(tile: &TileFetchId) -> Result<Option<TileFreshness>> {
+    Ok(DB_TILE_FRESHNESS.get(tile)?)
+}
+
+/// Save the validators of a fresh download of the tile.
+pub fn record_response(
+    tile: &TileFetchId,
+    response: &CurlResponse,
+) -> Result<()> {
+    let now = get_current_timestamp();
+    DB_TILE_FRESHNESS.insert(
+        tile,
+        &TileFreshness {
+            etag: response.header("etag").map(str::to_owned),
+            last_modified: response.header("last-modified").map(str::to_owned),
+            fetched_at: now,
+            checked_at: now,
+        },
+    )?;
+    Ok(())
+}
+
+/// Older than the server's `max_age`. Tiles cached before validators were
+/// recorded get a record now, so their `max_age` starts counting instead
+/// of the whole cache being refreshed at once.
+pub fn is_stale(tile: &TileFetchId) -> Result<bool> {
+    if config::is_pyramid_tile_server(&tile.server_name) {
+        return Ok(false);
+    }
+    let max_age = match config::get_tile_server(&tile.server_name)?.max_age {
+        Some(max_age) => max_age as f64,
+        None => return Ok(false),
+    };
+    Ok(match get_tile_freshness(tile)? {
+        Some(freshness) => {
+            freshness.checked_at + max_age < get_current_timestamp()
+        }
+        None => {
+            let now = get_current_timestamp();
+            DB_TILE_FRESHNESS.insert(
+                tile,
+                &TileFreshness {
+                    etag: None,
+                    last_modified: None,
+                    fetched_at: now,
+                    checked_at: now,
+                },
+            )?;
+            false
+        }
+    })
+}
+
+/// Start a background refresh of a stale tile; the stored copy keeps being
+/// served until it finishes. At most [`TILE_REFRESH_MAX_PARALLEL`] run.
+pub fn refresh_if_stale(tile: &TileFetchId) -> Result<()> {
+    if !is_stale(tile)? {
+        return Ok(());
+    }
+    let Ok(permit) = REFRESH_PERMITS.clone().try_acquire_owned() else {
+        return Ok(());
+    };
+    let key = (tile.server_name.clone(), tile.z, tile.x, tile.y);
+    if !REFRESHING
+        .lock()
+        .expect("poisoned refresh lock")
+        .insert(key.clone())
+    {
+        return Ok(());
+    }
+    let tile = tile.clone();
+    tokio::spawn(async move {
+        if let Err(err) = refresh_tile(&tile).await {
+            eprintln!("tile refresh failed for {:?}: {:?}", &tile, err);
+        }
+        REFRESHING
+            .lock()
+            .expect("poisoned refresh lock")
+            .remove(&key);
+        drop(permit);
+    });
+    Ok(())
+}
+
+/// Conditional re-download of a stored tile. A 304 only bumps
+/// `checked_at`; new content is validated and replaces the stored tile.
+pub async fn refresh_tile(tile: &TileFetchId) -> Result<RefreshOutcome> {
+    let url = tile.get_random_url()?;
+    let mut request_headers = vec![];
+    if let Some(freshness) = get_tile_freshness(tile)? {
+        if let Some(etag) = freshness.etag {
+            request_headers.push(format!("If-None-Match: {}", etag));
+        }
+        if let Some(last_modified) = freshness.last_modified {
+            request_headers
+                .push(format!("If-Modified-Since: {}", last_modified));
+        }
+    }
+    let mut all_socks: Vec<(String, String)> =
+        proxy_manager::get_random_proxies(&url, LINKS_CONFIG.retries)
+            .into_iter()
+            .map(|e| (e.addr, e.category))
+            .collect();
+    all_socks.push((
+        LINKS_CONFIG
+            .tor_addr_list
+            .choose(&mut rand::thread_rng())
+            .context("no socks proxy")?
+            .clone(),
+        "tor".to_owned(),
+    ));
+
+    let mut errors = vec![];
+    for socks in all_socks {
+        let tmp_file = config::tmpdir()
+            .join(format!("{}.tile_refresh", rand::thread_rng().gen::<u128>()));
+        let result =
+            refresh_once(tile, &url, &tmp_file, &socks, &request_headers).await;
+        let _ = tokio::fs::remove_file(&tmp_file).await;
+        match result {
+            Ok(outcome) => return Ok(outcome),
+            Err(err) => errors.push(err),
+        }
+    }
+    anyhow::bail!("cannot refresh tile. see below: \n {:#?}", errors);
+}
+
+async fn refresh_once(
+    tile: &TileFetchId,
+    url: &str,
+    tmp_file: &Path,
+    (socks_addr, socks_category): &(String, String),
+    request_headers: &[String],
+) -> Result<RefreshOutcome> {
+    let response = fetch::fetch_with_socks5_curl_headers(
+        url,
+        tmp_file,
+        socks_addr,
+        request_headers,
+    )
+    .await?;
+    if response.status == 304 {
+        let mut freshness =
+            get_tile_freshness(tile)?.context("304 without validators")?;
+        freshness.checked_at = get_current_timestamp();
+        DB_TILE_FRESHNESS.insert(tile, &freshness)?;
+        return Ok(RefreshOutcome::NotModified);
+    }
+    if response.status != 200 {
+        anyhow::bail!("refresh got http status {}", response.status);
+    }
+    let fetch_record = FetchRecord {
+        url: url.to_owned(),
+        socks_addr: socks_addr.clone(),
+        socks_category: socks_category.clone(),
+        response: response.clone(),
+        fetched_at: get_current_timestamp(),
+    };
+    let tile2 = tile.clone();
+    let tmp_file2 = tmp_file.to_owned();
+    let parse_result = spawn_blocking(move || -> Result<_> {
+        let parse_result = tile2.parse_respose(&tmp_file2)?;
+        tile_provenance::record_provenance(
+            &tile2,
+            &fetch_record,
+            &std::fs::read(&tmp_file2)?,
+        )?;
+        tile_history::archive_replaced(&tile2, &tmp_file2)?;
+        tile2.store_download(&tmp_file2)?;
+        Ok(parse_result)
+    })
+    .await??;
+    proxy_manager::set_download_result(tile, parse_result)?;
+    record_response(tile, &response)?;
+    Ok(RefreshOutcome::Updated)
+}
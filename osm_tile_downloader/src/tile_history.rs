@@ -0,0 +1,297 @@
+use anyhow::Context;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::task::spawn_blocking;
+
+use crate::config;
+use crate::config::{get_current_timestamp, LINKS_CONFIG, SLED_DB};
+use crate::download_tile;
+use crate::download_tile::TileFetchId;
+use crate::export_job::{TileExportRequest, TileExportSummary};
+use crate::geo_trig::geo_bbox;
+use crate::tile_freshness;
+use crate::tile_store;
+use crate::tile_store::{ContentTileStore, StoredTile};
+
+lazy_static::lazy_static! {
+    /// Replaced versions of a tile, oldest first.
+    pub static ref DB_TILE_HISTORY:
+        typed_sled::Tree::<TileFetchId, Vec<TileVersion>>
+        = typed_sled::Tree::<TileFetchId, Vec<TileVersion>>::open(
+            &SLED_DB,
+            "tile_history_v1");
+}
+
+/// Channel difference above which a pixel counts as changed, so jpeg
+/// re-encoding noise is not flagged.
+const PIXEL_CHANGE_MIN_DIFF: u8 = 24;
+
+/// One version of a tile. Archived versions live in the content blob
+/// directory under their hash.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TileVersion {
+    pub hash: String,
+    /// Unknown for tiles cached before freshness was recorded.
+    pub fetched_at: Option<f64>,
+    /// None for the current version.
+    pub replaced_at: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TileHistory {
+    pub current: Option<TileVersion>,
+    pub versions: Vec<TileVersion>,
+}
+
+/// Keep the stored version of a tile that is about to be replaced by
+/// `new_file`, if the content differs. Blocking.
+pub fn archive_replaced(tile: &TileFetchId, new_file: &Path) -> Result<()> {
+    let old_bytes =
+        match tile_store::get_tile_store(&tile.server_name)?.read(tile)? {
+            Some(bytes) => bytes,
+            None => return Ok(()),
+        };
+    let old_hash = ContentTileStore::hash_bytes(&old_bytes);
+    if old_hash == ContentTileStore::hash_bytes(&std::fs::read(new_file)?) {
+        return Ok(());
+    }
+    let blob_path = ContentTileStore::get_blob_path(&old_hash, &tile.extension);
+    if std::fs::metadata(&blob_path).is_err() {
+        std::fs::create_dir_all(blob_path.parent().context("no parent")?)?;
+        std::fs::write(&blob_path, &old_bytes)?;
+    }
+    let mut versions = DB_TILE_HISTORY.get(tile)?.unwrap_or_default();
+    versions.push(TileVersion {
+        hash: old_hash,
+        fetched_at: tile_freshness::get_tile_freshness(tile)?
+            .map(|f| f.fetched_at),
+        replaced_at: Some(get_current_timestamp()),
+    });
+    DB_TILE_HISTORY.insert(tile, &versions)?;
+    Ok(())
+}
+
+pub async fn get_tile_history(tile: &TileFetchId) -> Result<TileHistory> {
+    let current = tile_store::read_tile(tile).await?.map(|bytes| TileVersion {
+        hash: ContentTileStore::hash_bytes(&bytes),
+        fetched_at: tile_freshness::get_tile_freshness(tile)
+            .ok()
+            .flatten()
+            .map(|f| f.fetched_at),
+        replaced_at: None,
+    });
+    Ok(TileHistory {
+        current,
+        versions: DB_TILE_HISTORY.get(tile)?.unwrap_or_default(),
+    })
+}
+
+/// Unix seconds, or a `YYYY-MM-DD` date (UTC midnight).
+pub fn parse_date(date: &str) -> Result<f64> {
+    if let Ok(timestamp) = date.parse::<f64>() {
+        return Ok(timestamp);
+    }
+    let parts: Vec<i64> = date
+        .split('-')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("bad date {:?}", date))?;
+    let (y, m, d) = match parts[..] {
+        [y, m, d] if (1..=12).contains(&m) && (1..=31).contains(&d) => {
+            (y, m, d)
+        }
+        _ => anyhow::bail!("bad date {:?}, expected YYYY-MM-DD", date),
+    };
+    // days from civil, proleptic gregorian
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Ok((days * 86400) as f64)
+}
+
+/// The version of a tile that was current at `as_of`: an archived one, or
+/// the stored tile. None when `as_of` is before the oldest known version.
+pub async fn get_tile_as_of(
+    server_name: &str,
+    x: u64,
+    y: u64,
+    z: u8,
+    extension: &str,
+    as_of: f64,
+) -> Result<Option<StoredTile>> {
+    let tile = TileFetchId {
+        x,
+        y,
+        z,
+        server_name: server_name.to_owned(),
+        extension: extension.to_owned(),
+    };
+    let versions = DB_TILE_HISTORY.get(&tile)?.unwrap_or_default();
+    let archived = versions.iter().find(|v| {
+        v.replaced_at.unwrap_or(f64::MAX) > as_of
+            && v.fetched_at.unwrap_or(f64::MIN) <= as_of
+    });
+    if let Some(version) = archived {
+        let path = ContentTileStore::get_blob_path(&version.hash, extension);
+        return Ok(Some(StoredTile::File(path)));
+    }
+    // the stored tile is current since the last replacement, or since it
+    // was fetched when it was never replaced
+    let current_since = match versions.last() {
+        Some(last) => last.replaced_at,
+        None => {
+            tile_freshness::get_tile_freshness(&tile)?.map(|f| f.fetched_at)
+        }
+    };
+    if current_since.is_some_and(|since| since > as_of) {
+        return Ok(None);
+    }
+    Ok(Some(
+        download_tile::get_tile(server_name, x, y, z, extension).await?,
+    ))
+}
+
+/// Fraction of pixels whose largest channel difference exceeds
+/// [`PIXEL_CHANGE_MIN_DIFF`]. Images of different size count as changed.
+fn changed_pixel_fraction(before: &[u8], after: &[u8]) -> Result<f64> {
+    let decode = |bytes: &[u8]| -> Result<image::RgbaImage> {
+        Ok(download_tile::decode_tile_image(bytes)?.to_rgba8())
+    };
+    let (before, after) = (decode(before)?, decode(after)?);
+    if before.dimensions() != after.dimensions() {
+        return Ok(1.0);
+    }
+    let changed = before
+        .pixels()
+        .zip(after.pixels())
+        .filter(|(a, b)| {
+            a.0.iter()
+                .zip(b.0.iter())
+                .any(|(a, b)| a.abs_diff(*b) > PIXEL_CHANGE_MIN_DIFF)
+        })
+        .count();
+    Ok(changed as f64 / (before.width() * before.height()).max(1) as f64)
+}
+
+pub fn get_history_diff_path(server_name: &str) -> PathBuf {
+    LINKS_CONFIG
+        .tile_location
+        .join("export")
+        .join("history_diff")
+        .join(format!(
+            "{}_{}.geojson",
+            server_name,
+            get_current_timestamp() as u64
+        ))
+}
+
+/// Compare each tile with history in the region against its oldest
+/// version replaced after `since`, and write the tiles whose changed pixel
+/// fraction is above `threshold` as GeoJSON polygons. Blocking.
+fn write_history_diff(
+    server_name: &str,
+    request: &TileExportRequest,
+    threshold: f64,
+    since: f64,
+) -> Result<TileExportSummary> {
+    let store = tile_store::get_tile_store(server_name)?;
+    let mut features = vec![];
+    for k in DB_TILE_HISTORY.iter() {
+        let (tile, versions) = k?;
+        if tile.server_name != server_name
+            || tile.z < request.min_z
+            || tile.z > request.max_z
+            || !request.region.contains_tile(tile.x, tile.y, tile.z)
+        {
+            continue;
+        }
+        let ((x0, y0), (x1, y1)) = request.region.tile_range(tile.z)?;
+        if tile.x < x0 || tile.x > x1 || tile.y < y0 || tile.y > y1 {
+            continue;
+        }
+        let before = match versions
+            .iter()
+            .find(|v| v.replaced_at.unwrap_or(f64::MAX) >= since)
+        {
+            Some(before) => before,
+            None => continue,
+        };
+        let before_bytes = std::fs::read(ContentTileStore::get_blob_path(
+            &before.hash,
+            &tile.extension,
+        ))?;
+        let after_bytes = match store.read(&tile)? {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let changed = changed_pixel_fraction(&before_bytes, &after_bytes)?;
+        if changed <= threshold {
+            continue;
+        }
+        let b = geo_bbox(tile.x, tile.y, tile.z);
+        features.push(serde_json::json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [[
+                    [b.x_min, b.y_min],
+                    [b.x_max, b.y_min],
+                    [b.x_max, b.y_max],
+                    [b.x_min, b.y_max],
+                    [b.x_min, b.y_min],
+                ]],
+            },
+            "properties": {
+                "z": tile.z,
+                "x": tile.x,
+                "y": tile.y,
+                "changed_fraction": changed,
+                "before_hash": before.hash,
+                "before_fetched_at": before.fetched_at,
+                "replaced_at": before.replaced_at,
+            },
+        }));
+    }
+    let tile_count = features.len() as u64;
+    let path = get_history_diff_path(server_name);
+    std::fs::create_dir_all(path.parent().context("no parent")?)?;
+    std::fs::write(
+        &path,
+        serde_json::json!({
+            "type": "FeatureCollection",
+            "features": features,
+        })
+        .to_string(),
+    )?;
+    Ok(TileExportSummary {
+        server_name: server_name.to_owned(),
+        path,
+        tile_count,
+    })
+}
+
+/// One GeoJSON report of changed tiles per server.
+pub async fn diff_history(
+    request: TileExportRequest,
+    threshold: f64,
+    since: f64,
+) -> Result<Vec<TileExportSummary>> {
+    request.check()?;
+    let mut results = vec![];
+    for server_name in request.servers.iter() {
+        config::get_tile_server(server_name)?;
+        let server_name = server_name.clone();
+        let request = request.clone();
+        results.push(
+            spawn_blocking(move || {
+                write_history_diff(&server_name, &request, threshold, since)
+            })
+            .await??,
+        );
+    }
+    Ok(results)
+}
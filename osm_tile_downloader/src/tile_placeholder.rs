@@ -0,0 +1,323 @@
+use anyhow::Context;
+use anyhow::Result;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::config::get_current_timestamp;
+use crate::config::TileServerConfig;
+use crate::config::{LINKS_CONFIG, SLED_DB};
+use crate::download_tile;
+use crate::download_tile::{TileFetchId, TileParseResult};
+use crate::export_job;
+use crate::export_job::{
+    ExportJob, ExportRegion, TileExportRequest, TileExportSummary,
+};
+use crate::geo_trig::GeoBBOX;
+use crate::proxy_manager;
+use crate::stat_counter;
+use crate::tile_store;
+
+lazy_static::lazy_static! {
+    pub static ref DB_PLACEHOLDER_SAMPLES:
+        typed_sled::Tree::<PlaceholderKey, PlaceholderSample>
+        = typed_sled::Tree::<PlaceholderKey, PlaceholderSample>::open(
+            &SLED_DB,
+            "tile_placeholder_samples_v1");
+}
+
+/// Max differing bits between two dHashes for the same placeholder.
+const PLACEHOLDER_MAX_HAMMING: u32 = 5;
+/// Max spread of any channel over a downscaled tile to call it uniform.
+/// Downscaling first averages out jpeg noise.
+const UNIFORM_MAX_SPREAD: u8 = 4;
+/// Max difference between r, g and b for a uniform tile to count as grey.
+/// Keeps uniform ocean or land colour tiles as imagery.
+const UNIFORM_MAX_SATURATION: u8 = 6;
+
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Hash, Eq, PartialOrd, Ord,
+)]
+pub struct PlaceholderKey {
+    pub server_name: String,
+    pub dhash: u64,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct PlaceholderSample {
+    pub server_name: String,
+    pub dhash: String,
+    pub x: u64,
+    pub y: u64,
+    pub z: u8,
+    pub marked_at: f64,
+}
+
+/// 64 bit difference hash: one bit per horizontally adjacent pair of a
+/// 9x8 greyscale thumbnail.
+pub fn image_dhash(img: &DynamicImage) -> u64 {
+    let thumb = img.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if thumb.get_pixel(x, y).0[0] < thumb.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Some(colour) if the whole tile is one grey/white/black colour.
+fn uniform_grey_color(img: &DynamicImage) -> Option<[u8; 3]> {
+    let thumb = img.resize_exact(16, 16, FilterType::Triangle).into_rgb8();
+    let mut min = [255u8; 3];
+    let mut max = [0u8; 3];
+    for px in thumb.pixels() {
+        for c in 0..3 {
+            min[c] = min[c].min(px.0[c]);
+            max[c] = max[c].max(px.0[c]);
+        }
+    }
+    if (0..3).any(|c| max[c] - min[c] > UNIFORM_MAX_SPREAD) {
+        return None;
+    }
+    let color = thumb.get_pixel(8, 8).0;
+    let saturation = color.iter().max()? - color.iter().min()?;
+    if saturation > UNIFORM_MAX_SATURATION {
+        return None;
+    }
+    Some(color)
+}
+
+/// Uniform grey tiles count only when the server opts in with
+/// `uniform_placeholders`; marked samples always count.
+pub fn classify_tile_image(
+    server_config: &TileServerConfig,
+    img: &DynamicImage,
+) -> Result<TileParseResult> {
+    if server_config.uniform_placeholders.unwrap_or(false) {
+        if let Some(color) = uniform_grey_color(img) {
+            return Ok(TileParseResult::NoData(format!(
+                "uniform color #{:02x}{:02x}{:02x}",
+                color[0], color[1], color[2]
+            )));
+        }
+    }
+    let dhash = image_dhash(img);
+    for sample in get_placeholder_samples(&server_config.name)? {
+        if let Some(reason) = match_sample(dhash, &sample)? {
+            return Ok(TileParseResult::NoData(reason));
+        }
+    }
+    Ok(TileParseResult::Imagery)
+}
+
+/// NoData reason if `dhash` is close enough to the sample.
+fn match_sample(
+    dhash: u64,
+    sample: &PlaceholderSample,
+) -> Result<Option<String>> {
+    let sample_hash = u64::from_str_radix(&sample.dhash, 16)?;
+    if (dhash ^ sample_hash).count_ones() > PLACEHOLDER_MAX_HAMMING {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "placeholder {:016x} matches sample {} from z={} x={} y={}",
+        dhash, sample.dhash, sample.z, sample.x, sample.y
+    )))
+}
+
+pub fn get_placeholder_samples(
+    server_name: &str,
+) -> Result<Vec<PlaceholderSample>> {
+    let mut samples = vec![];
+    for k in DB_PLACEHOLDER_SAMPLES.iter() {
+        let (key, value) = k?;
+        if key.server_name.eq(server_name) {
+            samples.push(value);
+        }
+    }
+    Ok(samples)
+}
+
+/// Sample learned by [`mark_placeholder`], with the background job that
+/// re-classifies the server's cached tiles against it.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct MarkedPlaceholder {
+    pub sample: PlaceholderSample,
+    pub reclassify_job: ExportJob,
+}
+
+/// Learn the fingerprint of a cached tile that shows a provider
+/// placeholder. Cached tiles of the server are re-classified against it
+/// in a background job.
+pub async fn mark_placeholder(
+    server_name: &str,
+    x: u64,
+    y: u64,
+    z: u8,
+) -> Result<MarkedPlaceholder> {
+    let server_config = crate::config::get_tile_server(server_name)?;
+    let tile = TileFetchId {
+        x,
+        y,
+        z,
+        server_name: server_name.to_owned(),
+        extension: server_config.img_type.clone(),
+    };
+    let bytes = tile_store::read_tile(&tile)
+        .await?
+        .with_context(|| format!("tile not cached: {:?}", &tile))?;
+    let dhash = tokio::task::spawn_blocking(move || -> Result<u64> {
+        let img = download_tile::decode_tile_image(&bytes)?;
+        Ok(image_dhash(&img))
+    })
+    .await??;
+
+    let sample = PlaceholderSample {
+        server_name: server_name.to_owned(),
+        dhash: format!("{:016x}", dhash),
+        x,
+        y,
+        z,
+        marked_at: get_current_timestamp(),
+    };
+    DB_PLACEHOLDER_SAMPLES.insert(
+        &PlaceholderKey {
+            server_name: server_name.to_owned(),
+            dhash,
+        },
+        &sample,
+    )?;
+    let reason = match_sample(dhash, &sample)?.expect("sample matches itself");
+    proxy_manager::set_download_result(&tile, TileParseResult::NoData(reason))?;
+
+    let request = TileExportRequest {
+        servers: vec![server_name.to_owned()],
+        region: ExportRegion {
+            bbox: Some(GeoBBOX {
+                x_min: -180.0,
+                y_min: -90.0,
+                x_max: 180.0,
+                y_max: 90.0,
+            }),
+            polygon: None,
+        },
+        min_z: 0,
+        max_z: server_config.max_level,
+    };
+    let reclassify_job = export_job::start_export_job(
+        &format!("placeholder_reclassify dhash={}", sample.dhash),
+        request,
+        reclassify_cached_tiles(sample.clone()),
+    )?;
+    Ok(MarkedPlaceholder {
+        sample,
+        reclassify_job,
+    })
+}
+
+pub fn get_reclassify_report_path(sample: &PlaceholderSample) -> PathBuf {
+    LINKS_CONFIG
+        .tile_location
+        .join("export")
+        .join("placeholder_reclassify")
+        .join(format!(
+            "{}_{}_{}.json",
+            sample.server_name,
+            sample.dhash,
+            get_current_timestamp() as u64
+        ))
+}
+
+/// Mark cached tiles stored as imagery that match a new sample as NoData,
+/// listing them in a JSON report. Tiles that cannot be read or decoded are
+/// logged and skipped.
+async fn reclassify_cached_tiles(
+    sample: PlaceholderSample,
+) -> Result<Vec<TileExportSummary>> {
+    let server_name = sample.server_name.clone();
+    let cached = tokio::task::spawn_blocking(move || {
+        proxy_manager::get_download_results::<TileFetchId>().map(|results| {
+            results
+                .into_iter()
+                .filter(|(tile, result)| {
+                    tile.server_name == server_name
+                        && *result == TileParseResult::Imagery
+                })
+                .map(|(tile, _)| tile)
+                .collect::<Vec<_>>()
+        })
+    })
+    .await??;
+    let mut reclassified = vec![];
+    for tile in cached {
+        let bytes = match tile_store::read_tile(&tile).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => continue,
+            Err(err) => {
+                eprintln!("reclassify: cannot read {:?}: {}", &tile, err);
+                continue;
+            }
+        };
+        let dhash = tokio::task::spawn_blocking(move || -> Result<u64> {
+            Ok(image_dhash(&download_tile::decode_tile_image(&bytes)?))
+        })
+        .await?;
+        let dhash = match dhash {
+            Ok(dhash) => dhash,
+            Err(err) => {
+                eprintln!("reclassify: cannot decode {:?}: {}", &tile, err);
+                continue;
+            }
+        };
+        if let Some(reason) = match_sample(dhash, &sample)? {
+            proxy_manager::set_download_result(
+                &tile,
+                TileParseResult::NoData(reason),
+            )?;
+            stat_counter::stat_counter_increment(
+                "placeholder",
+                "reclassified",
+                &sample.server_name,
+                &sample.dhash,
+            )?;
+            reclassified.push((tile.z, tile.x, tile.y));
+        }
+    }
+
+    let path = get_reclassify_report_path(&sample);
+    tokio::fs::create_dir_all(path.parent().context("no parent")?).await?;
+    tokio::fs::write(&path, serde_json::to_vec(&reclassified)?).await?;
+    Ok(vec![TileExportSummary {
+        server_name: sample.server_name.clone(),
+        path,
+        tile_count: reclassified.len() as u64,
+    }])
+}
+
+/// Forget a sample. Tiles stored as NoData because of it are re-checked
+/// on their next request. Blocking, reads every download result.
+pub fn unmark_placeholder(server_name: &str, dhash: &str) -> Result<()> {
+    let dhash_value = u64::from_str_radix(dhash, 16)?;
+    DB_PLACEHOLDER_SAMPLES.remove(&PlaceholderKey {
+        server_name: server_name.to_owned(),
+        dhash: dhash_value,
+    })?;
+    let marker = format!("matches sample {:016x} ", dhash_value);
+    let results = proxy_manager::get_download_results::<TileFetchId>()?;
+    for (tile, result) in results {
+        if tile.server_name != server_name {
+            continue;
+        }
+        if let TileParseResult::NoData(reason) = result {
+            if reason.contains(&marker) {
+                proxy_manager::clear_download_entry(&tile)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SLED_DB;
+use crate::download_tile::TileFetchId;
+use crate::proxy_manager::FetchRecord;
+use crate::tile_store::ContentTileStore;
+
+lazy_static::lazy_static! {
+    pub static ref DB_TILE_PROVENANCE:
+        typed_sled::Tree::<TileFetchId, TileProvenance>
+        = typed_sled::Tree::<TileFetchId, TileProvenance>::open(
+            &SLED_DB,
+            "tile_provenance_v2");
+}
+
+/// Every fetch goes through a socks proxy; tor is told apart from the
+/// scraped ones.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FetchRoute {
+    Proxy,
+    Tor,
+}
+
+/// Where and how the stored copy of a tile was fetched.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TileProvenance {
+    pub source_url: String,
+    pub fetched_at: f64,
+    pub http_status: u16,
+    pub content_type: Option<String>,
+    pub size: u64,
+    /// Hex SHA-256 of the tile bytes.
+    pub hash: String,
+    pub route: FetchRoute,
+    pub socks_addr: String,
+}
+
+pub fn get_tile_provenance(
+    tile: &TileFetchId,
+) -> Result<Option<TileProvenance>> {
+    Ok(DB_TILE_PROVENANCE.get(tile)?)
+}
+
+/// Save the provenance of freshly fetched tile bytes.
+pub fn record_provenance(
+    tile: &TileFetchId,
+    fetch_record: &FetchRecord,
+    bytes: &[u8],
+) -> Result<()> {
+    let route = match fetch_record.socks_category.as_str() {
+        "tor" => FetchRoute::Tor,
+        _ => FetchRoute::Proxy,
+    };
+    DB_TILE_PROVENANCE.insert(
+        tile,
+        &TileProvenance {
+            source_url: fetch_record.url.clone(),
+            fetched_at: fetch_record.fetched_at,
+            http_status: fetch_record.response.status,
+            content_type: fetch_record
+                .response
+                .header("content-type")
+                .map(str::to_owned),
+            size: bytes.len() as u64,
+            hash: ContentTileStore::hash_bytes(bytes),
+            route,
+            socks_addr: fetch_record.socks_addr.clone(),
+        },
+    )?;
+    Ok(())
+}
+
+/// RFC 7231 date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(timestamp: f64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+        "Nov", "Dec",
+    ];
+    let secs = timestamp as i64;
+    let days = secs.div_euclid(86400);
+    let day_secs = secs.rem_euclid(86400);
+    // civil from days, proleptic gregorian
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[days.rem_euclid(7) as usize],
+        d,
+        MONTHS[(m - 1) as usize],
+        y,
+        day_secs / 3600,
+        day_secs % 3600 / 60,
+        day_secs % 60
+    )
+}
@@ -0,0 +1,696 @@
+use anyhow::Context;
+use anyhow::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::task::spawn_blocking;
+
+use crate::config;
+use crate::config::{TileServerConfig, LINKS_CONFIG, SLED_DB};
+use crate::download_tile::TileFetchId;
+use crate::proxy_manager::DownloadId;
+use crate::tile_history::DB_TILE_HISTORY;
+
+pub const TILE_STORE_KINDS: [&str; 3] = ["filesystem", "sqlite", "content"];
+
+lazy_static::lazy_static! {
+    static ref TILE_STORES: Mutex<HashMap<String, Arc<dyn TileStore>>>
+        = Mutex::new(HashMap::new());
+
+    pub static ref DB_CONTENT_INDEX:
+        typed_sled::Tree::<ContentIndexKey, String>
+        = typed_sled::Tree::<ContentIndexKey, String>::open(
+            &SLED_DB,
+            "tile_store_content_index_v1");
+}
+
+/// Where finished tiles of one server are kept. All methods block; use the
+/// async helpers below from async code.
+pub trait TileStore: Send + Sync {
+    fn contains(&self, tile: &TileFetchId) -> Result<bool>;
+    fn read(&self, tile: &TileFetchId) -> Result<Option<Vec<u8>>>;
+    /// Take over a validated download; `tmp_file` is consumed.
+    fn put_file(&self, tile: &TileFetchId, tmp_file: &Path) -> Result<()>;
+    fn remove(&self, tile: &TileFetchId) -> Result<()>;
+    /// Stored (x, y) of zoom `z` inside the inclusive tile range.
+    fn list(
+        &self,
+        server_name: &str,
+        z: u8,
+        range: ((u64, u64), (u64, u64)),
+    ) -> Result<Vec<(u64, u64)>>;
+    /// Plain file holding just this tile, if the store has one.
+    fn file_path(&self, _tile: &TileFetchId) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+fn in_range(
+    x: u64,
+    y: u64,
+    ((x0, y0), (x1, y1)): ((u64, u64), (u64, u64)),
+) -> bool {
+    x >= x0 && x <= x1 && y >= y0 && y <= y1
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to.parent().context("path has no parent")?)?;
+    std::fs::rename(from, to)?;
+    Ok(())
+}
+
+/// One file per tile at `TileFetchId::get_final_path`:
+/// `tile_location/map_type/name/z/x/y.ext`.
+pub struct FilesystemTileStore;
+
+impl TileStore for FilesystemTileStore {
+    fn contains(&self, tile: &TileFetchId) -> Result<bool> {
+        Ok(std::fs::metadata(tile.get_final_path()?).is_ok())
+    }
+
+    fn read(&self, tile: &TileFetchId) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(tile.get_final_path()?) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put_file(&self, tile: &TileFetchId, tmp_file: &Path) -> Result<()> {
+        move_file(tmp_file, &tile.get_final_path()?)
+    }
+
+    fn remove(&self, tile: &TileFetchId) -> Result<()> {
+        let _ = std::fs::remove_file(tile.get_final_path()?);
+        Ok(())
+    }
+
+    fn list(
+        &self,
+        server_name: &str,
+        z: u8,
+        range: ((u64, u64), (u64, u64)),
+    ) -> Result<Vec<(u64, u64)>> {
+        let server_config = config::get_tile_server(server_name)?;
+        let probe = TileFetchId {
+            x: 0,
+            y: 0,
+            z,
+            server_name: server_name.to_owned(),
+            extension: server_config.img_type.clone(),
+        };
+        let probe_path = probe.get_final_path()?;
+        let z_dir = probe_path
+            .parent()
+            .and_then(|x_dir| x_dir.parent())
+            .context("tile path too short")?;
+        if !z_dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let y_suffix = format!(".{}", server_config.img_type);
+        let mut tiles = vec![];
+        for x_entry in std::fs::read_dir(z_dir)? {
+            let x_entry = x_entry?;
+            let x = match x_entry.file_name().to_string_lossy().parse::<u64>() {
+                Ok(x) if x >= range.0 .0 && x <= range.1 .0 => x,
+                _ => continue,
+            };
+            for y_entry in std::fs::read_dir(x_entry.path())? {
+                let y_name = y_entry?.file_name().to_string_lossy().to_string();
+                if let Some(Ok(y)) =
+                    y_name.strip_suffix(&y_suffix).map(str::parse::<u64>)
+                {
+                    if in_range(x, y, range) {
+                        tiles.push((x, y));
+                    }
+                }
+            }
+        }
+        Ok(tiles)
+    }
+
+    fn file_path(&self, tile: &TileFetchId) -> Result<Option<PathBuf>> {
+        let path = tile.get_final_path()?;
+        Ok(std::fs::metadata(&path).is_ok().then_some(path))
+    }
+}
+
+/// All tiles of a server in one SQLite file with the MBTiles schema, at
+/// `tile_location/map_type/name.mbtiles`. Rows are TMS-flipped, so the
+/// file opens as a regular MBTiles tileset.
+pub struct SqliteTileStore {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+impl SqliteTileStore {
+    pub fn get_path(server_config: &TileServerConfig) -> PathBuf {
+        LINKS_CONFIG
+            .tile_location
+            .join(&server_config.map_type)
+            .join(format!("{}.mbtiles", server_config.name))
+    }
+
+    pub fn open(server_config: &TileServerConfig) -> Result<Self> {
+        let path = Self::get_path(server_config);
+        std::fs::create_dir_all(path.parent().context("no parent")?)?;
+        let conn = rusqlite::Connection::open(&path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(10))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+            CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT);
+            CREATE UNIQUE INDEX IF NOT EXISTS name ON metadata (name);
+            CREATE TABLE IF NOT EXISTS tiles (zoom_level INTEGER,
+                tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+            CREATE UNIQUE INDEX IF NOT EXISTS tile_index ON tiles
+                (zoom_level, tile_column, tile_row);",
+        )?;
+        for (name, value) in [
+            ("name", server_config.name.as_str()),
+            ("format", server_config.img_type.as_str()),
+        ] {
+            conn.execute(
+                "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                rusqlite::params![name, value],
+            )?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn tms_row(tile: &TileFetchId) -> i64 {
+        ((1u64 << tile.z) - 1 - tile.y) as i64
+    }
+}
+
+impl TileStore for SqliteTileStore {
+    fn contains(&self, tile: &TileFetchId) -> Result<bool> {
+        let conn = self.conn.lock().expect("poisoned sqlite lock");
+        let mut query = conn.prepare_cached(
+            "SELECT 1 FROM tiles
+            WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+        )?;
+        Ok(query.exists(rusqlite::params![
+            tile.z,
+            tile.x as i64,
+            Self::tms_row(tile)
+        ])?)
+    }
+
+    fn read(&self, tile: &TileFetchId) -> Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension;
+        let conn = self.conn.lock().expect("poisoned sqlite lock");
+        let mut query = conn.prepare_cached(
+            "SELECT tile_data FROM tiles
+            WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+        )?;
+        Ok(query
+            .query_row(
+                rusqlite::params![tile.z, tile.x as i64, Self::tms_row(tile)],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn put_file(&self, tile: &TileFetchId, tmp_file: &Path) -> Result<()> {
+        let bytes = std::fs::read(tmp_file)?;
+        {
+            let conn = self.conn.lock().expect("poisoned sqlite lock");
+            conn.execute(
+                "INSERT OR REPLACE INTO tiles
+                (zoom_level, tile_column, tile_row, tile_data)
+                VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![
+                    tile.z,
+                    tile.x as i64,
+                    Self::tms_row(tile),
+                    bytes
+                ],
+            )?;
+        }
+        std::fs::remove_file(tmp_file)?;
+        Ok(())
+    }
+
+    fn remove(&self, tile: &TileFetchId) -> Result<()> {
+        let conn = self.conn.lock().expect("poisoned sqlite lock");
+        conn.execute(
+            "DELETE FROM tiles
+            WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+            rusqlite::params![tile.z, tile.x as i64, Self::tms_row(tile)],
+        )?;
+        Ok(())
+    }
+
+    fn list(
+        &self,
+        _server_name: &str,
+        z: u8,
+        ((x0, y0), (x1, y1)): ((u64, u64), (u64, u64)),
+    ) -> Result<Vec<(u64, u64)>> {
+        let flip = |y: u64| ((1u64 << z) - 1 - y) as i64;
+        let conn = self.conn.lock().expect("poisoned sqlite lock");
+        let mut query = conn.prepare_cached(
+            "SELECT tile_column, tile_row FROM tiles
+            WHERE zoom_level = ?1
+            AND tile_column BETWEEN ?2 AND ?3
+            AND tile_row BETWEEN ?4 AND ?5",
+        )?;
+        let rows = query.query_map(
+            rusqlite::params![z, x0 as i64, x1 as i64, flip(y1), flip(y0)],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        )?;
+        let mut tiles = vec![];
+        for row in rows {
+            let (x, tms_row) = row?;
+            tiles.push((x as u64, flip(tms_row as u64) as u64));
+        }
+        Ok(tiles)
+    }
+}
+
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Hash, Eq, PartialOrd, Ord,
+)]
+pub struct ContentIndexKey {
+    pub server_name: String,
+    pub z: u8,
+    pub x: u64,
+    pub y: u64,
+}
+
+impl ContentIndexKey {
+    /// All keys of one server and zoom level. Keys serialize field by
+    /// field, so these sort as one contiguous run of the index.
+    pub fn zoom_range(
+        server_name: &str,
+        z: u8,
+    ) -> std::ops::RangeInclusive<ContentIndexKey> {
+        let key = |x, y| ContentIndexKey {
+            server_name: server_name.to_owned(),
+            z,
+            x,
+            y,
+        };
+        key(0, 0)..=key(u64::MAX, u64::MAX)
+    }
+}
+
+impl From<&TileFetchId> for ContentIndexKey {
+    fn from(tile: &TileFetchId) -> Self {
+        Self {
+            server_name: tile.server_name.clone(),
+            z: tile.z,
+            x: tile.x,
+            y: tile.y,
+        }
+    }
+}
+
+/// Tiles stored once per distinct content, as
+/// `tile_location/blobs/<sha256[..2]>/<sha256>.ext`, with a sled index from
+/// z/x/y to the hash.
+pub struct ContentTileStore;
+
+impl ContentTileStore {
+    pub fn get_blob_path(hash: &str, extension: &str) -> PathBuf {
+        LINKS_CONFIG
+            .tile_location
+            .join("blobs")
+            .join(&hash[..2])
+            .join(format!("{}.{}", hash, extension))
+    }
+
+    pub fn hash_bytes(bytes: &[u8]) -> String {
+        Sha256::digest(bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn get_hash(tile: &TileFetchId) -> Result<Option<String>> {
+        Ok(DB_CONTENT_INDEX.get(&tile.into())?)
+    }
+}
+
+impl TileStore for ContentTileStore {
+    fn contains(&self, tile: &TileFetchId) -> Result<bool> {
+        Ok(self.file_path(tile)?.is_some())
+    }
+
+    fn read(&self, tile: &TileFetchId) -> Result<Option<Vec<u8>>> {
+        match self.file_path(tile)? {
+            Some(path) => Ok(Some(std::fs::read(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_file(&self, tile: &TileFetchId, tmp_file: &Path) -> Result<()> {
+        let hash = Self::hash_bytes(&std::fs::read(tmp_file)?);
+        let blob_path = Self::get_blob_path(&hash, &tile.extension);
+        if std::fs::metadata(&blob_path).is_ok() {
+            std::fs::remove_file(tmp_file)?;
+        } else {
+            move_file(tmp_file, &blob_path)?;
+        }
+        DB_CONTENT_INDEX.insert(&tile.into(), &hash)?;
+        Ok(())
+    }
+
+    /// Drops the index entry only; the blob may be shared, and is deleted
+    /// by [`gc_content_blobs`] once nothing points to it.
+    fn remove(&self, tile: &TileFetchId) -> Result<()> {
+        DB_CONTENT_INDEX.remove(&tile.into())?;
+        Ok(())
+    }
+
+    fn list(
+        &self,
+        server_name: &str,
+        z: u8,
+        range: ((u64, u64), (u64, u64)),
+    ) -> Result<Vec<(u64, u64)>> {
+        let mut tiles = vec![];
+        for k in
+            DB_CONTENT_INDEX.range(ContentIndexKey::zoom_range(server_name, z))
+        {
+            let (key, _hash) = k?;
+            if in_range(key.x, key.y, range) {
+                tiles.push((key.x, key.y));
+            }
+        }
+        Ok(tiles)
+    }
+
+    fn file_path(&self, tile: &TileFetchId) -> Result<Option<PathBuf>> {
+        let hash = match Self::get_hash(tile)? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let path = Self::get_blob_path(&hash, &tile.extension);
+        Ok(std::fs::metadata(&path).is_ok().then_some(path))
+    }
+}
+
+fn open_tile_store(
+    kind: &str,
+    server_config: &TileServerConfig,
+) -> Result<Arc<dyn TileStore>> {
+    Ok(match kind {
+        "filesystem" => Arc::new(FilesystemTileStore),
+        "sqlite" => Arc::new(SqliteTileStore::open(server_config)?),
+        "content" => Arc::new(ContentTileStore),
+        other => anyhow::bail!("unknown tile store {:?}", other),
+    })
+}
+
+fn get_tile_store_kind(server_config: &TileServerConfig) -> &str {
+    server_config.tile_store.as_deref().unwrap_or("filesystem")
+}
+
+/// The store configured for the server, opened once.
+pub fn get_tile_store(server_name: &str) -> Result<Arc<dyn TileStore>> {
+    let mut stores = TILE_STORES.lock().expect("poisoned tile store lock");
+    if let Some(store) = stores.get(server_name) {
+        return Ok(store.clone());
+    }
+    let server_config = config::get_tile_server(server_name)?;
+    let store =
+        open_tile_store(get_tile_store_kind(&server_config), &server_config)?;
+    stores.insert(server_name.to_owned(), store.clone());
+    Ok(store)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DedupReport {
+    pub server_name: String,
+    pub tile_store: String,
+    /// Tiles in the content index.
+    pub tile_count: u64,
+    pub unique_count: u64,
+    /// Size the tiles would take as one file each.
+    pub total_bytes: u64,
+    /// Size of the distinct blobs they point to.
+    pub stored_bytes: u64,
+    pub saved_bytes: u64,
+}
+
+/// Space saved by content-addressed storage, per server. Blobs shared
+/// between servers count towards each of them. Blocking.
+pub fn get_dedup_report() -> Result<Vec<DedupReport>> {
+    let mut reports: HashMap<String, DedupReport> = HashMap::new();
+    for server_config in config::get_all_tile_servers()? {
+        reports.insert(
+            server_config.name.clone(),
+            DedupReport {
+                server_name: server_config.name.clone(),
+                tile_store: get_tile_store_kind(&server_config).to_owned(),
+                tile_count: 0,
+                unique_count: 0,
+                total_bytes: 0,
+                stored_bytes: 0,
+                saved_bytes: 0,
+            },
+        );
+    }
+    let mut blob_sizes: HashMap<String, u64> = HashMap::new();
+    let mut seen: std::collections::HashSet<(String, String)> =
+        std::collections::HashSet::new();
+    for k in DB_CONTENT_INDEX.iter() {
+        let (key, hash) = k?;
+        let report = match reports.get_mut(&key.server_name) {
+            Some(report) => report,
+            None => continue,
+        };
+        let extension = &config::get_tile_server(&key.server_name)?.img_type;
+        let size = match blob_sizes.get(&hash) {
+            Some(size) => *size,
+            None => {
+                let path = ContentTileStore::get_blob_path(&hash, extension);
+                let size =
+                    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                blob_sizes.insert(hash.clone(), size);
+                size
+            }
+        };
+        report.tile_count += 1;
+        report.total_bytes += size;
+        if seen.insert((key.server_name.clone(), hash)) {
+            report.unique_count += 1;
+            report.stored_bytes += size;
+        }
+    }
+    let mut reports: Vec<DedupReport> = reports
+        .into_values()
+        .map(|mut r| {
+            r.saved_bytes = r.total_bytes - r.stored_bytes;
+            r
+        })
+        .collect();
+    reports.sort_by(|a, b| a.server_name.cmp(&b.server_name));
+    Ok(reports)
+}
+
+/// Blobs younger than this are kept by the GC, since `put_file` moves the
+/// blob in before it writes the index entry.
+const CONTENT_GC_MIN_AGE: std::time::Duration =
+    std::time::Duration::from_secs(3600);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContentGcSummary {
+    pub blob_count: u64,
+    pub removed_count: u64,
+    pub removed_bytes: u64,
+}
+
+/// Delete blobs that neither the content index nor the tile history point
+/// to. Blocking.
+pub fn gc_content_blobs() -> Result<ContentGcSummary> {
+    let mut referenced: std::collections::HashSet<PathBuf> =
+        std::collections::HashSet::new();
+    let mut extensions: HashMap<String, String> = HashMap::new();
+    for k in DB_CONTENT_INDEX.iter() {
+        let (key, hash) = k?;
+        if !extensions.contains_key(&key.server_name) {
+            let extension = match config::get_tile_server(&key.server_name) {
+                Ok(server_config) => server_config.img_type,
+                // server gone from the config, keep its blobs
+                Err(_) => continue,
+            };
+            extensions.insert(key.server_name.clone(), extension);
+        }
+        referenced.insert(ContentTileStore::get_blob_path(
+            &hash,
+            &extensions[&key.server_name],
+        ));
+    }
+    for k in DB_TILE_HISTORY.iter() {
+        let (tile, versions) = k?;
+        for version in versions {
+            referenced.insert(ContentTileStore::get_blob_path(
+                &version.hash,
+                &tile.extension,
+            ));
+        }
+    }
+
+    let mut summary = ContentGcSummary {
+        blob_count: 0,
+        removed_count: 0,
+        removed_bytes: 0,
+    };
+    let blobs_dir = LINKS_CONFIG.tile_location.join("blobs");
+    if !blobs_dir.is_dir() {
+        return Ok(summary);
+    }
+    for prefix_entry in std::fs::read_dir(blobs_dir)? {
+        let prefix_path = prefix_entry?.path();
+        if !prefix_path.is_dir() {
+            continue;
+        }
+        for blob_entry in std::fs::read_dir(prefix_path)? {
+            let blob_entry = blob_entry?;
+            let metadata = blob_entry.metadata()?;
+            summary.blob_count += 1;
+            let young = metadata
+                .modified()?
+                .elapsed()
+                .map_or(true, |age| age < CONTENT_GC_MIN_AGE);
+            if young || referenced.contains(&blob_entry.path()) {
+                continue;
+            }
+            std::fs::remove_file(blob_entry.path())?;
+            summary.removed_count += 1;
+            summary.removed_bytes += metadata.len();
+        }
+    }
+    Ok(summary)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContentMigrationSummary {
+    pub server_name: String,
+    pub from: String,
+    pub tile_count: u64,
+}
+
+/// Move every tile of a server from the `from` store into the
+/// content-addressed store. Meant to run offline, before switching the
+/// server to `tile_store = "content"`. Blocking.
+pub fn migrate_to_content_store(
+    server_name: &str,
+    from: &str,
+) -> Result<ContentMigrationSummary> {
+    if from.eq("content") {
+        anyhow::bail!("cannot migrate from content store to itself");
+    }
+    let server_config = config::get_tile_server(server_name)?;
+    let source = open_tile_store(from, &server_config)?;
+    let target = ContentTileStore;
+    let mut tile_count = 0;
+    for z in 0..=server_config.max_level {
+        let max_extent = (1u64 << z) - 1;
+        let tiles =
+            source.list(server_name, z, ((0, 0), (max_extent, max_extent)))?;
+        for (x, y) in tiles.iter() {
+            let tile = TileFetchId {
+                x: *x,
+                y: *y,
+                z,
+                server_name: server_name.to_owned(),
+                extension: server_config.img_type.clone(),
+            };
+            let bytes = match source.read(&tile)? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let tmp_file = config::tmpdir().join(format!(
+                "{}.content_migration",
+                rand::thread_rng().gen::<u128>()
+            ));
+            std::fs::write(&tmp_file, bytes)?;
+            target.put_file(&tile, &tmp_file)?;
+            source.remove(&tile)?;
+            tile_count += 1;
+        }
+        if !tiles.is_empty() {
+            eprintln!(
+                "content migration {}: z={} moved {} tiles",
+                server_name,
+                z,
+                tiles.len()
+            );
+        }
+    }
+    DB_CONTENT_INDEX.flush()?;
+    Ok(ContentMigrationSummary {
+        server_name: server_name.to_owned(),
+        from: from.to_owned(),
+        tile_count,
+    })
+}
+
+/// A stored tile: a plain file when the store has one, else its bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredTile {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl StoredTile {
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            StoredTile::File(path) => Ok(tokio::fs::read(path).await?),
+            StoredTile::Bytes(bytes) => Ok(bytes.clone()),
+        }
+    }
+
+    pub async fn size(&self) -> Result<u64> {
+        match self {
+            StoredTile::File(path) => {
+                Ok(tokio::fs::metadata(path).await?.len())
+            }
+            StoredTile::Bytes(bytes) => Ok(bytes.len() as u64),
+        }
+    }
+}
+
+pub async fn get_stored_tile(tile: &TileFetchId) -> Result<Option<StoredTile>> {
+    let tile = tile.clone();
+    spawn_blocking(move || {
+        let store = get_tile_store(&tile.server_name)?;
+        if let Some(path) = store.file_path(&tile)? {
+            return Ok(Some(StoredTile::File(path)));
+        }
+        Ok(store.read(&tile)?.map(StoredTile::Bytes))
+    })
+    .await?
+}
+
+pub async fn read_tile(tile: &TileFetchId) -> Result<Option<Vec<u8>>> {
+    let tile = tile.clone();
+    spawn_blocking(move || get_tile_store(&tile.server_name)?.read(&tile))
+        .await?
+}
+
+pub async fn contains_tile(tile: &TileFetchId) -> Result<bool> {
+    let tile = tile.clone();
+    spawn_blocking(move || get_tile_store(&tile.server_name)?.contains(&tile))
+        .await?
+}
+
+/// Store tile bytes produced locally, e.g. pyramid tiles.
+pub async fn write_tile(tile: &TileFetchId, bytes: &[u8]) -> Result<()> {
+    let tmp_file = config::tmpdir()
+        .join(format!("{}.tile_store", rand::thread_rng().gen::<u128>()));
+    tokio::fs::write(&tmp_file, bytes).await?;
+    let tile = tile.clone();
+    spawn_blocking(move || {
+        get_tile_store(&tile.server_name)?.put_file(&tile, &tmp_file)
+    })
+    .await?
+}
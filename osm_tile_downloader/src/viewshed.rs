@@ -0,0 +1,325 @@
+use anyhow::Context;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::download_tile;
+use crate::elevation;
+use crate::elevation_profile;
+use crate::geo_trig::{haversine_meters, GeoBBOX, GeoPoint, EARTH_MEAN_RADIUS};
+
+/// Eye height of a standing person.
+pub const DEFAULT_OBSERVER_HEIGHT: f64 = 1.7;
+pub const VIEWSHED_MIN_RADIUS: f64 = 100.0;
+pub const VIEWSHED_MAX_RADIUS: f64 = 25_000.0;
+pub const LINE_OF_SIGHT_MAX_DISTANCE: f64 = 100_000.0;
+/// Cells from the observer to the edge of the viewshed grid.
+const VIEWSHED_HALF_CELLS: usize = 128;
+/// Atmospheric refraction coefficient; light bends over part of the
+/// earth's curvature.
+const REFRACTION_COEFFICIENT: f64 = 0.13;
+
+/// First terrain sample above the sight line.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct Obstruction {
+    pub distance: f64,
+    pub lon: f64,
+    pub lat: f64,
+    pub elevation: f32,
+    /// Meters the terrain rises above the sight line.
+    pub height_above_sight_line: f64,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct LineOfSight {
+    pub server_name: String,
+    pub distance: f64,
+    /// Ground elevations, without the observer and target heights.
+    pub observer_elevation: f32,
+    pub target_elevation: f32,
+    pub visible: bool,
+    pub obstruction: Option<Obstruction>,
+}
+
+/// Visibility grid around the observer, row major from the north-west
+/// corner, linear in lat/lon over `bbox`.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub struct Viewshed {
+    pub server_name: String,
+    pub observer: GeoPoint,
+    pub observer_elevation: f32,
+    pub radius: f64,
+    /// Cell side in meters.
+    pub cell_size: f64,
+    pub bbox: GeoBBOX,
+    pub size: usize,
+    pub cells: Vec<CellVisibility>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub enum CellVisibility {
+    /// Outside the radius or without elevation data.
+    Unknown,
+    Hidden,
+    Visible,
+}
+
+/// How far terrain at `distance` meters sinks below the observer's
+/// horizontal plane, with refraction.
+fn curvature_drop(distance: f64) -> f64 {
+    distance * distance * (1.0 - REFRACTION_COEFFICIENT)
+        / (2.0 * EARTH_MEAN_RADIUS)
+}
+
+/// Sight line from `observer_height` above the ground at `observer` to
+/// `target_height` above the ground at `target`, sampled every `spacing`
+/// meters along the great circle.
+pub async fn get_line_of_sight(
+    server_name: Option<&str>,
+    observer: &GeoPoint,
+    target: &GeoPoint,
+    observer_height: f64,
+    target_height: f64,
+    spacing: f64,
+) -> Result<LineOfSight> {
+    let distance = haversine_meters(observer, target);
+    if distance > LINE_OF_SIGHT_MAX_DISTANCE {
+        anyhow::bail!(
+            "points are {:.0} m apart, max is {}",
+            distance,
+            LINE_OF_SIGHT_MAX_DISTANCE
+        );
+    }
+    let server_config = elevation::get_elevation_server(server_name)?;
+    let samples =
+        elevation_profile::sample_path(&[*observer, *target], spacing)?;
+    let points: Vec<GeoPoint> = samples.iter().map(|s| s.1).collect();
+    let elevations =
+        elevation::get_elevations(Some(&server_config.name), &points).await?;
+    let observer_elevation =
+        elevations[0].context("no elevation data at the observer")?;
+    let target_elevation = elevations[elevations.len() - 1]
+        .context("no elevation data at the target")?;
+    let eye = observer_elevation as f64 + observer_height;
+    let target_top =
+        target_elevation as f64 + target_height - curvature_drop(distance);
+
+    let mut line_of_sight = LineOfSight {
+        server_name: server_config.name,
+        distance,
+        observer_elevation,
+        target_elevation,
+        visible: true,
+        obstruction: None,
+    };
+    if distance <= 0.0 {
+        return Ok(line_of_sight);
+    }
+    let last = samples.len() - 1;
+    for ((d, point), elevation) in
+        samples[1..last].iter().zip(elevations[1..last].iter())
+    {
+        let Some(elevation) = elevation else { continue };
+        let sight_line = eye + (target_top - eye) * d / distance;
+        let terrain = *elevation as f64 - curvature_drop(*d);
+        if terrain > sight_line {
+            line_of_sight.visible = false;
+            line_of_sight.obstruction = Some(Obstruction {
+                distance: *d,
+                lon: point.x_lon,
+                lat: point.y_lat,
+                elevation: *elevation,
+                height_above_sight_line: terrain - sight_line,
+            });
+            break;
+        }
+    }
+    Ok(line_of_sight)
+}
+
+/// Terrain visible from `observer_height` above the ground at `observer`
+/// within `radius` meters, by casting rays from the observer to every
+/// cell on the grid border.
+pub async fn get_viewshed(
+    server_name: Option<&str>,
+    observer: &GeoPoint,
+    observer_height: f64,
+    radius: f64,
+) -> Result<Viewshed> {
+    if !(VIEWSHED_MIN_RADIUS..=VIEWSHED_MAX_RADIUS).contains(&radius) {
+        anyhow::bail!(
+            "radius must be {} to {} meters, got {}",
+            VIEWSHED_MIN_RADIUS,
+            VIEWSHED_MAX_RADIUS,
+            radius
+        );
+    }
+    let server_config = elevation::get_elevation_server(server_name)?;
+    let half = VIEWSHED_HALF_CELLS;
+    let size = 2 * half + 1;
+    let cell_size = radius / half as f64;
+    let dlat = (cell_size / EARTH_MEAN_RADIUS).to_degrees();
+    let dlon = dlat / observer.y_lat.to_radians().cos();
+    let cell_center = |i: usize, j: usize| GeoPoint {
+        x_lon: observer.x_lon + (i as f64 - half as f64) * dlon,
+        y_lat: observer.y_lat - (j as f64 - half as f64) * dlat,
+    };
+    let points: Vec<GeoPoint> = (0..size * size)
+        .map(|k| cell_center(k % size, k / size))
+        .collect();
+    let elevations =
+        elevation::get_elevations(Some(&server_config.name), &points).await?;
+    let observer_elevation = elevations[half * size + half]
+        .context("no elevation data at the observer")?;
+    let eye = observer_elevation as f64 + observer_height;
+
+    let cells = tokio::task::spawn_blocking(move || {
+        let distance = |i: usize, j: usize| {
+            (i as f64 - half as f64).hypot(j as f64 - half as f64) * cell_size
+        };
+        let mut cells: Vec<CellVisibility> = (0..size * size)
+            .map(|k| {
+                let in_radius = distance(k % size, k / size) <= radius;
+                match elevations[k] {
+                    Some(_) if in_radius => CellVisibility::Hidden,
+                    _ => CellVisibility::Unknown,
+                }
+            })
+            .collect();
+        cells[half * size + half] = CellVisibility::Visible;
+
+        let last = size - 1;
+        let border = (0..size)
+            .flat_map(|t| [(t, 0), (t, last), (0, t), (last, t)])
+            .collect::<Vec<_>>();
+        for (bi, bj) in border {
+            let (di, dj) = (bi as f64 - half as f64, bj as f64 - half as f64);
+            let steps = di.abs().max(dj.abs());
+            // steepest angle to the terrain seen so far along the ray
+            let mut max_slope = f64::NEG_INFINITY;
+            for step in 1..=steps as usize {
+                let t = step as f64 / steps;
+                let i = (half as f64 + di * t).round() as usize;
+                let j = (half as f64 + dj * t).round() as usize;
+                let d = distance(i, j);
+                if d > radius {
+                    break;
+                }
+                let Some(elevation) = elevations[j * size + i] else {
+                    continue;
+                };
+                let slope = (elevation as f64 - curvature_drop(d) - eye) / d;
+                if slope >= max_slope {
+                    cells[j * size + i] = CellVisibility::Visible;
+                    max_slope = slope;
+                }
+            }
+        }
+        cells
+    })
+    .await?;
+
+    let (nw, se) = (cell_center(0, 0), cell_center(size - 1, size - 1));
+    Ok(Viewshed {
+        server_name: server_config.name,
+        observer: *observer,
+        observer_elevation,
+        radius,
+        cell_size,
+        bbox: GeoBBOX {
+            x_min: nw.x_lon - dlon / 2.0,
+            y_min: se.y_lat - dlat / 2.0,
+            x_max: se.x_lon + dlon / 2.0,
+            y_max: nw.y_lat + dlat / 2.0,
+        },
+        size,
+        cells,
+    })
+}
+
+/// Visible cells green, hidden ones dark, unknown ones transparent.
+pub fn render_viewshed_png(viewshed: &Viewshed) -> Result<Vec<u8>> {
+    let size = viewshed.size as u32;
+    let img = image::RgbaImage::from_fn(size, size, |i, j| {
+        match viewshed.cells[(j * size + i) as usize] {
+            CellVisibility::Visible => image::Rgba([40, 200, 40, 140]),
+            CellVisibility::Hidden => image::Rgba([0, 0, 0, 140]),
+            CellVisibility::Unknown => image::Rgba([0, 0, 0, 0]),
+        }
+    });
+    download_tile::encode_tile_image(
+        image::DynamicImage::ImageRgba8(img),
+        "png",
+    )
+}
+
+/// Visible area as one MultiPolygon, made of the runs of visible cells in
+/// each grid row.
+pub fn viewshed_geojson(viewshed: &Viewshed) -> serde_json::Value {
+    let size = viewshed.size;
+    let bbox = &viewshed.bbox;
+    let dlon = (bbox.x_max - bbox.x_min) / size as f64;
+    let dlat = (bbox.y_max - bbox.y_min) / size as f64;
+    let mut polygons = vec![];
+    let mut visible_count = 0;
+    for j in 0..size {
+        let row = &viewshed.cells[j * size..(j + 1) * size];
+        let mut i = 0;
+        while i < size {
+            if row[i] != CellVisibility::Visible {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < size && row[i] == CellVisibility::Visible {
+                i += 1;
+            }
+            visible_count += i - start;
+            let (x0, x1) = (
+                bbox.x_min + start as f64 * dlon,
+                bbox.x_min + i as f64 * dlon,
+            );
+            let (y0, y1) = (
+                bbox.y_max - (j + 1) as f64 * dlat,
+                bbox.y_max - j as f64 * dlat,
+            );
+            polygons.push(vec![vec![
+                [x0, y0],
+                [x1, y0],
+                [x1, y1],
+                [x0, y1],
+                [x0, y0],
+            ]]);
+        }
+    }
+    let observer_point = [viewshed.observer.x_lon, viewshed.observer.y_lat];
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": [
+            {
+                "type": "Feature",
+                "geometry": {
+                    "type": "MultiPolygon",
+                    "coordinates": polygons,
+                },
+                "properties": {
+                    "observer_elevation": viewshed.observer_elevation,
+                    "radius": viewshed.radius,
+                    "cell_size": viewshed.cell_size,
+                    "visible_area": visible_count as f64
+                        * viewshed.cell_size
+                        * viewshed.cell_size,
+                },
+            },
+            {
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": observer_point,
+                },
+                "properties": {
+                    "observer": true,
+                },
+            },
+        ],
+    })
+}
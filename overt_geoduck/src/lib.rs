@@ -253,6 +253,61 @@ pub fn crop_geoparquet(
     Ok(std::fs::metadata(&parquet_out)?.file_size() as usize)
 }
 
+const SQL_COPY_NAMES_AS_JSON: &str = "
+COPY(
+    SELECT id, names.primary AS name, CAST({kind_column} AS VARCHAR) AS kind,
+        bbox.xmin AS xmin, bbox.ymin AS ymin, bbox.xmax AS xmax, bbox.ymax AS ymax
+    FROM {view_name}
+    WHERE names.primary IS NOT NULL
+) TO '{file_path}'
+WITH (FORMAT JSON);
+";
+
+/// Dump id, primary name, `kind_column` and bbox of every named feature
+/// as newline delimited JSON, for building a search index.
+pub fn export_names_json(
+    parquet_in: &Path,
+    kind_column: &str,
+    json_out: &Path,
+) -> anyhow::Result<usize> {
+    let json_out = json_out
+        .to_str()
+        .context("cannot transform path to string.")?;
+    let parquet_in = parquet_in
+    .to_str()
+    .context("cannot transform path to string.")?;
+
+    let (init_sql, temp_dir) = get_duck_settings_sql()?;
+    defer!{
+        if std::fs::remove_dir_all(&temp_dir).is_err() {
+            eprintln!("failed to remove temp dir: {:?}", temp_dir);
+        }
+    }
+    let mut sql_all = init_sql.to_owned();
+    sql_all.push_str(&OvertDataType::sql_create_view_from_disk("names_view", parquet_in));
+
+    let mut map: HashMap<String, String> = HashMap::with_capacity(10);
+    map.insert("view_name".to_owned(), "names_view".to_string());
+    map.insert("kind_column".to_owned(), kind_column.to_string());
+    map.insert("file_path".to_owned(), json_out.to_string());
+    let sql = strfmt::strfmt(SQL_COPY_NAMES_AS_JSON, &map)
+        .expect("sql_copy_names: failed strfmt on sql");
+    eprintln!("geoduck: exporting names of {:?} \n", parquet_in);
+    sql_all.push_str(&sql);
+
+    execute_duck(&sql_all)?;
+
+    if !std::path::PathBuf::from(json_out).exists() {
+        anyhow::bail!(
+            "duck did not dump any file for {:?} at {}",
+            parquet_in,
+            json_out
+        );
+    }
+
+    Ok(std::fs::metadata(&json_out)?.len() as usize)
+}
+
 fn get_duck_settings_sql() -> anyhow::Result<(String, String)> {
     // returns (SQL, temp_dir)
     eprintln!("geoduck: initializing");